@@ -45,7 +45,7 @@ class Experiment(object):
         self._name = name
         self._directory_name = self.compute_directory_name(config)
         self._migration = config.pop("migration")
-        self._bin_shift = config.pop("bin_shift")
+        self._bin_shift = config.get("bin_shift")
         self._workers = config.pop("workers")
         self._processes = config.pop("processes")
         self._rate = int(config.pop("rate"))
@@ -79,7 +79,7 @@ class Experiment(object):
         return self._directory_name
 
     def get_features(self):
-        features = ["dynamic_scaling_mechanism/bin-{}".format(self._bin_shift)]
+        features = []
         if self._fake_stateful:
             features.append("fake_stateful")
         return features
@@ -205,7 +205,7 @@ class Experiment(object):
         if not dryrun:
             ensure_dir(self.get_result_directory_name())
         if build:
-            build_cmd = ". ~/eth_proxy.sh && cargo rustc --target-dir {} --bin {} --release --no-default-features --features {}".format(
+            build_cmd = ". ~/eth_proxy.sh && cargo rustc --target-dir {} --bin {} --release --features {}".format(
                 shlex.quote(self.get_build_directory_name()), self.binary, shlex.quote(" ".join(self.get_features())))
             if self._machine_local:
                 run_cmd(build_cmd, node=self.single_machine_id, dryrun=dryrun)
@@ -33,7 +33,7 @@ use timely::dataflow::Stream;
 use timely::dataflow::Scope;
 use timely::ExchangeData;
 
-use dynamic_scaling_mechanism::{ControlInst, Control};
+use dynamic_scaling_mechanism::{ControlInst, Control, StatefulConfig};
 use dynamic_scaling_mechanism::operator::StatefulOperator;
 
 use nexmark::event::Event;
@@ -89,6 +89,7 @@ fn main() {
         .arg(Arg::with_name("duration").long("duration").takes_value(true).required(true))
         .arg(Arg::with_name("migration").long("migration").takes_value(true).required(true))
         .arg(Arg::with_name("time_dilation").long("time_dilation").takes_value(true).required(false))
+        .arg(Arg::with_name("bin_shift").long("bin_shift").takes_value(true).required(false))
         .arg(Arg::with_name("queries").long("queries").takes_value(true).required(true).multiple(true).value_delimiter(" "))
         .arg(Arg::with_name("timely").multiple(true))
         .get_matches();
@@ -103,6 +104,8 @@ fn main() {
 
     let time_dilation = matches.value_of("time_dilation").map_or(1, |arg| arg.parse().unwrap_or(1));
 
+    let stateful_config = matches.value_of("bin_shift").map_or(StatefulConfig::new(), |arg| StatefulConfig::new().with_bin_shift(arg.parse().expect("couldn't parse bin_shift")));
+
     let queries: Vec<_> = matches.values_of("queries").unwrap().map(String::from).collect();
 
     // Read and report RSS
@@ -137,6 +140,7 @@ fn main() {
                 people: &people,
                 closed_auctions: &closed_auctions,
                 closed_auctions_flex: &closed_auctions_flex,
+                config: &stateful_config,
             };
 
             let nexmark_timer = NexmarkTimer {
@@ -198,7 +202,7 @@ fn main() {
                 worker.dataflow(|scope| {
                     let control = Some(control.clone()).replay_into(scope);
                     input.to_stream(scope)
-                        .distribute(&control, |e| calculate_hash(&e.id()), "q0-flex")
+                        .distribute(&control, &stateful_config, |e| calculate_hash(&e.id()), "q0-flex")
                         .probe_with(&mut probe);
                 });
             }
@@ -345,11 +349,11 @@ fn main() {
         config1.insert("first-event-number", format!("{}", index));
         let mut config = nexmark::config::NEXMarkConfig::new(&config1);
 
-        let mut instructions: Vec<(u64, Vec<ControlInst>)> = map_mode.instructions(peers, duration_ns).unwrap();
+        let mut instructions: Vec<(u64, Vec<ControlInst>)> = map_mode.instructions(peers, stateful_config.bins(), duration_ns).unwrap();
 
         if index == 0 {
             println!("time_dilation\t{}", time_dilation);
-            println!("bin_shift\t{}", ::dynamic_scaling_mechanism::BIN_SHIFT);
+            println!("bin_shift\t{}", stateful_config.bin_shift());
 
             for instruction in instructions.iter().take(10) {
                 // Format instructions first to be able to truncate the string representation
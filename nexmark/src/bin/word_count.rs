@@ -32,7 +32,7 @@ use timely::dataflow::Stream;
 use timely::dataflow::Scope;
 use timely::ExchangeData;
 
-use dynamic_scaling_mechanism::Control;
+use dynamic_scaling_mechanism::{Control, StatefulConfig};
 use dynamic_scaling_mechanism::notificator::{Notify, TotalOrderFrontierNotificator};
 use dynamic_scaling_mechanism::state_machine::BinnedStateMachine;
 
@@ -122,6 +122,7 @@ fn main() {
         .arg(Arg::with_name("migration").long("migration").takes_value(true).required(true))
         .arg(Arg::with_name("domain").long("domain").takes_value(true).required(true))
         .arg(Arg::with_name("validate").long("validate"))
+        .arg(Arg::with_name("bin_shift").long("bin_shift").takes_value(true).required(false))
         .arg(Arg::with_name("timely").multiple(true))
         .arg(Arg::with_name("backend").long("backend").takes_value(true).possible_values(&["hashmap", "hashmapnative", "vec", "vecnative"]).default_value("hashmap"))
         .get_matches();
@@ -136,6 +137,9 @@ fn main() {
 
     let validate: bool = matches.is_present("validate");
 
+    let stateful_config = matches.value_of("bin_shift").map_or(StatefulConfig::new(), |arg| StatefulConfig::new().with_bin_shift(arg.parse().expect("couldn't parse bin_shift")));
+    let bin_shift = stateful_config.bin_shift();
+
     let backend: Backend = match matches.value_of("backend").expect("backend missing") {
         "hashmap" => Backend::HashMap,
         "hashmapnative" => Backend::HashMapNative,
@@ -207,11 +211,11 @@ fn main() {
                                 match backend {
                                     Backend::Vector => {
                                         let mut session = output.session(cap);
-                                        let max_number = (key_space >> ::dynamic_scaling_mechanism::BIN_SHIFT).next_power_of_two();
+                                        let max_number = (key_space >> bin_shift).next_power_of_two();
                                         println!("max_number: {}", max_number);
-                                        let bin_count = 1 << ::dynamic_scaling_mechanism::BIN_SHIFT;
+                                        let bin_count = 1 << bin_shift;
                                         for bin in index * bin_count / peers..(index + 1) * bin_count / peers {
-                                            let number = word_generator.word_at((max_number << ::dynamic_scaling_mechanism::BIN_SHIFT) + bin);
+                                            let number = word_generator.word_at((max_number << bin_shift) + bin);
                                             assert!(number < 2 * key_space);
                                             session.give((number, 1));
                                         }
@@ -263,7 +267,7 @@ fn main() {
                         .stateful_state_machine(|key: &_, val, agg: &mut u64| {
                             *agg += val;
                             (false, Some((*key, *agg)))
-                        }, |key| calculate_hash(key), &control)
+                        }, |key| calculate_hash(key), &control, &stateful_config)
                         .probe_with(&mut probe))
                 },
                 Backend::HashMapNative => {
@@ -302,7 +306,7 @@ fn main() {
             let vec_output = match backend {
                 Backend::Vector => {
                     Some(input
-                        .stateful_unary(&control, move |(k, _v)| (*k as u64) << (64 - ::dynamic_scaling_mechanism::BIN_SHIFT), "StateMachine", move |cap, data, bin, output| {
+                        .stateful_unary(&control, &stateful_config, move |(k, _v)| (*k as u64) << (64 - bin_shift), "StateMachine", move |cap, data, bin, output| {
                             let states: &mut Vec<u64> = bin.state();
                             let mut session_cap = cap.clone();
                             for (time, (key, val)) in data.drain(..) {
@@ -311,7 +315,7 @@ fn main() {
                                 }
                                 let mut session = output.session(&session_cap);
                                 let states_len = states.len();
-                                let position = key >> ::dynamic_scaling_mechanism::BIN_SHIFT;
+                                let position = key >> bin_shift;
                                 if states.len() <= position {
                                     states.extend(::std::iter::repeat(0).take(position - states_len + 1))
                                 }
@@ -374,10 +378,10 @@ fn main() {
             }
         });
 
-        let mut instructions = map_mode.instructions(peers, duration_ns).unwrap();
+        let mut instructions = map_mode.instructions(peers, stateful_config.bins(), duration_ns).unwrap();
 
         if index == 0 {
-            println!("bin_shift\t{}", ::dynamic_scaling_mechanism::BIN_SHIFT);
+            println!("bin_shift\t{}", bin_shift);
 
             for instruction in instructions.iter().take(10) {
                 // Format instructions first to be able to truncate the string representation
@@ -3,7 +3,7 @@ use timely::dataflow::{Scope, Stream};
 use timely::dataflow::operators::capture::event::link::EventLink;
 use timely::dataflow::operators::capture::Replay;
 
-use dynamic_scaling_mechanism::Control;
+use dynamic_scaling_mechanism::{Control, StatefulConfig};
 use event::{Bid, Auction, Person, Date};
 
 mod q1;
@@ -51,6 +51,7 @@ pub struct NexmarkInput<'a> {
     pub people: &'a Rc<EventLink<usize, Person>>,
     pub closed_auctions: &'a Rc<EventLink<usize, (Auction, Bid)>>,
     pub closed_auctions_flex: &'a Rc<EventLink<usize, (Auction, Bid)>>,
+    pub config: &'a StatefulConfig<usize>,
 }
 
 impl<'a> NexmarkInput<'a> {
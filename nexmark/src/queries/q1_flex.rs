@@ -12,6 +12,6 @@ pub fn q1_flex<S: Scope<Timestamp=usize>>(input: &NexmarkInput, _nt: NexmarkTime
     let control = input.control(scope);
 
     input.bids(scope)
-        .distribute(&control, |bid| calculate_hash(&bid.auction), "q0-flex")
+        .distribute(&control, input.config, |bid| calculate_hash(&bid.auction), "q0-flex")
         .map_in_place(|(_, _, b)| b.price = (b.price * 89) / 100)
 }
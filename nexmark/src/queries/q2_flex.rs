@@ -12,7 +12,7 @@ pub fn q2_flex<S: Scope<Timestamp=usize>>(input: &NexmarkInput, _nt: NexmarkTime
 
     let auction_skip = 123;
     let state_stream = input.bids(scope)
-        .distribute(&control, |bid| calculate_hash(&bid.auction), "q2-flex");
+        .distribute(&control, input.config, |bid| calculate_hash(&bid.auction), "q2-flex");
     state_stream
         .filter(move |(_, _, b)| b.auction % auction_skip == 0)
         .map(|(_, _, b)| (b.auction, b.price))
@@ -19,7 +19,7 @@ pub fn q3_flex<S: Scope<Timestamp=usize>>(input: &NexmarkInput, _nt: NexmarkTime
     let people = input.people(scope)
         .filter(|p| p.state == "OR" || p.state == "ID" || p.state == "CA");
 
-    auctions.stateful_binary(&control, &people, |a| calculate_hash(&a.seller), |p| calculate_hash(&p.id), "q3-flex join", |cap, data, auction_bin, people_bin, output| {
+    auctions.stateful_binary(&control, input.config, &people, |a| calculate_hash(&a.seller), |p| calculate_hash(&p.id), "q3-flex join", |cap, data, auction_bin, people_bin, output| {
         let mut session = output.session(&cap);
         let people_state: &mut HashMap<_, Person> = people_bin.state();
         for (_time, auction) in data.drain(..) {
@@ -14,7 +14,7 @@ pub fn q4_flex<S: Scope<Timestamp=usize>>(input: &NexmarkInput, _nt: NexmarkTime
 
     input.closed_auctions_flex(scope)
         .map(|(a, b)| (a.category, b.price))
-        .stateful_unary(&control, |x: &(usize, usize)| calculate_hash(&x.0), "Q4 Average",
+        .stateful_unary(&control, input.config, |x: &(usize, usize)| calculate_hash(&x.0), "Q4 Average",
                         |cap, data, bin, output| {
                             let mut session = output.session(&cap);
                             let state: &mut HashMap<_, _> = bin.state();
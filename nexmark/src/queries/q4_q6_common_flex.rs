@@ -16,7 +16,7 @@ pub fn q4_q6_common_flex<S: Scope<Timestamp=usize>>(input: &NexmarkInput, nt: Ne
     let bids = input.bids(scope);
     let auctions = input.auctions(scope);
 
-    bids.stateful_binary_input(&control,
+    bids.stateful_binary_input(&control, input.config,
                                &auctions,
                                |bid: &Bid| calculate_hash(&bid.auction),
                                |a: &Auction| calculate_hash(&a.id),
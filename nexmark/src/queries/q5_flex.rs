@@ -1,59 +1,49 @@
-
-use ::std::collections::HashMap;
 use ::timely::dataflow::{Scope, Stream};
 use timely::dataflow::operators::Map;
 
-use dynamic_scaling_mechanism::operator::StatefulOperator;
+use dynamic_scaling_mechanism::windows::{Aggregate, WindowOperator, Windows};
 use ::event::Date;
 use ::calculate_hash;
 
 use {queries::NexmarkInput, queries::NexmarkTimer};
 
+/// Counts bids per auction, reporting the auction with the most bids.
+struct HotAuction;
+
+impl Aggregate<(usize, usize)> for HotAuction {
+    // Bid counts, sorted by auction
+    type State = Vec<(usize, u64)>;
+    type Output = Option<usize>;
+    fn add(&self, state: &mut Self::State, &(auction, _date_time): &(usize, usize)) {
+        match state.binary_search_by_key(&auction, |&(a, _)| a) {
+            Ok(index) => state[index].1 += 1,
+            Err(index) => state.insert(index, (auction, 1)),
+        }
+    }
+    fn merge(&self, state: &mut Self::State, other: Self::State) {
+        for (auction, count) in other {
+            match state.binary_search_by_key(&auction, |&(a, _)| a) {
+                Ok(index) => state[index].1 += count,
+                Err(index) => state.insert(index, (auction, count)),
+            }
+        }
+    }
+    fn result(&self, state: &Self::State) -> Option<usize> {
+        state.iter().max_by_key(|&&(_, count)| count).map(|&(auction, _)| auction)
+    }
+}
+
 pub fn q5_flex<S: Scope<Timestamp=usize>>(input: &NexmarkInput, nt: NexmarkTimer, scope: &mut S, window_slice_count: usize, window_slide_ns: usize) -> Stream<S, usize>
 {
     let control = input.control(scope);
 
     let bids = input.bids(scope)
-        // Discretize bid's datetime based on slides
-        .map(move |b| (b.auction, Date::new(((*b.date_time / window_slide_ns) + 1) * window_slide_ns)));
-    // TODO: Could pre-aggregate pre-exchange, if there was reason to do so.
-
-    #[derive(Abomonation, Eq, PartialEq, Clone)]
-    enum InsDel<T> { Ins(T), Del(T) };
-    let mut in_buffer = Vec::new();
-
-    // Partitions by auction id
-    bids.stateful_unary_input(&control, |(auction, _time)| calculate_hash(auction), "q5-flex", move |state, cap, _time, data, _output| {
-        data.swap(&mut in_buffer);
-
-        for (_, key_id, (auction, a_time)) in in_buffer.drain(..) {
-            let not = state.get(key_id).notificator();
-            not.notify_at_data(cap, nt.from_nexmark_time(a_time), InsDel::Ins(auction));
-            not.notify_at_data(cap, nt.from_nexmark_time(Date::new(*a_time + window_slice_count * window_slide_ns)), InsDel::Del(auction));
-        }
-    }, move |cap, data, bid_bin, output| {
-        // Process additions (if any)
-//        println!("data.len(): {:?}", data.len());
-        for (_time, action) in data.drain(..) {
-            match action {
-                InsDel::Ins(auction) => {
-                    // Stash pending deletions
-                    let bid_state: &mut HashMap<_, _> = bid_bin.state();
-                    let slot = bid_state.entry(auction).or_insert(0);
-                    *slot += 1;
-                },
-                InsDel::Del(auction) => {
-                    let slot = bid_bin.state().entry(auction).or_insert(0);
-                    *slot -= 1;
-                }
-            }
-        }
-        bid_bin.state().retain(|_k, v| *v != 0);
-        // Output results (if any)
-        // TODO: This only accumulates per *bin*, not globally!
-        if let Some((auction, _count)) = bid_bin.state().iter().max_by_key(|(_auction_id, count)| *count) {
-            let mut session = output.session(&cap);
-            session.give(*auction);
-        }
-    })
+        .map(move |b| (b.auction, *b.date_time));
+
+    // Partitions by auction id, pre-aggregates per bin
+    // TODO: This only accumulates per *bin*, not globally!
+    let windows = Windows::sliding((window_slice_count * window_slide_ns) as u64, window_slide_ns as u64).per_bin();
+    bids.window(&control, input.config, windows, |&(auction, _date_time)| calculate_hash(&auction), |&(_auction, date_time)| date_time as u64,
+        move |end| nt.from_nexmark_time(Date::new(end as usize)), HotAuction, "q5-flex")
+        .flat_map(|(_bin, _start, _end, auction)| auction)
 }
@@ -14,7 +14,7 @@ pub fn q6_flex<S: Scope<Timestamp=usize>>(input: &NexmarkInput, _nt: NexmarkTime
     let winners = input.closed_auctions_flex(scope)
         .map(|(_a, b)| (b.bidder, b.price));
 
-    winners.stateful_unary(&control, |(b, _p)| calculate_hash(b), "q6-flex", |cap, data, bin, output| {
+    winners.stateful_unary(&control, input.config, |(b, _p)| calculate_hash(b), "q6-flex", |cap, data, bin, output| {
         let mut session = output.session(&cap);
         let state: &mut HashMap<_, _> = bin.state();
         for (_time, (bidder, price)) in data.drain(..) {
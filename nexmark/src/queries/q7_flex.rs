@@ -4,7 +4,7 @@ use ::timely::dataflow::{Scope, Stream};
 use timely::dataflow::channels::pact::Exchange;
 use timely::dataflow::operators::{FrontierNotificator, Map, Operator};
 
-use dynamic_scaling_mechanism::operator::StatefulOperator;
+use dynamic_scaling_mechanism::windows::{Max, WindowOperator, Windows};
 use ::event::Date;
 use ::calculate_hash;
 
@@ -15,28 +15,12 @@ pub fn q7_flex<S: Scope<Timestamp=usize>>(input: &NexmarkInput, nt: NexmarkTimer
     let control = input.control(scope);
 
     let bids = input.bids(scope)
-        .map(move |b| (b.auction, Date::new(((*b.date_time / window_size_ns) + 1) * window_size_ns), b.price));
+        .map(move |b| (b.auction, *b.date_time, b.price));
 
-
-    // Partition by auction id to avoid serializing the computation
-    bids.stateful_unary_input(&control, |(a, _window, _price)| calculate_hash(a), "q7-flex pre-reduce", move |state, cap, _time, data, _output| {
-        for (_, key_id, (_auction, window, price)) in data.iter() {
-            let not = state.get(*key_id).notificator();
-            not.notify_at_data(cap, nt.from_nexmark_time(*window), (*window, *price));
-        }
-    }, |cap, maxima, bid_bin, output| {
-        let mut windows = HashMap::new();
-        let bid_state: &mut HashMap<_, _> = bid_bin.state();
-        for (_time, (window, price)) in maxima.drain(..) {
-            let open_windows = bid_state.entry(window).or_insert(0);
-            if *open_windows < price {
-                *open_windows = price;
-                windows.insert(window, price);
-            }
-        }
-        let mut session = output.session(&cap);
-        session.give_iterator(windows.drain());
-    })
+    // Partition by auction id to avoid serializing the computation, pre-aggregate per bin
+    bids.window(&control, input.config, Windows::tumbling(window_size_ns as u64).per_bin(), |&(a, _date_time, _price)| calculate_hash(&a), |&(_a, date_time, _price)| date_time as u64,
+        move |end| nt.from_nexmark_time(Date::new(end as usize)), Max(|&(_a, _date_time, price): &(usize, usize, usize)| price as u64), "q7-flex pre-reduce")
+        .map(|(_bin, _start, end, price)| (Date::new(end as usize), price as usize))
         // Aggregate the partial counts. This doesn't need to be stateful since we request notification upon a window firing time and then we drop the state immediately after processing
         .unary_frontier(Exchange::new(move |x: &(Date, usize)| (*x.0 / window_size_ns) as u64), "Q7 All-reduce", |_cap, _info|
             {
@@ -20,7 +20,7 @@ pub fn q8_flex<S: Scope<Timestamp=usize>>(input: &NexmarkInput, _nt: NexmarkTime
         .map(|p| (p.id, p.date_time));
 
     let window_size_ns = 12 * 60 * 60 * 1_000_000_000;
-    people.stateful_binary(&control, &auctions, |(p, _d)| calculate_hash(p), |(s, _d)| calculate_hash(s), "q8-flex", |_cap, data, people_bin: &mut Bin<_, HashMap<_, _>, _>, _auctions_state: &mut Bin<_, Vec<()>, _>, _output| {
+    people.stateful_binary(&control, input.config, &auctions, |(p, _d)| calculate_hash(p), |(s, _d)| calculate_hash(s), "q8-flex", |_cap, data, people_bin: &mut Bin<_, HashMap<_, _>, _>, _auctions_state: &mut Bin<_, Vec<()>, _>, _output| {
         // Update people state
         for (_time, (person, date)) in data.drain(..) {
             people_bin.state().entry(person as u64).or_insert(*date);
@@ -1,5 +1,6 @@
 use streaming_harness::util::ToNanos;
 use dynamic_scaling_mechanism::{ControlInst};
+use dynamic_scaling_mechanism::plan::Plan;
 
 #[derive(Clone, Copy, Debug)]
 pub enum ParseError {}
@@ -29,17 +30,17 @@ impl ::std::str::FromStr for ExperimentMapMode {
 }
 
 impl ExperimentMapMode {
-    pub fn instructions(&self, peers: usize, duration_ns: u64) -> Result<Vec<(u64, Vec<ControlInst>)>, String> {
+    pub fn instructions(&self, peers: usize, bins: usize, duration_ns: u64) -> Result<Vec<(u64, Vec<ControlInst>)>, String> {
         match self {
             ExperimentMapMode::None => {
-                let mut map = vec![0; 1 << ::dynamic_scaling_mechanism::BIN_SHIFT];
+                let mut map = vec![0; bins];
                 for (i, element) in map.iter_mut().enumerate() {
                     *element = i % peers;
                 };
                 Ok(vec![(0, vec![ControlInst::Map(map)])])
             }
             ExperimentMapMode::Sudden => {
-                let mut map = vec![0; 1 << ::dynamic_scaling_mechanism::BIN_SHIFT];
+                let mut map = vec![0; bins];
                 // TODO(moritzo) HAAAACCCCKKK
                 if peers != 2 {
                     for (i, v) in map.iter_mut().enumerate() {
@@ -59,7 +60,7 @@ impl ExperimentMapMode {
                 Ok(vec![(duration_ns/3, vec![ControlInst::Map(initial_map)]), (2*duration_ns/3, vec![ControlInst::Map(map)])])
             },
             ExperimentMapMode::Fluid => {
-                let mut map = vec![0; 1 << ::dynamic_scaling_mechanism::BIN_SHIFT];
+                let mut map = vec![0; bins];
                 // TODO(moritzo) HAAAACCCCKKK
                 if peers != 2 {
                     for (i, v) in map.iter_mut().enumerate() {
@@ -76,27 +77,8 @@ impl ExperimentMapMode {
                 Ok(configurations)
             },
             ExperimentMapMode::File(migrations_file) => {
-                let f = ::std::fs::File::open(migrations_file).map_err(|e| e.to_string())?;
-                let file = ::std::io::BufReader::new(&f);
-                use ::std::io::BufRead;
-                let mut instructions = Vec::new();
-                for line in file.lines() {
-                    let line = line.map_err(|e| e.to_string())?;
-                    let mut parts = line.split_whitespace();
-                    let indicator = parts.next().expect("Missing map/diff indicator");
-                    let ts: u64 = parts.next().expect("missing time stamp").parse().expect("Failed to parse time stamp");
-                    let instr = match indicator {
-                        "M" => (ts, vec![ControlInst::Map(parts.map(|x| x.parse().expect("Failed to parse parts")).collect())]),
-                        "D" => {
-                            let parts: Vec<usize> = parts.map(|x| x.parse().unwrap()).collect();
-                            let inst = parts.chunks(2).map(|x| ControlInst::Move(::dynamic_scaling_mechanism::BinId::new(x[0]), x[1])).collect();
-                            (ts, inst)
-                        },
-                        _ => return Err("Incorrect input found in map file".to_string()),
-                    };
-                    instructions.push(instr);
-                }
-                Ok(instructions)
+                let plan = Plan::read(migrations_file, bins, peers).map_err(|e| e.to_string())?;
+                Ok(plan.steps().to_vec())
             },
 //            _ => panic!("unsupported map mode"),
         }
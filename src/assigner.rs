@@ -0,0 +1,193 @@
+//! Strategies to assign keys to bins.
+//!
+//! A [`BinAssigner`] maps a key to a position in the key space. Bins cover ranges of positions,
+//! initially the `1 << bin_shift` ranges sharing the same `bin_shift` most significant bits, see
+//! [`BinLayout`].
+//!
+//! [`BinAssigner`]: trait.BinAssigner.html
+//! [`BinLayout`]: ../struct.BinLayout.html
+
+use ::ControlError;
+
+/// Multiplier for Fibonacci hashing, `2^64` divided by the golden ratio, rounded to an odd number.
+const FIBONACCI: u64 = 0x9E37_79B9_7F4A_7C15;
+
+/// Maps keys to positions, which determine a key's bin.
+///
+/// Assigners may be updated at runtime with `ControlInst::Reassign`. Moving keys between bins
+/// requires the stateful operator to be able to determine the keys of its state.
+pub trait BinAssigner: ::std::fmt::Debug + Send + Sync {
+    /// The position of `key`. The `bin_shift` most significant bits of the position select the
+    /// initial bin.
+    fn position(&self, key: u64, bin_shift: usize) -> u64;
+
+    /// Test if the assigner supports `1 << bin_shift` initial bins.
+    fn check(&self, _bin_shift: usize) -> Result<(), ControlError> {
+        Ok(())
+    }
+
+    /// Update the assigner's parameters. Returns an error if the assigner does not support
+    /// updates or the parameters are invalid.
+    fn update(&mut self, _parameters: &[u64]) -> Result<(), ControlError> {
+        Err(ControlError::InvalidAssignment)
+    }
+
+    /// The parameters last provided to `update`, if any. Checkpoints record the parameters to
+    /// restore the assigner.
+    fn parameters(&self) -> Vec<u64> {
+        Vec::new()
+    }
+
+    /// Clone the assigner into a box.
+    fn clone_box(&self) -> Box<dyn BinAssigner>;
+}
+
+impl Clone for Box<dyn BinAssigner> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+/// Assigns keys by their most significant bits. This is the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TopBits;
+
+impl BinAssigner for TopBits {
+    #[inline(always)]
+    fn position(&self, key: u64, _bin_shift: usize) -> u64 {
+        key
+    }
+
+    fn clone_box(&self) -> Box<dyn BinAssigner> {
+        Box::new(*self)
+    }
+}
+
+/// Mixes all bits of a key into its most significant bits with Fibonacci hashing, whose top bits
+/// then select the bin. Suitable for keys with weak high bits, such as small integers.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FastRange;
+
+impl BinAssigner for FastRange {
+    #[inline(always)]
+    fn position(&self, key: u64, _bin_shift: usize) -> u64 {
+        key.wrapping_mul(FIBONACCI)
+    }
+
+    fn clone_box(&self) -> Box<dyn BinAssigner> {
+        Box::new(*self)
+    }
+}
+
+/// Assigns keys to initial bins with jump consistent hashing. The remaining bits of the position
+/// are a mix of the key's bits.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JumpHash;
+
+impl BinAssigner for JumpHash {
+    fn position(&self, key: u64, bin_shift: usize) -> u64 {
+        if bin_shift == 0 {
+            key
+        } else {
+            let bin = jump_consistent_hash(key, 1 << bin_shift);
+            (bin << (64 - bin_shift)) | (key.wrapping_mul(FIBONACCI) >> bin_shift)
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn BinAssigner> {
+        Box::new(*self)
+    }
+}
+
+/// Jump consistent hash by Lamping and Veach.
+fn jump_consistent_hash(mut key: u64, buckets: u64) -> u64 {
+    let mut bucket = -1i64;
+    let mut jump = 0i64;
+    while jump < buckets as i64 {
+        bucket = jump;
+        key = key.wrapping_mul(2_862_933_555_777_941_757).wrapping_add(1);
+        jump = ((bucket + 1) as f64 * ((1i64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    bucket as u64
+}
+
+/// Assigns keys to initial bins by an explicit table of key ranges. Initial bin `i` covers the
+/// keys from `boundaries[i]` up to, but excluding, `boundaries[i + 1]`.
+///
+/// The boundaries can be moved at runtime by providing a new table of the same length.
+#[derive(Clone, Debug)]
+pub struct KeyRanges {
+    boundaries: Vec<u64>,
+}
+
+impl KeyRanges {
+    /// Construct a table from the start of each initial bin's range. There need to be
+    /// `1 << bin_shift` boundaries, the first being 0, in strictly increasing order.
+    pub fn new(boundaries: Vec<u64>) -> Self {
+        assert!(Self::check(&boundaries).is_ok(), "Invalid boundaries: {:?}", boundaries);
+        Self { boundaries }
+    }
+
+    /// Construct a table of `1 << bin_shift` ranges of equal size.
+    pub fn uniform(bin_shift: usize) -> Self {
+        let boundaries = if bin_shift == 0 {
+            vec![0]
+        } else {
+            (0..1u64 << bin_shift).map(|bin| bin << (64 - bin_shift)).collect()
+        };
+        Self { boundaries }
+    }
+
+    /// The start of each initial bin's range.
+    pub fn boundaries(&self) -> &[u64] {
+        &self.boundaries
+    }
+
+    fn check(boundaries: &[u64]) -> Result<(), ControlError> {
+        if boundaries.first() != Some(&0) || boundaries.windows(2).any(|w| w[0] >= w[1]) {
+            Err(ControlError::InvalidAssignment)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl BinAssigner for KeyRanges {
+    fn position(&self, key: u64, bin_shift: usize) -> u64 {
+        if bin_shift == 0 {
+            key
+        } else {
+            let bin = match self.boundaries.binary_search(&key) {
+                Ok(bin) => bin,
+                Err(bin) => bin - 1,
+            };
+            // Preserve the order of keys within the range
+            ((bin as u64) << (64 - bin_shift)) | ((key - self.boundaries[bin]) >> bin_shift)
+        }
+    }
+
+    fn check(&self, bin_shift: usize) -> Result<(), ControlError> {
+        if self.boundaries.len() == 1 << bin_shift {
+            Ok(())
+        } else {
+            Err(ControlError::InvalidAssignment)
+        }
+    }
+
+    fn update(&mut self, parameters: &[u64]) -> Result<(), ControlError> {
+        if parameters.len() != self.boundaries.len() {
+            return Err(ControlError::InvalidAssignment);
+        }
+        Self::check(parameters)?;
+        self.boundaries = parameters.to_vec();
+        Ok(())
+    }
+
+    fn parameters(&self) -> Vec<u64> {
+        self.boundaries.clone()
+    }
+
+    fn clone_box(&self) -> Box<dyn BinAssigner> {
+        Box::new(self.clone())
+    }
+}
@@ -0,0 +1,248 @@
+//! Durable checkpoints of the state of stateful operators.
+//!
+//! A batch of control instructions containing `ControlInst::Checkpoint` instructs each worker to
+//! write the bins it owns to the operator's checkpoint directory. The checkpoint is taken once all
+//! records before the batch's time are applied, and before any record at or after the batch's
+//! time is. It captures the configuration in place before the batch. With
+//! `StatefulConfig::with_checkpoint_interval`, workers additionally write a checkpoint whenever
+//! the control frontier passes another multiple of the interval.
+//!
+//! Checkpoint `sequence` is written to `{directory}/{sequence}`, and periodic checkpoint `round`
+//! of configuration `sequence` to `{directory}/{sequence}-{round}`. Each bin is written to a file
+//! `bin-{bin}`, encoded with the operator's `Codec`. Once its bins are written, worker `index`
+//! describes the configuration in `worker-{index}`. A checkpoint is complete once all workers
+//! wrote their description. Failures to write a checkpoint are reported on the operator's
+//! diagnostics stream as `ControlError::CheckpointFailed`.
+//!
+//! A dataflow started with `StatefulConfig::with_restore` rebuilds its configuration and bins from
+//! a checkpoint. Input needs to be replayed from the checkpoint's `frontier`, and control batches
+//! continue after the checkpoint's sequence number. Workers read the bins they own from the
+//! checkpoint, regardless of which worker wrote them: the checkpoint directory needs to be shared
+//! by all workers, or copied to each of them. Failures to restore a checkpoint are reported as
+//! `ControlError::RestoreFailed`, and the operator starts from its initial configuration.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use abomonation::Abomonation;
+use timely::ExchangeData;
+use timely::order::TotalOrder;
+use timely::progress::Timestamp;
+use timely::progress::frontier::Antichain;
+
+use assigner::BinAssigner;
+use codec::{Codec, CodecStats};
+use migratable::MigratableState;
+use ::{BinLayout, ControlSet, State};
+
+/// A worker's description of a checkpoint.
+#[derive(Abomonation, Clone, Debug)]
+struct Manifest<T> {
+    // Number of workers writing the checkpoint
+    peers: usize,
+    sequence: u64,
+    frontier: Vec<T>,
+    map: Vec<usize>,
+    bin_shift: usize,
+    generation: u64,
+    prefixes: Vec<Option<(u64, usize)>>,
+    // Parameters of the key assigner
+    parameters: Vec<u64>,
+    codec: Codec,
+    // Round of the next periodic checkpoint
+    round: u64,
+}
+
+/// The directory of checkpoint `sequence` in `directory`.
+pub fn path(directory: &Path, sequence: u64) -> PathBuf {
+    directory.join(sequence.to_string())
+}
+
+/// The directory of periodic checkpoint `round` of configuration `sequence` in `directory`.
+pub fn periodic_path(directory: &Path, sequence: u64, round: u64) -> PathBuf {
+    directory.join(format!("{}-{}", sequence, round))
+}
+
+/// Parse the name of a checkpoint directory into its sequence number and periodic round.
+fn parse_name(name: &str) -> Option<(u64, Option<u64>)> {
+    let mut parts = name.splitn(2, '-');
+    let sequence = parts.next()?.parse().ok()?;
+    match parts.next() {
+        Some(round) => round.parse().ok().map(|round| (sequence, Some(round))),
+        None => Some((sequence, None)),
+    }
+}
+
+/// The most recent complete checkpoint in `directory`, if any.
+pub fn latest<T: Abomonation+Clone>(directory: &Path) -> io::Result<Option<PathBuf>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        if let Some(name) = entry?.file_name().to_str().and_then(parse_name) {
+            names.push(name);
+        }
+    }
+    // Periodic checkpoints of a configuration follow the checkpoint requested with it
+    names.sort();
+    for (sequence, round) in names.into_iter().rev() {
+        let checkpoint = match round {
+            Some(round) => periodic_path(directory, sequence, round),
+            None => path(directory, sequence),
+        };
+        if is_complete::<T>(&checkpoint)? {
+            return Ok(Some(checkpoint));
+        }
+    }
+    Ok(None)
+}
+
+/// The frontier of `checkpoint`. Input needs to be replayed from this frontier.
+pub fn frontier<T: Abomonation+Clone>(checkpoint: &Path) -> io::Result<Vec<T>> {
+    Ok(read_manifest::<T>(checkpoint, 0)?.frontier)
+}
+
+/// Test if all workers completed `checkpoint`.
+fn is_complete<T: Abomonation+Clone>(checkpoint: &Path) -> io::Result<bool> {
+    match read_manifest::<T>(checkpoint, 0) {
+        Ok(manifest) => Ok((1..manifest.peers).all(|worker| manifest_path(checkpoint, worker).exists())),
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(error) => Err(error),
+    }
+}
+
+fn manifest_path(checkpoint: &Path, worker: usize) -> PathBuf {
+    checkpoint.join(format!("worker-{}", worker))
+}
+
+fn bin_path(checkpoint: &Path, bin: usize) -> PathBuf {
+    checkpoint.join(format!("bin-{}", bin))
+}
+
+/// Write `bytes` to `path`, replacing it atomically.
+pub(crate) fn write_file(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let temporary = path.with_extension("tmp");
+    {
+        let mut file = File::create(&temporary)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+    }
+    fs::rename(&temporary, path)
+}
+
+/// Read the contents of `path`.
+pub(crate) fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_manifest<T: Abomonation+Clone>(checkpoint: &Path, worker: usize) -> io::Result<Manifest<T>> {
+    let bytes = read_file(&manifest_path(checkpoint, worker))?;
+    Codec::None.decode::<Manifest<T>>(&bytes).pop()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "empty checkpoint description"))
+}
+
+/// Write the bins `index` owns in `config` to `checkpoint`, describing it as checkpoint `sequence`
+/// at `frontier`, followed by periodic checkpoint `round`.
+pub(crate) fn write<T, D, W, M>(checkpoint: &Path, sequence: u64, round: u64, frontier: &Antichain<T>, index: usize, peers: usize, config: &ControlSet<T>, states: &mut State<T, D, M>, codec: Codec) -> io::Result<()>
+    where
+        T: Timestamp+TotalOrder,
+        D: MigratableState<W>,
+        W: ExchangeData,
+        M: ExchangeData,
+{
+    fs::create_dir_all(checkpoint)?;
+    let mut stats = CodecStats::default();
+    for bin in config.layout().active().filter(|bin| config.map()[*bin] == index) {
+        let state = states.bins[bin].as_mut().expect("Checkpointing bin but it is None");
+        // Copy the items of spilled bins from their files, copy the state's items, or take them
+        // and rebuild the state if it cannot copy them
+        let encoded = match states.spill {
+            Some(ref spill) if state.spilled => spill.encoded::<W>(bin, codec, &mut stats)?,
+            _ => match state.data.items() {
+                Some(items) => codec.encode(&items, &mut stats),
+                None => {
+                    let items: Vec<W> = ::std::mem::replace(&mut state.data, Default::default()).snapshot().collect();
+                    let encoded = codec.encode(&items, &mut stats);
+                    state.data.restore(items);
+                    encoded
+                },
+            },
+        };
+        let pending: Vec<(T, M)> = state.notificator.iter_pending().map(|(t, d)| (t.clone(), d.clone())).collect();
+        // The length of the encoded items, followed by the items and pending notifications
+        let mut bytes = Vec::new();
+        for shift in 0..8 {
+            bytes.push((encoded.len() as u64 >> (8 * shift)) as u8);
+        }
+        bytes.extend_from_slice(&encoded);
+        bytes.extend_from_slice(&codec.encode(&pending, &mut stats));
+        write_file(&bin_path(checkpoint, bin), &bytes)?;
+    }
+    let layout = config.layout();
+    let manifest = Manifest {
+        peers,
+        sequence,
+        frontier: frontier.elements().to_vec(),
+        map: config.map().clone(),
+        bin_shift: layout.bin_shift,
+        generation: layout.generation,
+        prefixes: layout.prefixes.clone(),
+        parameters: layout.assigner().parameters(),
+        codec,
+        round,
+    };
+    write_file(&manifest_path(checkpoint, index), &Codec::None.encode(&vec![manifest], &mut stats))
+}
+
+/// Read the configuration of `checkpoint`, the round of the next periodic checkpoint, and the
+/// items and pending notifications of the bins `index` owns. Bins of workers beyond `peers` are
+/// distributed over the remaining workers, which read them from files other workers wrote.
+pub(crate) fn restore<T, W, M>(checkpoint: &Path, index: usize, peers: usize, mut assigner: Box<dyn BinAssigner>) -> io::Result<(ControlSet<T>, u64, Vec<(usize, Vec<W>, Vec<(T, M)>)>)>
+    where
+        T: Timestamp,
+        W: ExchangeData,
+        M: ExchangeData,
+{
+    let manifest = read_manifest::<T>(checkpoint, 0)?;
+    let map: Vec<usize> = manifest.map.iter().map(|worker| worker % peers).collect();
+    if !manifest.parameters.is_empty() {
+        assigner.update(&manifest.parameters).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    }
+    let mut layout = BinLayout {
+        bin_shift: manifest.bin_shift,
+        assigner,
+        generation: manifest.generation,
+        prefixes: manifest.prefixes,
+        ranges: Vec::new(),
+    };
+    if layout.prefixes.iter().any(|prefix| prefix.map_or(true, |(_, len)| len != layout.bin_shift)) {
+        layout.update_ranges();
+    }
+
+    let mut bins = Vec::new();
+    for bin in layout.active().filter(|bin| map[*bin] == index) {
+        let path = bin_path(checkpoint, bin);
+        let bytes = read_file(&path).map_err(|error| io::Error::new(error.kind(), format!("{}: {}", path.display(), error)))?;
+        if bytes.len() < 8 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated bin"));
+        }
+        let len = (0..8).fold(0, |len, shift| len | (u64::from(bytes[shift]) << (8 * shift))) as usize;
+        let items = manifest.codec.decode(&bytes[8..8 + len]);
+        let pending = manifest.codec.decode(&bytes[8 + len..]);
+        bins.push((bin, items, pending));
+    }
+
+    let mut frontier = Antichain::new();
+    for time in manifest.frontier {
+        frontier.insert(time);
+    }
+    let config = ControlSet {
+        sequence: manifest.sequence,
+        frontier,
+        map,
+        layout,
+        checkpoint: false,
+    };
+    Ok((config, manifest.round, bins))
+}
@@ -0,0 +1,223 @@
+//! Encoding of migrated state on the wire.
+//!
+//! Chunks of state sent to other workers can be compressed by selecting a [`Codec`] in the
+//! operator's `StatefulConfig`. The codec is sent along with each chunk, receivers need no
+//! configuration.
+//!
+//! [`Codec`]: enum.Codec.html
+
+use std::time::Instant;
+
+use abomonation::Abomonation;
+
+/// Minimum length of a match.
+const MIN_MATCH: usize = 4;
+/// The last match has to start this many bytes before the end of the input.
+const MATCH_LIMIT: usize = 12;
+/// The last bytes of the input are always literals.
+const LAST_LITERALS: usize = 5;
+/// Bits of the hash table used to find matches.
+const HASH_LOG: usize = 12;
+/// Largest distance of a match.
+const MAX_OFFSET: usize = 0xFFFF;
+
+/// Encodings of migrated state.
+#[derive(Abomonation, Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Codec {
+    /// Send state as is. This is the default.
+    None,
+    /// Compress state with LZ4 block compression.
+    Lz4,
+}
+
+impl Codec {
+    /// Serialize and encode `items`, recording the sizes and time spent in `stats`.
+    pub fn encode<W: Abomonation>(self, items: &Vec<W>, stats: &mut CodecStats) -> Vec<u8> {
+        let start = Instant::now();
+        let mut raw = Vec::new();
+        unsafe { ::abomonation::encode(items, &mut raw).expect("Failed to serialize state"); }
+        stats.raw_bytes += raw.len();
+        let encoded = match self {
+            Codec::None => raw,
+            Codec::Lz4 => {
+                let mut encoded = Vec::with_capacity(8 + raw.len() / 2);
+                // Prefix the block with the size of the decompressed data
+                for shift in 0..8 {
+                    encoded.push((raw.len() as u64 >> (8 * shift)) as u8);
+                }
+                compress(&raw, &mut encoded);
+                encoded
+            },
+        };
+        let elapsed = start.elapsed();
+        stats.encoded_bytes += encoded.len();
+        stats.encode_nanos += elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos());
+        encoded
+    }
+
+    /// Decode and deserialize `bytes` produced by `encode`. `bytes` need not be aligned.
+    pub fn decode<W: Abomonation+Clone>(self, bytes: &[u8]) -> Vec<W> {
+        let decompressed = match self {
+            Codec::None => None,
+            Codec::Lz4 => {
+                let len = (0..8).fold(0, |len, shift| len | (u64::from(bytes[shift]) << (8 * shift)));
+                let mut raw = Vec::with_capacity(len as usize);
+                decompress(&bytes[8..], &mut raw);
+                assert_eq!(raw.len() as u64, len, "Decompressed state has the wrong size");
+                Some(raw)
+            },
+        };
+        let raw = decompressed.as_ref().map_or(bytes, |raw| &raw[..]);
+        // Abomonation reads values in place, copy them to memory aligned for `u64`
+        let mut buffer = vec![0u64; (raw.len() + 7) / 8];
+        let aligned = unsafe { ::std::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, raw.len()) };
+        aligned.copy_from_slice(raw);
+        let (items, rest) = unsafe { ::abomonation::decode::<Vec<W>>(aligned) }.expect("Failed to deserialize state");
+        assert!(rest.is_empty(), "Trailing bytes after state");
+        items.clone()
+    }
+}
+
+/// Sizes of and time spent encoding state with a `Codec`.
+#[derive(Abomonation, Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CodecStats {
+    /// The number of bytes of serialized state before encoding
+    pub raw_bytes: usize,
+    /// The number of bytes of encoded state
+    pub encoded_bytes: usize,
+    /// The time spent serializing and encoding state, in nanoseconds
+    pub encode_nanos: u64,
+}
+
+impl CodecStats {
+    /// The compression ratio, i.e., the raw size divided by the encoded size. 1 if nothing was
+    /// encoded.
+    pub fn ratio(&self) -> f64 {
+        if self.encoded_bytes == 0 {
+            1.
+        } else {
+            self.raw_bytes as f64 / self.encoded_bytes as f64
+        }
+    }
+}
+
+impl ::std::ops::AddAssign for CodecStats {
+    fn add_assign(&mut self, other: Self) {
+        self.raw_bytes += other.raw_bytes;
+        self.encoded_bytes += other.encoded_bytes;
+        self.encode_nanos += other.encode_nanos;
+    }
+}
+
+/// Read four bytes at `pos`.
+#[inline(always)]
+fn read_u32(input: &[u8], pos: usize) -> u32 {
+    u32::from(input[pos])
+        | u32::from(input[pos + 1]) << 8
+        | u32::from(input[pos + 2]) << 16
+        | u32::from(input[pos + 3]) << 24
+}
+
+/// Write the remainder of a length exceeding its token's nibble.
+fn write_length(output: &mut Vec<u8>, mut length: usize) {
+    while length >= 255 {
+        output.push(255);
+        length -= 255;
+    }
+    output.push(length as u8);
+}
+
+/// Read the remainder of a length exceeding its token's nibble.
+fn read_length(input: &[u8], pos: &mut usize) -> usize {
+    let mut length = 0;
+    loop {
+        let byte = input[*pos];
+        *pos += 1;
+        length += byte as usize;
+        if byte != 255 {
+            return length;
+        }
+    }
+}
+
+/// Write a sequence of literals, followed by a match unless it is the last sequence.
+fn write_sequence(output: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let match_nibble = matched.map_or(0, |(_, length)| ::std::cmp::min(length - MIN_MATCH, 15));
+    output.push((::std::cmp::min(literals.len(), 15) << 4 | match_nibble) as u8);
+    if literals.len() >= 15 {
+        write_length(output, literals.len() - 15);
+    }
+    output.extend_from_slice(literals);
+    if let Some((offset, length)) = matched {
+        output.push(offset as u8);
+        output.push((offset >> 8) as u8);
+        if length - MIN_MATCH >= 15 {
+            write_length(output, length - MIN_MATCH - 15);
+        }
+    }
+}
+
+/// Compress `input` to an LZ4 block, appending to `output`.
+fn compress(input: &[u8], output: &mut Vec<u8>) {
+    let mut table = vec![0usize; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut pos = 0;
+    if input.len() > MATCH_LIMIT {
+        let limit = input.len() - MATCH_LIMIT;
+        while pos < limit {
+            let sequence = read_u32(input, pos);
+            let hash = (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize;
+            // Positions are stored off by one, 0 marks an empty slot
+            let candidate = table[hash];
+            table[hash] = pos + 1;
+            if candidate > 0 && pos - (candidate - 1) <= MAX_OFFSET && read_u32(input, candidate - 1) == sequence {
+                let candidate = candidate - 1;
+                let max_length = input.len() - LAST_LITERALS - pos;
+                let mut length = MIN_MATCH;
+                while length < max_length && input[candidate + length] == input[pos + length] {
+                    length += 1;
+                }
+                write_sequence(output, &input[anchor..pos], Some((pos - candidate, length)));
+                pos += length;
+                anchor = pos;
+            } else {
+                pos += 1;
+            }
+        }
+    }
+    write_sequence(output, &input[anchor..], None);
+}
+
+/// Decompress an LZ4 block, appending to `output`.
+fn decompress(input: &[u8], output: &mut Vec<u8>) {
+    let start = output.len();
+    let mut pos = 0;
+    while pos < input.len() {
+        let token = input[pos];
+        pos += 1;
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals += read_length(input, &mut pos);
+        }
+        output.extend_from_slice(&input[pos..pos + literals]);
+        pos += literals;
+        if pos == input.len() {
+            // The last sequence has no match
+            break;
+        }
+        let offset = input[pos] as usize | (input[pos + 1] as usize) << 8;
+        pos += 2;
+        let mut length = (token & 15) as usize;
+        if length == 15 {
+            length += read_length(input, &mut pos);
+        }
+        length += MIN_MATCH;
+        assert!(offset > 0 && offset <= output.len() - start, "Invalid match offset");
+        // Matches may overlap with the bytes they produce
+        let from = output.len() - offset;
+        for index in from..from + length {
+            let byte = output[index];
+            output.push(byte);
+        }
+    }
+}
@@ -0,0 +1,242 @@
+//! Automatic load balancing of stateful operators.
+//!
+//! A controller consumes the per-bin statistics of a stateful operator, see
+//! `StatefulConfig::with_stats_interval`, and emits control instructions moving bins between
+//! workers. Which bins move is decided by a [`BalancePolicy`]. The controller's output is meant
+//! to be fed back to the operator's control input through a loop:
+//!
+//! ```ignore
+//! let (handle, control) = scope.feedback(1);
+//! let stateful = input.stateful(key, &control, &config, None, None);
+//! let initial = ControlSet::initial(&config, scope.peers());
+//! stateful.stats
+//!     .balance(&stateful.acks, &stateful.diagnostics, &initial, Capped::new(4, Greedy), |stats| stats.records as u64)
+//!     .connect_loop(handle);
+//! ```
+//!
+//! The controller assumes it is the only source of control instructions once the operator reached
+//! the configuration it starts from. It issues a new configuration only once the previous one has
+//! been applied on all workers, or was rejected.
+//!
+//! [`BalancePolicy`]: trait.BalancePolicy.html
+
+use timely::dataflow::{Stream, Scope};
+use timely::dataflow::channels::pact::Exchange;
+use timely::dataflow::operators::{Broadcast, Concat, Filter, Map};
+use timely::dataflow::operators::generic::operator::Operator;
+use timely::order::TotalOrder;
+
+use fnv::FnvHashMap as HashMap;
+
+use notificator::TotalOrderFrontierNotificator;
+use stateful::{BinStats, MigrationAck, MigrationPhase};
+use ::{BinId, Control, ControlError, ControlInst, ControlSet};
+
+/// Decides which bins to move, given the load of each bin.
+pub trait BalancePolicy {
+    /// Plan moves for the bins in `map`, which assigns bins to workers, where bin `i` carries
+    /// `loads[i]`. Returns `(bin, worker)` pairs.
+    fn plan(&mut self, map: &[usize], loads: &[u64], peers: usize) -> Vec<(usize, usize)>;
+}
+
+/// The load of each worker under `map`.
+fn worker_loads(map: &[usize], loads: &[u64], peers: usize) -> Vec<u64> {
+    let mut worker_loads = vec![0; peers];
+    for (bin, worker) in map.iter().enumerate() {
+        worker_loads[*worker] += loads[bin];
+    }
+    worker_loads
+}
+
+/// Repeatedly moves a bin from the most loaded to the least loaded worker, as long as this reduces
+/// the load of the most loaded worker.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Greedy;
+
+impl BalancePolicy for Greedy {
+    fn plan(&mut self, map: &[usize], loads: &[u64], peers: usize) -> Vec<(usize, usize)> {
+        let mut map = map.to_vec();
+        let mut worker_loads = worker_loads(&map, loads, peers);
+        let mut moves = Vec::new();
+        loop {
+            let max = (0..peers).max_by_key(|worker| worker_loads[*worker]).expect("No workers");
+            let min = (0..peers).min_by_key(|worker| worker_loads[*worker]).expect("No workers");
+            // The bin minimizing the larger of the two workers' loads after the move
+            let best = (0..map.len())
+                .filter(|bin| map[*bin] == max && loads[*bin] > 0)
+                .map(|bin| (::std::cmp::max(worker_loads[max] - loads[bin], worker_loads[min] + loads[bin]), bin))
+                .min();
+            match best {
+                Some((load, bin)) if load < worker_loads[max] => {
+                    worker_loads[max] -= loads[bin];
+                    worker_loads[min] += loads[bin];
+                    map[bin] = min;
+                    moves.retain(|&(moved, _)| moved != bin);
+                    moves.push((bin, min));
+                },
+                _ => return moves,
+            }
+        }
+    }
+}
+
+/// Only rebalances if the most loaded worker carries more than `ratio` times the average load.
+#[derive(Clone, Copy, Debug)]
+pub struct Threshold<P> {
+    ratio: f64,
+    policy: P,
+}
+
+impl<P: BalancePolicy> Threshold<P> {
+    /// Apply `policy` once the load is skewed by more than `ratio`.
+    pub fn new(ratio: f64, policy: P) -> Self {
+        Self { ratio, policy }
+    }
+}
+
+impl<P: BalancePolicy> BalancePolicy for Threshold<P> {
+    fn plan(&mut self, map: &[usize], loads: &[u64], peers: usize) -> Vec<(usize, usize)> {
+        let worker_loads = worker_loads(map, loads, peers);
+        let max = worker_loads.iter().cloned().max().unwrap_or(0);
+        let average = worker_loads.iter().sum::<u64>() as f64 / peers as f64;
+        if max as f64 > self.ratio * average {
+            self.policy.plan(map, loads, peers)
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+/// Moves at most `max_moves` bins per step, in the order `policy` planned them.
+#[derive(Clone, Copy, Debug)]
+pub struct Capped<P> {
+    max_moves: usize,
+    policy: P,
+}
+
+impl<P: BalancePolicy> Capped<P> {
+    /// Limit `policy` to `max_moves` bins per step.
+    pub fn new(max_moves: usize, policy: P) -> Self {
+        Self { max_moves, policy }
+    }
+}
+
+impl<P: BalancePolicy> BalancePolicy for Capped<P> {
+    fn plan(&mut self, map: &[usize], loads: &[u64], peers: usize) -> Vec<(usize, usize)> {
+        let mut moves = self.policy.plan(map, loads, peers);
+        moves.truncate(self.max_moves);
+        moves
+    }
+}
+
+/// Provides the `balance` method.
+pub trait Balance<G: Scope> {
+    /// Rebalance a stateful operator based on its statistics.
+    ///
+    /// Statistics of a time are combined into per-bin loads with `load`, once all workers reported
+    /// them. `policy` then plans moves, which are emitted as a batch of `ControlInst::Move` to all
+    /// workers. `acks` and `diagnostics` are the operator's acknowledgements and rejected batches,
+    /// the next batch is only planned once all workers applied the previous one, or rejected it.
+    ///
+    /// Planning starts from `current`, the operator's configuration, and batches are numbered
+    /// following its sequence number.
+    fn balance<P, L>(&self, acks: &Stream<G, MigrationAck<G::Timestamp>>, diagnostics: &Stream<G, (u64, ControlError)>, current: &ControlSet<G::Timestamp>, policy: P, load: L) -> Stream<G, Control>
+        where
+            P: BalancePolicy+'static,
+            L: Fn(&BinStats)->u64+'static,
+    ;
+}
+
+impl<G: Scope> Balance<G> for Stream<G, BinStats>
+    where
+        G::Timestamp: TotalOrder,
+{
+    fn balance<P, L>(&self, acks: &Stream<G, MigrationAck<G::Timestamp>>, diagnostics: &Stream<G, (u64, ControlError)>, current: &ControlSet<G::Timestamp>, mut policy: P, load: L) -> Stream<G, Control>
+        where
+            P: BalancePolicy+'static,
+            L: Fn(&BinStats)->u64+'static,
+    {
+        let peers = self.scope().peers();
+        let mut map = current.map().clone();
+        let layout = current.layout().clone();
+
+        let mut sequence = current.sequence + 1;
+        // The batch waiting to be applied, the workers that applied it, and the map before it
+        let mut outstanding: Option<(u64, usize, Vec<usize>)> = None;
+
+        // Batches applied by a worker, or rejected. Failed checkpoints and restores do not reject
+        // a batch.
+        let outcomes = acks
+            .filter(|ack| ack.phase == MigrationPhase::Applied)
+            .map(|ack| (ack.sequence, None))
+            .concat(&diagnostics
+                .filter(|&(_, ref error)| match *error {
+                    ControlError::CheckpointFailed(_) | ControlError::RestoreFailed(_) => false,
+                    _ => true,
+                })
+                .map(|(sequence, error)| (sequence, Some(error))));
+
+        let mut stats_buffer = Vec::new();
+        let mut outcomes_buffer = Vec::new();
+
+        // Loads by time, until all workers reported
+        let mut loads: HashMap<G::Timestamp, Vec<u64>> = Default::default();
+        let mut notificator = TotalOrderFrontierNotificator::new();
+
+        // A single worker plans for all workers
+        self.binary_frontier(&outcomes, Exchange::new(|_| 0), Exchange::new(|_| 0), "Balance", move |_cap, _info| {
+            move |stats_in, outcomes_in, output| {
+                outcomes_in.for_each(|_time, data| {
+                    data.swap(&mut outcomes_buffer);
+                    for (acked, error) in outcomes_buffer.drain(..) {
+                        let rejected = match outstanding {
+                            Some((pending, ref mut applied, _)) if pending == acked => {
+                                *applied += 1;
+                                error.is_some()
+                            },
+                            _ => false,
+                        };
+                        // The operators keep their configuration, plan from it again
+                        if rejected {
+                            map = outstanding.take().expect("Rejected batch not outstanding").2;
+                        }
+                    }
+                });
+                if outstanding.as_ref().map_or(false, |&(_, applied, _)| applied == peers) {
+                    outstanding = None;
+                }
+
+                stats_in.for_each(|time, data| {
+                    data.swap(&mut stats_buffer);
+                    let bins = map.len();
+                    let time_loads = loads.entry(time.time().clone()).or_insert_with(|| vec![0; bins]);
+                    for stats in stats_buffer.drain(..) {
+                        if *stats.bin < bins {
+                            time_loads[*stats.bin] += load(&stats);
+                        }
+                    }
+                    notificator.notify_at(&time.retain());
+                });
+
+                notificator.for_each(&[stats_in.frontier()], |cap, time, _not| {
+                    let time_loads = loads.remove(&time).expect("Notified without loads");
+                    if outstanding.is_some() {
+                        return;
+                    }
+                    let mut moves = policy.plan(&map, &time_loads, peers);
+                    moves.retain(|&(bin, worker)| layout.is_active(bin) && worker < peers && map[bin] != worker);
+                    if !moves.is_empty() {
+                        let count = moves.len();
+                        let mut session = output.session(&cap.delayed(&time));
+                        outstanding = Some((sequence, 0, map.clone()));
+                        for (bin, worker) in moves {
+                            map[bin] = worker;
+                            session.give(Control::new(sequence, count, ControlInst::Move(BinId(bin), worker)));
+                        }
+                        sequence += 1;
+                    }
+                });
+            }
+        }).broadcast()
+    }
+}
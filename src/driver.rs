@@ -0,0 +1,164 @@
+//! Advancing the control input of stateful operators along with their data.
+//!
+//! Stateful operators stash records until the control frontier passes their time, which requires
+//! applications to advance the control input in lockstep with the data. The `drive` method
+//! decouples the two: its output's frontier follows the data frontier ahead by a lead, and
+//! instructions that arrive behind it are delayed to the current frontier:
+//!
+//! ```ignore
+//! let control = scope.input_from(&mut control_input).drive(&input, 1);
+//! let stateful = input.stateful(key, &control, &config, None, None);
+//! ```
+
+use std::collections::BTreeMap;
+
+use fnv::FnvHashMap as HashMap;
+
+use timely::Data;
+use timely::dataflow::{Stream, Scope};
+use timely::dataflow::channels::pact::Pipeline;
+use timely::dataflow::operators::{Broadcast, Capability};
+use timely::dataflow::operators::generic::builder_rc::OperatorBuilder;
+use timely::order::TotalOrder;
+use timely::progress::{PathSummary, Timestamp};
+use timely::progress::frontier::Antichain;
+
+use ::Control;
+
+/// Provides the `drive` method.
+pub trait DriveControl<G: Scope> {
+    /// Advance a control stream along with `data`.
+    ///
+    /// The output's frontier stays `lead` ahead of the data frontier, so records are never stashed
+    /// behind an idle control input. A batch of instructions is released once all of its `count`
+    /// instructions arrived, at its own time or the output's frontier, whichever is later. Each
+    /// batch is released at a distinct time, a batch that would share the time of its predecessor
+    /// waits for the data frontier to advance. Batches still waiting once both inputs are complete
+    /// are dropped, as no records remain to apply them to.
+    ///
+    /// Workers observe the data frontier at different moments. Worker 0 decides the time of each
+    /// batch from its own copy and broadcasts it, every worker releases its copy at that time.
+    fn drive<D: Data>(&self, data: &Stream<G, D>, lead: <G::Timestamp as Timestamp>::Summary) -> Stream<G, Control>;
+}
+
+impl<G: Scope> DriveControl<G> for Stream<G, Control>
+    where
+        G::Timestamp: TotalOrder,
+{
+    fn drive<D: Data>(&self, data: &Stream<G, D>, lead: <G::Timestamp as Timestamp>::Summary) -> Stream<G, Control> {
+        let index = self.scope().index();
+
+        // Decide the release time of each batch on worker 0. The output follows the data `lead`
+        // ahead, the control input does not hold it back.
+        let mut builder = OperatorBuilder::new("ControlDriverDecide".to_owned(), self.scope());
+        let mut control_in = builder.new_input_connection(self, Pipeline, vec![Antichain::new()]);
+        let mut data_in = builder.new_input_connection(data, Pipeline, vec![Antichain::from_elem(lead.clone())]);
+        let (mut release_out, releases) = builder.new_output();
+
+        builder.build(move |mut capabilities| {
+            let mut cap = if index == 0 { capabilities.pop() } else { None };
+            // Incomplete and waiting batches by sequence number, with the time of their earliest
+            // instruction, the number of instructions received and the expected count
+            let mut pending: BTreeMap<u64, (G::Timestamp, usize, usize)> = BTreeMap::new();
+            // The time of the last released batch
+            let mut last: Option<G::Timestamp> = None;
+
+            let mut control_buffer = Vec::new();
+
+            move |frontiers| {
+                let mut release_handle = release_out.activate();
+
+                control_in.for_each(|time, data| {
+                    data.swap(&mut control_buffer);
+                    for control in control_buffer.drain(..).filter(|_| index == 0) {
+                        let entry = pending.entry(control.sequence()).or_insert_with(|| (time.time().clone(), 0, control.count()));
+                        if time.time().less_than(&entry.0) {
+                            entry.0 = time.time().clone();
+                        }
+                        entry.1 += 1;
+                    }
+                });
+                data_in.for_each(|_time, _data| {});
+
+                if let Some(ref mut cap) = cap {
+                    // Stay `lead` ahead of the data
+                    let target = frontiers[1].frontier().iter().min().and_then(|time| lead.results_in(time));
+                    if let Some(target) = target {
+                        if cap.time().less_than(&target) {
+                            cap.downgrade(&target);
+                        }
+                    }
+
+                    // Release complete batches in sequence order
+                    while let Some((&sequence, &(ref time, received, count))) = pending.iter().next() {
+                        let time = if cap.time().less_than(time) { time.clone() } else { cap.time().clone() };
+                        if received < count || !last.as_ref().map_or(true, |last| last.less_than(&time)) {
+                            break;
+                        }
+                        pending.remove(&sequence);
+                        release_handle.session(&cap.delayed(&time)).give(sequence);
+                        last = Some(time);
+                    }
+                }
+
+                if frontiers[0].frontier().is_empty() && frontiers[1].frontier().is_empty() {
+                    pending.clear();
+                    cap = None;
+                }
+            }
+        });
+
+        // Release each worker's copy of a batch at the time worker 0 decided
+        let releases = releases.broadcast();
+        let mut builder = OperatorBuilder::new("ControlDriver".to_owned(), self.scope());
+        let mut control_in = builder.new_input_connection(self, Pipeline, vec![Antichain::new()]);
+        let mut release_in = builder.new_input(&releases, Pipeline);
+        let (mut output, stream) = builder.new_output();
+
+        builder.build(move |_capabilities| {
+            // Received batches and their release capabilities by sequence number
+            let mut batches: HashMap<u64, Vec<Control>> = Default::default();
+            let mut release_caps: HashMap<u64, Capability<G::Timestamp>> = Default::default();
+
+            let mut control_buffer = Vec::new();
+            let mut release_buffer = Vec::new();
+
+            move |frontiers| {
+                let mut output_handle = output.activate();
+
+                control_in.for_each(|_time, data| {
+                    data.swap(&mut control_buffer);
+                    for control in control_buffer.drain(..) {
+                        batches.entry(control.sequence()).or_insert_with(Vec::new).push(control);
+                    }
+                });
+                release_in.for_each(|time, data| {
+                    data.swap(&mut release_buffer);
+                    for sequence in release_buffer.drain(..) {
+                        release_caps.insert(sequence, time.retain());
+                    }
+                });
+
+                let ready: Vec<u64> = release_caps.keys()
+                    .filter(|sequence| batches.get(sequence).map_or(false, |controls| controls.len() >= controls[0].count()))
+                    .cloned()
+                    .collect();
+                for sequence in ready {
+                    let cap = release_caps.remove(&sequence).expect("Releasing batch without capability");
+                    let controls = batches.remove(&sequence).expect("Releasing missing batch");
+                    output_handle.session(&cap).give_iterator(controls.into_iter());
+                }
+
+                // Batches missing at the end of either input are never released
+                if frontiers[0].frontier().is_empty() {
+                    release_caps.clear();
+                }
+                if frontiers[1].frontier().is_empty() {
+                    batches.clear();
+                }
+            }
+        });
+
+        stream
+    }
+}
@@ -23,7 +23,7 @@ trait BinarySkeleton<S, K, V>
         K: ExchangeData+Hash+Eq,
         V: ExchangeData, // Input data
 {
-    fn left_join<V2>(&mut self, other: &Stream<S, (K, V2)>, name: &str, control: &Stream<S, ::Control>) -> Stream<S, (K, V, V2)>
+    fn left_join<V2>(&mut self, other: &Stream<S, (K, V2)>, name: &str, control: &Stream<S, ::Control>, config: &::StatefulConfig<S::Timestamp>) -> Stream<S, (K, V, V2)>
         where
             V2: ExchangeData+Eq,
 ;
@@ -36,11 +36,11 @@ where
     K: ExchangeData+Hash+Eq,
     V: ExchangeData+Eq, // Input data
 {
-    fn left_join<V2>(&mut self, other: &Stream<S, (K, V2)>, name: &str, control: &Stream<S, ::Control>) -> Stream<S, (K, V, V2)>
+    fn left_join<V2>(&mut self, other: &Stream<S, (K, V2)>, name: &str, control: &Stream<S, ::Control>, config: &::StatefulConfig<S::Timestamp>) -> Stream<S, (K, V, V2)>
         where
             V2: ExchangeData+Eq,
     {
-        self.stateful_binary(&control, other, |t| calculate_hash(&t.0), |t| calculate_hash(&t.0), name, |cap, data, bin1: &mut Bin<_, HashMap<K, V>, _>, bin2: &mut Bin<_, HashMap<K, Vec<V2>>, _>, output| {
+        self.stateful_binary(&control, config, other, |t| calculate_hash(&t.0), |t| calculate_hash(&t.0), name, |cap, data, bin1: &mut Bin<_, HashMap<K, V>, _>, bin2: &mut Bin<_, HashMap<K, Vec<V2>>, _>, output| {
             let mut session = output.session(&cap);
             let bin: &mut HashMap<_, _> = bin2.state();
             for (_time, (key, value)) in data {
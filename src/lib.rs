@@ -6,16 +6,38 @@ extern crate timely;
 extern crate abomonation;
 #[macro_use] extern crate abomonation_derive;
 
-mod stateful;
+pub mod assigner;
+pub mod checkpoint;
+pub mod codec;
+pub mod controller;
+pub mod driver;
+pub mod stateful;
 pub mod state_machine;
 pub mod join;
+pub mod logging;
+pub mod migratable;
 pub mod notificator;
 pub mod operator;
+pub mod partial;
+pub mod plan;
+pub mod planner;
+mod spill;
+pub mod tracked;
+pub mod windows;
 
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use timely::dataflow::operators::Capability;
 use timely::order::{PartialOrder, TotalOrder};
 use timely::progress::frontier::Antichain;
 use timely::progress::Timestamp;
 
+use assigner::{BinAssigner, TopBits};
+use codec::Codec;
+use logging::{MegaphoneEvent, MegaphoneLogger};
+use spill::Spill;
+
 /// A control message consisting of a sequence number, a total count of messages to be expected
 /// and an instruction.
 #[derive(Abomonation, Clone, Debug)]
@@ -44,20 +66,254 @@ type KeyType = u64;
 pub struct Key(KeyType);
 
 impl Key {
-    /// Calculate the bin id for this key.
-    ///
-    /// Warning: will go away once `BIN_SHIFT` ceases to exist.
-    pub fn bin(self) -> usize {
-        key_to_bin(self)
+    /// Calculate the bin id for this key, given `bin_shift` bits of bin identifier.
+    pub fn bin(self, bin_shift: usize) -> usize {
+        key_to_bin(self, bin_shift)
     }
 }
 
-/// Compute the bin for a key.
-///
-/// Warning: will go away once `BIN_SHIFT` ceases to exist.
+/// Compute the bin for a key. The bin is formed by the `bin_shift` most significant bits of the
+/// key.
 #[inline(always)]
-pub fn key_to_bin(key: Key) -> usize {
-    (key.0 >> (::std::mem::size_of::<KeyType>() * 8 - BIN_SHIFT)) as usize
+pub fn key_to_bin(key: Key, bin_shift: usize) -> usize {
+    if bin_shift == 0 {
+        0
+    } else {
+        (key.0 >> (::std::mem::size_of::<KeyType>() * 8 - bin_shift)) as usize
+    }
+}
+
+/// Configuration of a stateful operator.
+///
+/// The configuration determines the number of bins a stateful operator maintains and how keys are
+/// assigned to bins. All stateful operators that share a control stream must agree on the number
+/// of bins, as the control instructions refer to bin identifiers. Settings referring to times are
+/// expressed in the operators' timestamp type `T`.
+#[derive(Clone, Debug)]
+pub struct StatefulConfig<T: Timestamp> {
+    bin_shift: usize,
+    worker_policy: WorkerPolicy,
+    assigner: Box<dyn BinAssigner>,
+    chunk_size: usize,
+    migration_budget: MigrationBudget,
+    pre_copy: bool,
+    codec: Codec,
+    checkpoint_directory: Option<PathBuf>,
+    checkpoint_interval: Option<T::Summary>,
+    restore: Option<PathBuf>,
+    spill: Option<(PathBuf, usize)>,
+    stats_interval: Option<Duration>,
+}
+
+impl<T: Timestamp> StatefulConfig<T> {
+    /// Construct a new `StatefulConfig` with the default `BIN_SHIFT`, rejecting out-of-range
+    /// workers and assigning keys by their most significant bits.
+    pub fn new() -> Self {
+        Self {
+            bin_shift: BIN_SHIFT,
+            worker_policy: WorkerPolicy::Reject,
+            assigner: Box::new(TopBits),
+            chunk_size: 1 << 20,
+            migration_budget: MigrationBudget::Unlimited,
+            pre_copy: false,
+            codec: Codec::None,
+            checkpoint_directory: None,
+            checkpoint_interval: None,
+            restore: None,
+            spill: None,
+            stats_interval: None,
+        }
+    }
+
+    /// Set the number of bins to `1 << bin_shift`. The bin shift must be less than the number of
+    /// key bits.
+    pub fn with_bin_shift(mut self, bin_shift: usize) -> Self {
+        assert!(bin_shift < ::std::mem::size_of::<KeyType>() * 8, "Bin shift must be less than the number of key bits");
+        self.bin_shift = bin_shift;
+        self
+    }
+
+    /// The number of key bits used to identify a bin.
+    pub fn bin_shift(&self) -> usize {
+        self.bin_shift
+    }
+
+    /// The number of bins, i.e., `1 << bin_shift`.
+    pub fn bins(&self) -> usize {
+        1 << self.bin_shift
+    }
+
+    /// Set the policy for control instructions targeting workers that do not exist.
+    pub fn with_worker_policy(mut self, worker_policy: WorkerPolicy) -> Self {
+        self.worker_policy = worker_policy;
+        self
+    }
+
+    /// The policy for control instructions targeting workers that do not exist.
+    pub fn worker_policy(&self) -> WorkerPolicy {
+        self.worker_policy
+    }
+
+    /// Set the strategy to assign keys to bins. Stateful operators panic if the assigner does not
+    /// support the configured bin shift.
+    pub fn with_assigner<A: BinAssigner + 'static>(mut self, assigner: A) -> Self {
+        self.assigner = Box::new(assigner);
+        self
+    }
+
+    /// The strategy to assign keys to bins.
+    pub fn assigner(&self) -> &dyn BinAssigner {
+        &*self.assigner
+    }
+
+    /// Set the size in bytes after which migrated state is sent as a separate message. Defaults
+    /// to 1 MiB.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "Chunk size must be positive");
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// The size in bytes after which migrated state is sent as a separate message.
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Set the amount of state to send per activation when migrating bins.
+    pub fn with_migration_budget(mut self, migration_budget: MigrationBudget) -> Self {
+        self.migration_budget = migration_budget;
+        self
+    }
+
+    /// The amount of state to send per activation when migrating bins.
+    pub fn migration_budget(&self) -> MigrationBudget {
+        self.migration_budget
+    }
+
+    /// Copy moving bins ahead of a configuration change, for operators whose state tracks changes.
+    pub fn with_pre_copy(mut self, pre_copy: bool) -> Self {
+        self.pre_copy = pre_copy;
+        self
+    }
+
+    /// Test if moving bins are copied ahead of a configuration change.
+    pub fn pre_copy(&self) -> bool {
+        self.pre_copy
+    }
+
+    /// Set the encoding of state sent to other workers.
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// The encoding of state sent to other workers.
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Set the directory to write checkpoints to when instructed by `ControlInst::Checkpoint` or
+    /// periodically, see `with_checkpoint_interval`.
+    pub fn with_checkpoint_directory<P: Into<PathBuf>>(mut self, directory: P) -> Self {
+        self.checkpoint_directory = Some(directory.into());
+        self
+    }
+
+    /// The directory to write checkpoints to, if any.
+    pub fn checkpoint_directory(&self) -> Option<&Path> {
+        self.checkpoint_directory.as_ref().map(|directory| directory.as_path())
+    }
+
+    /// Write a checkpoint every `interval`, in addition to those requested by
+    /// `ControlInst::Checkpoint`. Requires a checkpoint directory.
+    pub fn with_checkpoint_interval(mut self, interval: T::Summary) -> Self {
+        self.checkpoint_interval = Some(interval);
+        self
+    }
+
+    /// The interval between periodic checkpoints, if any.
+    pub fn checkpoint_interval(&self) -> Option<&T::Summary> {
+        self.checkpoint_interval.as_ref()
+    }
+
+    /// Start from the configuration and state of a checkpoint, see `checkpoint::latest`. All
+    /// workers need to read the checkpoint, see `checkpoint`.
+    pub fn with_restore<P: Into<PathBuf>>(mut self, checkpoint: P) -> Self {
+        self.restore = Some(checkpoint.into());
+        self
+    }
+
+    /// The checkpoint to start from, if any.
+    pub fn restore(&self) -> Option<&Path> {
+        self.restore.as_ref().map(|checkpoint| checkpoint.as_path())
+    }
+
+    /// Spill cold bins to files in `directory`, keeping about `budget` bytes of state in memory.
+    pub fn with_spill<P: Into<PathBuf>>(mut self, directory: P, budget: usize) -> Self {
+        self.spill = Some((directory.into(), budget));
+        self
+    }
+
+    /// The directory to spill bins to and the memory budget, if any.
+    pub fn spill(&self) -> Option<(&Path, usize)> {
+        self.spill.as_ref().map(|&(ref directory, budget)| (directory.as_path(), budget))
+    }
+
+    /// Report per-bin statistics on the `stats` stream about every `interval`.
+    pub fn with_stats_interval(mut self, interval: Duration) -> Self {
+        self.stats_interval = Some(interval);
+        self
+    }
+
+    /// The interval at which per-bin statistics are reported, if any.
+    pub fn stats_interval(&self) -> Option<Duration> {
+        self.stats_interval
+    }
+}
+
+/// Policy for control instructions that assign bins to workers beyond the number of peers.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WorkerPolicy {
+    /// Reject the control batch with `ControlError::WorkerOutOfRange`.
+    Reject,
+    /// Assign the bin to the last worker instead, logging `MegaphoneEvent::WorkerClamped`.
+    Clamp,
+}
+
+/// The amount of state a stateful operator sends per activation when migrating bins.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MigrationBudget {
+    /// Send all state as soon as a configuration becomes active. This is the default.
+    Unlimited,
+    /// Send at most approximately this many bytes per activation in the background.
+    Bytes(usize),
+    /// Send at most this many state items per activation in the background.
+    Records(usize),
+}
+
+impl MigrationBudget {
+    /// The budget available per activation.
+    fn limit(&self) -> usize {
+        match *self {
+            MigrationBudget::Unlimited => usize::max_value(),
+            MigrationBudget::Bytes(limit) | MigrationBudget::Records(limit) => limit,
+        }
+    }
+
+    /// The cost of sending an item of `bytes` bytes.
+    fn cost(&self, bytes: usize) -> usize {
+        match *self {
+            MigrationBudget::Unlimited => 0,
+            MigrationBudget::Bytes(_) => bytes,
+            MigrationBudget::Records(_) => 1,
+        }
+    }
+}
+
+impl<T: Timestamp> Default for StatefulConfig<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ::std::ops::Deref for BinId {
@@ -81,6 +337,18 @@ pub enum ControlInst {
     Map(Vec<usize>),
     /// Provide a map update
     Move(BinId, /*worker*/ usize),
+    /// Split a bin into two. The bin keeps the lower half of its keys, the upper half is assigned
+    /// to a new bin on the same worker. The new bin's identifier is the number of bins before the
+    /// split.
+    Split(BinId),
+    /// Merge the second bin into the first. The bins need to be siblings, i.e., be the result of
+    /// splitting a bin, and reside on the same worker. The second bin is retired.
+    Merge(BinId, BinId),
+    /// Update the parameters of the key assigner, e.g., the boundaries of `KeyRanges`.
+    Reassign(Vec<u64>),
+    /// Write a checkpoint of the configuration and state in place at the batch's time. The
+    /// batch's other instructions take effect after the checkpoint.
+    Checkpoint,
     /// No-op
     None,
 }
@@ -90,6 +358,21 @@ impl Control {
     pub fn new(sequence: u64, count: usize, inst: ControlInst) -> Self {
         Self { sequence, count, inst }
     }
+
+    /// The sequence number of the control's batch.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// The number of controls in the batch.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// The instruction.
+    pub fn inst(&self) -> &ControlInst {
+        &self.inst
+    }
 }
 
 /// A compiled set of control instructions
@@ -101,6 +384,24 @@ pub struct ControlSet<T> {
     pub frontier: Antichain<T>,
     /// Explicit mapping of bins to workers
     pub map: Vec<usize>,
+    /// Assignment of keys to bins
+    pub layout: BinLayout,
+    /// Write a checkpoint before the configuration becomes active
+    pub checkpoint: bool,
+}
+
+impl<T: Timestamp> ControlSet<T> {
+    /// The configuration a stateful operator with `config` on `peers` workers starts from, unless
+    /// it restores a checkpoint. Bins are assigned to workers round-robin.
+    pub fn initial(config: &StatefulConfig<T>, peers: usize) -> Self {
+        ControlSet {
+            sequence: 0,
+            frontier: Antichain::from_elem(Default::default()),
+            map: (0..peers).cycle().take(config.bins()).collect(),
+            layout: BinLayout::new(config.bin_shift(), config.assigner().clone_box()),
+            checkpoint: false,
+        }
+    }
 }
 
 impl<T> ControlSet<T> {
@@ -110,8 +411,246 @@ impl<T> ControlSet<T> {
         &self.map
     }
 
+    /// Obtain the current key to bin assignment
+    pub fn layout(&self) -> &BinLayout {
+        &self.layout
+    }
+
+    /// The worker responsible for `key`.
+    #[inline(always)]
+    pub fn worker(&self, key: Key) -> usize {
+        self.map[self.layout.bin(key)]
+    }
+
+    /// The worker responsible for the keys at `position`.
+    pub fn worker_at(&self, position: u64) -> usize {
+        self.map[self.layout.bin_at(position)]
+    }
+
+}
+
+/// Assignment of keys to bins.
+///
+/// A `BinAssigner` maps each key to a position. Each bin covers the positions sharing a prefix of
+/// the most significant bits. Initially, all bins have a prefix of `bin_shift` bits, and bin `i`
+/// covers prefix `i`. Splitting a bin extends its prefix by one bit and assigns the upper half of
+/// its positions to a new bin. Merging two sibling bins shortens the prefix by one bit and retires
+/// the second bin.
+#[derive(Clone, Debug)]
+pub struct BinLayout {
+    bin_shift: usize,
+    assigner: Box<dyn BinAssigner>,
+    // Number of updates applied to the assigner
+    generation: u64,
+    // Prefix and prefix length by bin, `None` for retired bins
+    prefixes: Vec<Option<(u64, usize)>>,
+    // Sorted start of each bin's key range. Empty while all bins have a prefix of `bin_shift` bits.
+    ranges: Vec<(u64, usize)>,
+}
+
+impl BinLayout {
+
+    /// Construct a layout of `1 << bin_shift` bins of equal size. Panics if `assigner` does not
+    /// support `bin_shift`.
+    pub fn new(bin_shift: usize, assigner: Box<dyn BinAssigner>) -> Self {
+        assert!(bin_shift < ::std::mem::size_of::<KeyType>() * 8, "Bin shift must be less than the number of key bits");
+        if let Err(error) = assigner.check(bin_shift) {
+            panic!("Assigner {:?} does not support bin shift {}: {}", assigner, bin_shift, error);
+        }
+        Self {
+            bin_shift,
+            assigner,
+            generation: 0,
+            prefixes: (0..1u64 << bin_shift).map(|prefix| Some((prefix, bin_shift))).collect(),
+            ranges: Vec::new(),
+        }
+    }
+
+    /// The prefix length of the initial bins.
+    pub fn bin_shift(&self) -> usize {
+        self.bin_shift
+    }
+
+    /// The strategy to assign keys to positions.
+    pub fn assigner(&self) -> &dyn BinAssigner {
+        &*self.assigner
+    }
+
+    /// The number of updates applied to the assigner. Keys may have changed bins if the
+    /// generation differs between two layouts.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// The number of bin identifiers, including retired bins.
+    pub fn bins(&self) -> usize {
+        self.prefixes.len()
+    }
+
+    /// Test if `bin` covers any keys.
+    pub fn is_active(&self, bin: usize) -> bool {
+        self.prefix(bin).is_some()
+    }
+
+    /// Iterate the bins covering keys.
+    pub fn active<'a>(&'a self) -> impl Iterator<Item=usize> + 'a {
+        (0..self.prefixes.len()).filter(move |bin| self.prefixes[*bin].is_some())
+    }
+
+    /// The prefix and prefix length of `bin`, or `None` if the bin does not cover any keys.
+    pub fn prefix(&self, bin: usize) -> Option<(u64, usize)> {
+        self.prefixes.get(bin).and_then(|prefix| *prefix)
+    }
+
+    /// The smallest position covered by `bin`, or `None` if the bin does not cover any keys.
+    pub fn start(&self, bin: usize) -> Option<u64> {
+        self.prefix(bin).map(|(prefix, len)| prefix_start(prefix, len))
+    }
+
+    /// Compute the position of a key.
+    #[inline(always)]
+    pub fn position(&self, key: Key) -> u64 {
+        self.assigner.position(key.0, self.bin_shift)
+    }
+
+    /// Compute the bin for a key.
+    #[inline(always)]
+    pub fn bin(&self, key: Key) -> usize {
+        self.bin_at(self.position(key))
+    }
+
+    /// Compute the bin covering a position.
+    #[inline(always)]
+    pub fn bin_at(&self, position: u64) -> usize {
+        if self.ranges.is_empty() {
+            key_to_bin(Key(position), self.bin_shift)
+        } else {
+            match self.ranges.binary_search_by_key(&position, |&(start, _)| start) {
+                Ok(index) => self.ranges[index].1,
+                Err(index) => self.ranges[index - 1].1,
+            }
+        }
+    }
+
+    /// Update the assigner's parameters.
+    fn reassign(&mut self, parameters: &[u64]) -> Result<(), ControlError> {
+        self.assigner.update(parameters)?;
+        self.generation += 1;
+        Ok(())
+    }
+
+    /// Split `bin`, returning the identifier of the bin receiving the upper half of its keys.
+    fn split(&mut self, bin: usize) -> Result<usize, ControlError> {
+        match self.prefix(bin) {
+            // Prefixes are limited like the bin shift
+            Some((prefix, len)) if len + 1 < ::std::mem::size_of::<KeyType>() * 8 => {
+                let new_bin = self.prefixes.len();
+                self.prefixes[bin] = Some((prefix << 1, len + 1));
+                self.prefixes.push(Some(((prefix << 1) | 1, len + 1)));
+                self.update_ranges();
+                Ok(new_bin)
+            },
+            _ => Err(ControlError::InvalidSplit(BinId(bin))),
+        }
+    }
+
+    /// Merge `other` into `bin`, retiring `other`.
+    fn merge(&mut self, bin: usize, other: usize) -> Result<(), ControlError> {
+        match (self.prefix(bin), self.prefix(other)) {
+            (Some((prefix, len)), Some((other_prefix, other_len)))
+                if bin != other && len == other_len && len > 0 && prefix >> 1 == other_prefix >> 1 => {
+                self.prefixes[bin] = Some((prefix >> 1, len - 1));
+                self.prefixes[other] = None;
+                self.update_ranges();
+                Ok(())
+            },
+            _ => Err(ControlError::InvalidMerge(BinId(bin), BinId(other))),
+        }
+    }
+
+    fn update_ranges(&mut self) {
+        self.ranges.clear();
+        for (bin, prefix) in self.prefixes.iter().enumerate() {
+            if let Some((prefix, len)) = *prefix {
+                self.ranges.push((prefix_start(prefix, len), bin));
+            }
+        }
+        self.ranges.sort();
+    }
+}
+
+/// The smallest key with the prefix `prefix` of length `len`.
+fn prefix_start(prefix: u64, len: usize) -> u64 {
+    if len == 0 {
+        0
+    } else {
+        prefix << (::std::mem::size_of::<KeyType>() * 8 - len)
+    }
+}
+
+/// Errors detected while compiling `Control` messages into a `ControlSet`.
+///
+/// A batch of `Control` messages that fails to compile is rejected as a whole; the configuration
+/// in place before the batch stays active.
+#[derive(Abomonation, Clone, Debug, Eq, PartialEq)]
+pub enum ControlError {
+    /// More `Control` messages were received than announced by their count.
+    TooManyControls(/*count*/ usize),
+    /// Fewer `Control` messages were received than announced by their count.
+    MissingControls(/*missing*/ usize),
+    /// A `Control` carried a sequence number different from the rest of its batch.
+    SequenceMismatch(/*expected*/ u64, /*found*/ u64),
+    /// A provided map does not have one entry per bin.
+    MapLength(/*expected*/ usize, /*found*/ usize),
+    /// An instruction refers to a bin that does not exist.
+    BinOutOfRange(BinId, /*bins*/ usize),
+    /// An instruction assigns a bin to a worker that does not exist.
+    WorkerOutOfRange(/*worker*/ usize, /*peers*/ usize),
+    /// A bin cannot be split any further.
+    InvalidSplit(BinId),
+    /// Two bins cannot be merged because they are not siblings or reside on different workers.
+    InvalidMerge(BinId, BinId),
+    /// The operator cannot split bins or reassign keys as it cannot determine the keys of its
+    /// state.
+    RekeyUnsupported,
+    /// The key assigner rejected its new parameters.
+    InvalidAssignment,
+    /// The operator cannot write checkpoints as it has no checkpoint directory.
+    CheckpointUnsupported,
+    /// Writing a checkpoint failed. The configuration was installed regardless.
+    CheckpointFailed(/*error*/ String),
+    /// Restoring a checkpoint failed. The operator started from the initial configuration.
+    RestoreFailed(/*error*/ String),
+    /// The configuration's frontier is not in advance of the configuration it follows.
+    UnorderedFrontier,
+    /// The operator does not support a setting of its `StatefulConfig`, and ignores it.
+    SettingUnsupported(/*setting*/ String),
 }
 
+impl ::std::fmt::Display for ControlError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+            ControlError::TooManyControls(count) => write!(f, "received more than {} controls", count),
+            ControlError::MissingControls(missing) => write!(f, "missing {} controls", missing),
+            ControlError::SequenceMismatch(expected, found) => write!(f, "inconsistent sequence number: expected {}, found {}", expected, found),
+            ControlError::MapLength(expected, found) => write!(f, "provided map does not have correct len: {} != {}", expected, found),
+            ControlError::BinOutOfRange(bin, bins) => write!(f, "bin {} out of range, number of bins: {}", *bin, bins),
+            ControlError::WorkerOutOfRange(worker, peers) => write!(f, "worker {} out of range, number of peers: {}", worker, peers),
+            ControlError::InvalidSplit(bin) => write!(f, "cannot split bin {}", *bin),
+            ControlError::InvalidMerge(bin, other) => write!(f, "cannot merge bin {} into bin {}", *other, *bin),
+            ControlError::RekeyUnsupported => write!(f, "re-keying state is not supported"),
+            ControlError::InvalidAssignment => write!(f, "invalid key assignment parameters"),
+            ControlError::CheckpointUnsupported => write!(f, "no checkpoint directory configured"),
+            ControlError::CheckpointFailed(ref error) => write!(f, "failed to write checkpoint: {}", error),
+            ControlError::RestoreFailed(ref error) => write!(f, "failed to restore checkpoint: {}", error),
+            ControlError::UnorderedFrontier => write!(f, "frontier not in advance of the previous configuration"),
+            ControlError::SettingUnsupported(ref setting) => write!(f, "unsupported setting: {}", setting),
+        }
+    }
+}
+
+impl ::std::error::Error for ControlError {}
+
 /// A builder to compile `ControlSet`s.
 #[derive(Default)]
 pub struct ControlSetBuilder<T> {
@@ -120,32 +659,73 @@ pub struct ControlSetBuilder<T> {
     instructions: Vec<ControlInst>,
 
     count: Option<usize>,
+    error: Option<ControlError>,
 }
 
 impl<T: PartialOrder> ControlSetBuilder<T> {
 
     /// Add a new `Control` to this builder.
     ///
-    /// TODO: Currently, it will assert if count and sequence numbers do not match. Should fail
-    /// gracefully instead.
-    pub fn apply(&mut self, control: Control) {
+    /// Returns an error if the count or sequence number of `control` does not match the controls
+    /// applied so far. The first error is retained and reported again by `build`.
+    pub fn apply(&mut self, control: Control) -> Result<(), ControlError> {
+        let result = self.apply_inner(control);
+        if let Err(ref error) = result {
+            if self.error.is_none() {
+                self.error = Some(error.clone());
+            }
+        }
+        result
+    }
+
+    fn apply_inner(&mut self, control: Control) -> Result<(), ControlError> {
+        if let Some(sequence) = self.sequence {
+            if sequence != control.sequence {
+                return Err(ControlError::SequenceMismatch(sequence, control.sequence));
+            }
+        } else {
+            self.sequence = Some(control.sequence);
+        }
         if self.count.is_none() {
             self.count = Some(control.count);
         }
         if let Some(ref mut count) = self.count {
-            assert!(*count > 0, "Received incorrect number of Controls");
+            if *count == 0 {
+                return Err(ControlError::TooManyControls(control.count));
+            }
             *count -= 1;
         }
-        if let Some(sequence) = self.sequence {
-            assert_eq!(sequence, control.sequence, "Received control with inconsistent sequence number");
-        } else {
-            self.sequence = Some(control.sequence);
-        }
         match control.inst {
             ControlInst::None => {},
             inst => self.instructions.push(inst),
         };
+        Ok(())
+    }
+
+    /// Test if the controls applied to this builder move keys to different bins, i.e., split bins
+    /// or reassign keys.
+    pub fn requires_rekey(&self) -> bool {
+        self.instructions.iter().any(|inst| match *inst {
+            ControlInst::Split(_) | ControlInst::Reassign(_) => true,
+            _ => false,
+        })
+    }
 
+    /// The workers at or beyond `peers` the controls applied to this builder assign bins to.
+    pub fn workers_out_of_range(&self, peers: usize) -> Vec<usize> {
+        let mut workers: Vec<usize> = self.instructions.iter().flat_map(|inst| match *inst {
+            ControlInst::Map(ref map) => map.clone(),
+            ControlInst::Move(_, worker) => vec![worker],
+            _ => Vec::new(),
+        }).filter(|worker| *worker >= peers).collect();
+        workers.sort();
+        workers.dedup();
+        workers
+    }
+
+    /// The sequence number of the controls applied to this builder, if any.
+    pub fn sequence(&self) -> Option<u64> {
+        self.sequence
     }
 
     /// Provide a frontier to be used to construct the configuration's `Antichain` from.
@@ -154,32 +734,88 @@ impl<T: PartialOrder> ControlSetBuilder<T> {
     }
 
     /// Build a `ControlSet` by consuming this builder.
-    pub fn build(self, previous: &ControlSet<T>) -> ControlSet<T> {
-        assert_eq!(0, self.count.unwrap_or(0));
+    ///
+    /// Returns an error if any of the applied controls was rejected, if controls are missing, or
+    /// if the instructions do not fit the bins of `previous`. In this case, no `ControlSet` is
+    /// produced. Workers at or beyond `peers` are handled according to `policy`.
+    pub fn build(self, previous: &ControlSet<T>, peers: usize, policy: WorkerPolicy) -> Result<ControlSet<T>, ControlError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        match self.count.unwrap_or(0) {
+            0 => {},
+            missing => return Err(ControlError::MissingControls(missing)),
+        }
         let mut frontier = Antichain::new();
         for f in self.frontier {frontier.insert(f);}
 
         let mut map = previous.map().clone();
+        let mut layout = previous.layout().clone();
+        let mut checkpoint = false;
 
         for inst in self.instructions {
             match inst {
                 ControlInst::Map(ref new_map) => {
-                    assert_eq!(1 << BIN_SHIFT, new_map.len(), "provided map does not have correct len: {} != {}", 1 << BIN_SHIFT, new_map.len());
+                    if map.len() != new_map.len() {
+                        return Err(ControlError::MapLength(map.len(), new_map.len()));
+                    }
                     map.clear();
-                    map.extend( new_map.iter());
+                    for worker in new_map {
+                        map.push(check_worker(*worker, peers, policy)?);
+                    }
                 },
                 ControlInst::Move(BinId(bin), target) => {
-                    assert!(bin < (1 << BIN_SHIFT));
-                    map[bin] = target
+                    if !layout.is_active(bin) {
+                        return Err(ControlError::BinOutOfRange(BinId(bin), map.len()));
+                    }
+                    map[bin] = check_worker(target, peers, policy)?;
+                },
+                ControlInst::Split(BinId(bin)) => {
+                    if !layout.is_active(bin) {
+                        return Err(ControlError::BinOutOfRange(BinId(bin), map.len()));
+                    }
+                    let new_bin = layout.split(bin)?;
+                    let worker = map[bin];
+                    map.push(worker);
+                    debug_assert_eq!(new_bin + 1, map.len());
                 },
+                ControlInst::Merge(BinId(bin), BinId(other)) => {
+                    for &bin in &[bin, other] {
+                        if !layout.is_active(bin) {
+                            return Err(ControlError::BinOutOfRange(BinId(bin), map.len()));
+                        }
+                    }
+                    // Both bins must reside on the same worker, including instructions before
+                    // this one
+                    if map[bin] != map[other] {
+                        return Err(ControlError::InvalidMerge(BinId(bin), BinId(other)));
+                    }
+                    layout.merge(bin, other)?;
+                },
+                ControlInst::Reassign(ref parameters) => layout.reassign(parameters)?,
+                ControlInst::Checkpoint => checkpoint = true,
                 ControlInst::None => {},
             }
         }
 
-        ControlSet {
-            sequence: self.sequence.unwrap(),
+        Ok(ControlSet {
+            sequence: self.sequence.unwrap_or(0),
             frontier,
             map,
+            layout,
+            checkpoint,
+        })
+    }
+}
+
+/// Check that `worker` is a valid worker index, applying `policy` otherwise.
+fn check_worker(worker: usize, peers: usize, policy: WorkerPolicy) -> Result<usize, ControlError> {
+    if worker < peers {
+        Ok(worker)
+    } else {
+        match policy {
+            WorkerPolicy::Reject => Err(ControlError::WorkerOutOfRange(worker, peers)),
+            WorkerPolicy::Clamp => Ok(peers - 1),
         }
     }
 }
@@ -190,6 +826,11 @@ pub struct State<T, D, N>
         T: Timestamp + TotalOrder,
 {
     bins: Vec<Option<Bin<T, D, N>>>,
+    layout: BinLayout,
+    spill: Option<Spill<D>>,
+    logger: Option<MegaphoneLogger<T>>,
+    // Bytes received for bins being received
+    receiving: ::fnv::FnvHashMap<usize, usize>,
 }
 
 impl<T, D, N> State<T, D, N>
@@ -197,25 +838,97 @@ impl<T, D, N> State<T, D, N>
         T: Timestamp + TotalOrder,
 {
     /// Construct a new `State` with the provided vector of bins and a default `FrontierNotificator`.
-    fn new(bins: Vec<Option<Bin<T, D, N>>>) -> Self {
-        Self { bins }
+    fn new(layout: BinLayout, bins: Vec<Option<Bin<T, D, N>>>) -> Self {
+        assert_eq!(layout.bins(), bins.len());
+        Self { bins, layout, spill: None, logger: None, receiving: Default::default() }
     }
 
     /// Get the state associated with a key from this bin. Asserts if the state is not available.
     pub fn get(&mut self, key: Key) -> &mut Bin<T, D, N> {
-        assert!(self.bins[key_to_bin(key)].is_some(), "Accessing bin {} for key {:?}", key_to_bin(key), key);
-        self.bins[key_to_bin(key)].as_mut().expect("Trying to access non-available bin")
+        let bin = self.layout.bin(key);
+        assert!(self.bins[bin].is_some(), "Accessing bin {} for key {:?}", bin, key);
+        self.load(bin);
+        self.bins[bin].as_mut().expect("Trying to access non-available bin")
+    }
+
+    /// The prefix length of the initial bins.
+    pub fn bin_shift(&self) -> usize {
+        self.layout.bin_shift()
+    }
+
+    /// The current assignment of keys to bins.
+    pub fn layout(&self) -> &BinLayout {
+        &self.layout
+    }
+
+    /// Install a new assignment of keys to bins, making room for new bins.
+    fn set_layout(&mut self, layout: BinLayout) {
+        self.reserve_bins(layout.bins());
+        self.layout = layout;
+    }
+
+    /// Make sure there is a slot for `bins` bins.
+    fn reserve_bins(&mut self, bins: usize) {
+        while self.bins.len() < bins {
+            self.bins.push(None);
+        }
     }
 
     /// Iterate all bins. This might go away.
     pub fn scan<F: FnMut(&mut D)>(&mut self, mut f: F) {
-        for state in &mut self.bins {
-            if let Some(bin) = state.as_mut() {
+        for bin in 0..self.bins.len() {
+            self.load(bin);
+            if let Some(bin) = self.bins[bin].as_mut() {
                 f(&mut bin.data);
             }
         }
     }
 
+    /// Spill cold bins to `spill`.
+    fn set_spill(&mut self, spill: Spill<D>) {
+        self.spill = Some(spill);
+    }
+
+    /// Log the receipt of bins to `logger`.
+    fn set_logger(&mut self, logger: Option<MegaphoneLogger<T>>) {
+        self.logger = logger;
+    }
+
+    /// Account the size of `message` received for `bin` at `time`, logging the start of the bin's
+    /// receipt.
+    fn receive<X: ::abomonation::Abomonation>(&mut self, bin: usize, time: &T, message: &X) {
+        if self.logger.is_some() {
+            let bytes = ::abomonation::measure(message);
+            if !self.receiving.contains_key(&bin) {
+                ::logging::log(&self.logger, MegaphoneEvent::BinReceiveStart { time: time.clone(), bin: BinId(bin), bytes });
+            }
+            *self.receiving.entry(bin).or_insert(0) += bytes;
+        }
+    }
+
+    /// Log that a source finished sending `bin` at `time`.
+    fn received(&mut self, bin: usize, time: &T) {
+        if self.logger.is_some() {
+            let bytes = self.receiving.remove(&bin).unwrap_or(0);
+            ::logging::log(&self.logger, MegaphoneEvent::BinReceiveEnd { time: time.clone(), bin: BinId(bin), bytes });
+        }
+    }
+
+    /// Make sure the data of `bin` is in memory, if the bin is present.
+    fn load(&mut self, bin: usize) {
+        if let (Some(spill), Some(Some(state))) = (self.spill.as_mut(), self.bins.get_mut(bin)) {
+            spill.access(bin, state);
+        }
+    }
+
+    /// Spill cold bins other than `exclude` until the state in memory fits the budget.
+    fn evict(&mut self, exclude: &[usize]) -> ::std::io::Result<()> {
+        match self.spill {
+            Some(ref mut spill) => spill.evict(&mut self.bins, exclude),
+            None => Ok(()),
+        }
+    }
+
 }
 
 /// A bin with data and a notificator.
@@ -225,6 +938,20 @@ pub struct Bin<T, D, N>
 {
     data: D,
     notificator: ::stateful::Notificator<T, N>,
+    // State is still being received
+    held: bool,
+    // Data was written to disk
+    spilled: bool,
+    // Work done since the bin was last reported
+    counters: BinCounters,
+}
+
+/// Work done on a bin since its statistics were last reported.
+#[derive(Clone, Copy, Debug, Default)]
+struct BinCounters {
+    notifications: usize,
+    folds: usize,
+    fold_nanos: u64,
 }
 
 impl<T, D, N> Bin<T, D, N>
@@ -240,6 +967,54 @@ impl<T, D, N> Bin<T, D, N>
     pub fn notificator(&mut self) -> &mut ::stateful::Notificator<T, N> {
         &mut self.notificator
     }
+
+    /// Test if the bin's state is still being received. Records for a held bin must not be
+    /// processed yet.
+    pub fn is_held(&self) -> bool {
+        self.held
+    }
+
+    /// Register a timer folding `data` at `time`, once the inputs' frontiers pass it. `cap` is the
+    /// capability passed to the fold, which must not be after `time`.
+    ///
+    /// Timers are pending notifications of the bin, and migrate with it like pending records.
+    /// `data` has the bin's record type, and typically identifies the key the timer is for.
+    /// Returns `false` without registering the timer if the inputs are complete, as it could no
+    /// longer fire.
+    pub fn register_timer(&mut self, cap: &Capability<T>, time: T, data: N) -> bool {
+        if self.notificator.is_complete() {
+            return false;
+        }
+        self.notificator.notify_at_data(cap, time, data);
+        true
+    }
+
+    /// Cancel the timers for which `predicate` returns `true`. Returns the number of cancelled
+    /// timers.
+    pub fn cancel_timers<P: FnMut(&T, &N)->bool>(&mut self, mut predicate: P) -> usize {
+        let mut cancelled = 0;
+        self.notificator.retain(|time, data| {
+            let cancel = predicate(time, data);
+            if cancel {
+                cancelled += 1;
+            }
+            !cancel
+        });
+        cancelled
+    }
+
+    /// Iterate the pending timers as `(time, data)`, in no particular order. Records of a held bin
+    /// are pending as well.
+    pub fn timers<'a>(&'a self) -> impl Iterator<Item=(&'a T, &'a N)> + 'a {
+        self.notificator.iter_pending()
+    }
+
+    /// Record that a fold applied `notifications` notifications to the bin, taking `elapsed`.
+    pub fn record_fold(&mut self, notifications: usize, elapsed: Duration) {
+        self.counters.notifications += notifications;
+        self.counters.folds += 1;
+        self.counters.fold_nanos += elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos());
+    }
 }
 
 impl<T, D, N> Default for Bin<T, D, N>
@@ -252,85 +1027,88 @@ impl<T, D, N> Default for Bin<T, D, N>
         Self {
             data: Default::default(),
             notificator: ::stateful::Notificator::new(),
+            held: false,
+            spilled: false,
+            counters: Default::default(),
         }
     }
 }
-/// Static bin-shift parameter. Enable feature "bin-1" with no default features to set this value.
+/// Default bin-shift parameter. Enable feature "bin-1" with no default features to set this value.
 #[cfg(feature = "bin-1")]
 pub const BIN_SHIFT: usize = 1;
 
-/// Static bin-shift parameter. Enable feature "bin-2" with no default features to set this value.
+/// Default bin-shift parameter. Enable feature "bin-2" with no default features to set this value.
 #[cfg(feature = "bin-2")]
 pub const BIN_SHIFT: usize = 2;
 
-/// Static bin-shift parameter. Enable feature "bin-3" with no default features to set this value.
+/// Default bin-shift parameter. Enable feature "bin-3" with no default features to set this value.
 #[cfg(feature = "bin-3")]
 pub const BIN_SHIFT: usize = 3;
 
-/// Static bin-shift parameter. Enable feature "bin-4" with no default features to set this value.
+/// Default bin-shift parameter. Enable feature "bin-4" with no default features to set this value.
 #[cfg(feature = "bin-4")]
 pub const BIN_SHIFT: usize = 4;
 
-/// Static bin-shift parameter. Enable feature "bin-5" with no default features to set this value.
+/// Default bin-shift parameter. Enable feature "bin-5" with no default features to set this value.
 #[cfg(feature = "bin-5")]
 pub const BIN_SHIFT: usize = 5;
 
-/// Static bin-shift parameter. Enable feature "bin-6" with no default features to set this value.
+/// Default bin-shift parameter. Enable feature "bin-6" with no default features to set this value.
 #[cfg(feature = "bin-6")]
 pub const BIN_SHIFT: usize = 6;
 
-/// Static bin-shift parameter. Enable feature "bin-7" with no default features to set this value.
+/// Default bin-shift parameter. Enable feature "bin-7" with no default features to set this value.
 #[cfg(feature = "bin-7")]
 pub const BIN_SHIFT: usize = 7;
 
-/// Static bin-shift parameter. Enable feature "bin-8" with no default features to set this value.
+/// Default bin-shift parameter. Enable feature "bin-8" with no default features to set this value.
 #[cfg(feature = "bin-8")]
 pub const BIN_SHIFT: usize = 8;
 
-/// Static bin-shift parameter. Enable feature "bin-9" with no default features to set this value.
+/// Default bin-shift parameter. Enable feature "bin-9" with no default features to set this value.
 #[cfg(feature = "bin-9")]
 pub const BIN_SHIFT: usize = 9;
 
-/// Static bin-shift parameter. Enable feature "bin-10" with no default features to set this value.
+/// Default bin-shift parameter. Enable feature "bin-10" with no default features to set this value.
 #[cfg(feature = "bin-10")]
 pub const BIN_SHIFT: usize = 10;
 
-/// Static bin-shift parameter. Enable feature "bin-11" with no default features to set this value.
+/// Default bin-shift parameter. Enable feature "bin-11" with no default features to set this value.
 #[cfg(feature = "bin-11")]
 pub const BIN_SHIFT: usize = 11;
 
-/// Static bin-shift parameter. Enable feature "bin-12" with no default features to set this value.
+/// Default bin-shift parameter. Enable feature "bin-12" with no default features to set this value.
 #[cfg(feature = "bin-12")]
 pub const BIN_SHIFT: usize = 12;
 
-/// Static bin-shift parameter. Enable feature "bin-13" with no default features to set this value.
+/// Default bin-shift parameter. Enable feature "bin-13" with no default features to set this value.
 #[cfg(feature = "bin-13")]
 pub const BIN_SHIFT: usize = 13;
 
-/// Static bin-shift parameter. Enable feature "bin-14" with no default features to set this value.
+/// Default bin-shift parameter. Enable feature "bin-14" with no default features to set this value.
 #[cfg(feature = "bin-14")]
 pub const BIN_SHIFT: usize = 14;
 
-/// Static bin-shift parameter. Enable feature "bin-15" with no default features to set this value.
+/// Default bin-shift parameter. Enable feature "bin-15" with no default features to set this value.
 #[cfg(feature = "bin-15")]
 pub const BIN_SHIFT: usize = 15;
 
-/// Static bin-shift parameter. Enable feature "bin-16" with no default features to set this value.
+/// Default bin-shift parameter. Enable feature "bin-16" with no default features to set this value.
 #[cfg(feature = "bin-16")]
 pub const BIN_SHIFT: usize = 16;
 
-/// Static bin-shift parameter. Enable feature "bin-17" with no default features to set this value.
+/// Default bin-shift parameter. Enable feature "bin-17" with no default features to set this value.
 #[cfg(feature = "bin-17")]
 pub const BIN_SHIFT: usize = 17;
 
-/// Static bin-shift parameter. Enable feature "bin-18" with no default features to set this value.
+/// Default bin-shift parameter. Enable feature "bin-18" with no default features to set this value.
 #[cfg(feature = "bin-18")]
 pub const BIN_SHIFT: usize = 18;
 
-/// Static bin-shift parameter. Enable feature "bin-19" with no default features to set this value.
+/// Default bin-shift parameter. Enable feature "bin-19" with no default features to set this value.
 #[cfg(feature = "bin-19")]
 pub const BIN_SHIFT: usize = 19;
 
 #[cfg(feature = "bin-20")]
-/// Static bin-shift parameter. Enable feature "bin-20" with no default features to set this value.
+/// Default bin-shift parameter. Enable feature "bin-20" with no default features to set this value.
 pub const BIN_SHIFT: usize = 20;
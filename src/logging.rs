@@ -0,0 +1,141 @@
+//! Structured events describing migrations, logged through timely's logging framework.
+//!
+//! Stateful operators log [`MegaphoneEvent`]s to the logger registered under [`LOGGER_NAME`] in
+//! the worker's log registry. The logger needs to be registered before the dataflow is
+//! constructed:
+//!
+//! ```ignore
+//! worker.log_register().insert::<MegaphoneEvent<usize>, _>(LOGGER_NAME, |_time, data| {
+//!     for (elapsed, worker, event) in data.drain(..) {
+//!         println!("{:?}\t{}\t{:?}", elapsed, worker, event);
+//!     }
+//! });
+//! ```
+//!
+//! Timely tags each event with the time since the worker started and the worker's index. Events
+//! carry the logical time of the dataflow, the logger's event type has to name the dataflow's
+//! timestamp.
+//!
+//! [`MegaphoneEvent`]: enum.MegaphoneEvent.html
+//! [`LOGGER_NAME`]: constant.LOGGER_NAME.html
+
+use ::BinId;
+
+/// The name of the logger Megaphone logs to.
+pub const LOGGER_NAME: &str = "megaphone";
+
+/// A logger for Megaphone events at logical times `T`.
+pub type MegaphoneLogger<T> = ::timely::logging::Logger<MegaphoneEvent<T>>;
+
+/// Events of a stateful operator at logical times `T`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MegaphoneEvent<T> {
+    /// A batch of control instructions was received and compiled into a configuration.
+    ConfigurationReceived {
+        /// The time of the control instructions
+        time: T,
+        /// The configuration's sequence number
+        sequence: u64,
+        /// The number of bytes of the control instructions
+        bytes: usize,
+    },
+    /// A configuration became active.
+    ConfigurationInstalled {
+        /// The time at which the configuration became active
+        time: T,
+        /// The configuration's sequence number
+        sequence: u64,
+        /// The number of bins this worker moved to other workers
+        moved_bins: usize,
+        /// The number of bytes sent when installing the configuration, excluding background sends
+        bytes: usize,
+    },
+    /// Started sending the contents of a bin.
+    BinSendStart {
+        /// The time at which the bin is sent
+        time: T,
+        /// The bin
+        bin: BinId,
+        /// The estimated number of bytes of the bin's state, if the state provides
+        /// `MigratableState::size_hint_bytes`
+        bytes: Option<usize>,
+    },
+    /// Finished sending the contents of a bin to a worker. A bin whose keys are redistributed
+    /// ends once for each bin receiving keys.
+    BinSendEnd {
+        /// The time at which the bin was sent
+        time: T,
+        /// The bin
+        bin: BinId,
+        /// The receiving worker
+        target: usize,
+        /// The number of bytes of state and notifications sent, excluding pre-copied snapshots
+        bytes: usize,
+    },
+    /// Started receiving the contents of a bin.
+    BinReceiveStart {
+        /// The time at which the bin is received
+        time: T,
+        /// The bin
+        bin: BinId,
+        /// The number of bytes of the first message received for the bin
+        bytes: usize,
+    },
+    /// A source finished sending the contents of a bin.
+    BinReceiveEnd {
+        /// The time at which the bin was received
+        time: T,
+        /// The bin
+        bin: BinId,
+        /// The number of bytes of state and notifications received since the bin's start
+        bytes: usize,
+    },
+    /// Records were stashed until the control input passes their time.
+    RecordsStashed {
+        /// The time of the records
+        time: T,
+        /// The number of records
+        records: usize,
+        /// The number of bytes of the records
+        bytes: usize,
+    },
+    /// Pending notifications were sent along with a bin.
+    NotificationsTransferred {
+        /// The time at which the bin was sent
+        time: T,
+        /// The bin
+        bin: BinId,
+        /// The receiving worker
+        target: usize,
+        /// The number of notifications
+        count: usize,
+        /// The number of bytes of the notifications
+        bytes: usize,
+    },
+    /// A configuration assigned bins to a worker beyond the number of peers, which were assigned
+    /// to the last worker instead, see `WorkerPolicy::Clamp`.
+    WorkerClamped {
+        /// The time of the control instructions
+        time: T,
+        /// The configuration's sequence number
+        sequence: u64,
+        /// The worker the control instructions named
+        worker: usize,
+        /// The number of peers
+        peers: usize,
+    },
+    /// Spilling cold bins failed. The bins stay in memory.
+    SpillFailed {
+        /// The data frontier at which bins were spilled
+        frontier: Vec<T>,
+        /// The error writing the bins
+        error: String,
+    },
+}
+
+/// Log `event` to `logger`, if any.
+pub(crate) fn log<T: 'static>(logger: &Option<MegaphoneLogger<T>>, event: MegaphoneEvent<T>) {
+    if let Some(ref logger) = *logger {
+        logger.log(event);
+    }
+}
@@ -0,0 +1,113 @@
+//! State that can be migrated between workers.
+//!
+//! Each bin holds a state of a type implementing [`MigratableState`]. When a bin moves, its state
+//! is turned into a sequence of items of the wire format `W`, which the bin's new owner restores.
+//!
+//! [`MigratableState`]: trait.MigratableState.html
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+
+/// A per-bin state that can be sent to another worker as a sequence of items of type `W`.
+///
+/// A state starts out as its `Default` value. Restoring the items of a snapshot into it has to
+/// reproduce the snapshotted state. A bin may receive its state in several pieces, each of which
+/// is restored as it arrives.
+pub trait MigratableState<W>: Default {
+    /// The items of a snapshot.
+    type Snapshot: Iterator<Item=W>;
+
+    /// Consume the state and produce its items.
+    fn snapshot(self) -> Self::Snapshot;
+
+    /// Add the `items` of a snapshot to the state.
+    fn restore<I: IntoIterator<Item=W>>(&mut self, items: I);
+
+    /// The approximate size of the state's items on the wire, if known without inspecting each
+    /// item. States smaller than the configured chunk size are then sent without measuring them.
+    fn size_hint_bytes(&self) -> Option<usize> {
+        None
+    }
+
+    /// Copy the state's items without consuming it, if supported. Checkpoints encode these items
+    /// instead of taking a snapshot and restoring it.
+    fn items(&self) -> Option<Vec<W>> where W: Clone {
+        None
+    }
+
+    /// Combine `other` into this state, for example when merging bins on the same worker.
+    fn merge(&mut self, other: Self) {
+        self.restore(other.snapshot());
+    }
+}
+
+/// The size of `len` items of type `X`, if they own no memory. Types owning memory need to be
+/// dropped, and their items are measured instead.
+fn fixed_size<X>(len: usize) -> Option<usize> {
+    if ::std::mem::needs_drop::<X>() {
+        None
+    } else {
+        Some(len * ::std::mem::size_of::<X>())
+    }
+}
+
+impl<K: Eq+Hash, V, S: BuildHasher+Default> MigratableState<(K, V)> for HashMap<K, V, S> {
+    type Snapshot = ::std::collections::hash_map::IntoIter<K, V>;
+
+    fn snapshot(self) -> Self::Snapshot {
+        self.into_iter()
+    }
+
+    fn restore<I: IntoIterator<Item=(K, V)>>(&mut self, items: I) {
+        self.extend(items);
+    }
+
+    fn size_hint_bytes(&self) -> Option<usize> {
+        fixed_size::<(K, V)>(self.len())
+    }
+}
+
+impl<K: Ord, V> MigratableState<(K, V)> for BTreeMap<K, V> {
+    type Snapshot = ::std::collections::btree_map::IntoIter<K, V>;
+
+    fn snapshot(self) -> Self::Snapshot {
+        self.into_iter()
+    }
+
+    fn restore<I: IntoIterator<Item=(K, V)>>(&mut self, items: I) {
+        self.extend(items);
+    }
+
+    fn size_hint_bytes(&self) -> Option<usize> {
+        fixed_size::<(K, V)>(self.len())
+    }
+
+    fn merge(&mut self, mut other: Self) {
+        self.append(&mut other);
+    }
+}
+
+impl<T> MigratableState<T> for Vec<T> {
+    type Snapshot = ::std::vec::IntoIter<T>;
+
+    fn snapshot(self) -> Self::Snapshot {
+        self.into_iter()
+    }
+
+    fn restore<I: IntoIterator<Item=T>>(&mut self, items: I) {
+        self.extend(items);
+    }
+
+    fn size_hint_bytes(&self) -> Option<usize> {
+        fixed_size::<T>(self.len())
+    }
+
+    fn items(&self) -> Option<Vec<T>> where T: Clone {
+        Some(self.clone())
+    }
+
+    fn merge(&mut self, mut other: Self) {
+        self.append(&mut other);
+    }
+}
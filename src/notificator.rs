@@ -9,7 +9,8 @@ use timely::dataflow::operators::Capability;
 
 /// Common trait to all notificator implementations.
 ///
-/// Currently only prvides `drain`. TODO: Allow to schedule notifications.
+/// Currently only prvides `drain`. Stateful operators schedule notifications with
+/// `Bin::register_timer`.
 pub trait Notify<T: Timestamp, D> {
     /// Drain all pending notifications that are not in advance of `frontiers`.
     ///
@@ -90,6 +91,8 @@ pub trait Notify<T: Timestamp, D> {
 pub struct TotalOrderFrontierNotificator<T: Timestamp + TotalOrder, D = ()> {
     capability: Option<Capability<T>>,
     pending: BinaryHeap<OrderReversed<T, D>>,
+    // All frontiers were empty, no further notifications will be delivered
+    complete: bool,
 }
 
 impl<T: Timestamp + TotalOrder> TotalOrderFrontierNotificator<T, ()> {
@@ -101,6 +104,7 @@ impl<T: Timestamp + TotalOrder> TotalOrderFrontierNotificator<T, ()> {
         Self {
             capability,
             pending: pending.into_iter().map(|x| OrderReversed{ element: x.time().clone(), data: ()}).collect(),
+            complete: false,
         }
     }
 
@@ -165,6 +169,7 @@ impl<T: Timestamp + TotalOrder, D> TotalOrderFrontierNotificator<T, D> {
         Self {
             capability: None,
             pending: Default::default(),
+            complete: false,
 //            available: ::std::collections::BinaryHeap::new(),
         }
     }
@@ -227,10 +232,36 @@ impl<T: Timestamp + TotalOrder, D> TotalOrderFrontierNotificator<T, D> {
         }
     }
 
+    /// Iterate pending `(time, data)` pairs without consuming them, in no particular order.
+    pub fn iter_pending<'a>(&'a self) -> impl Iterator<Item=(&'a T, &'a D)> + 'a {
+        self.pending.iter().map(|e| (&e.element, &e.data))
+    }
+
     /// Descructures the notificator to obtain pending `(time, data)` pairs.
     pub fn pending(self) -> impl Iterator<Item=(T, D)> {
         self.pending.into_iter().map(|e| (e.element, e.data))
     }
+
+    /// The capability retained for pending notifications, at or before all of their times.
+    pub fn capability(&self) -> Option<&Capability<T>> {
+        self.capability.as_ref()
+    }
+
+    /// Remove pending notifications for which `predicate` returns `false`.
+    pub fn retain<P: FnMut(&T, &D)->bool>(&mut self, mut predicate: P) {
+        let mut pending = ::std::mem::replace(&mut self.pending, BinaryHeap::new()).into_vec();
+        pending.retain(|e| predicate(&e.element, &e.data));
+        self.pending = pending.into();
+        if self.pending.is_empty() {
+            self.capability.take();
+        }
+    }
+
+    /// Test if `drain` observed all frontiers empty. Notifications requested afterwards are not
+    /// delivered.
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
 }
 
 impl<T: Timestamp + TotalOrder, D> Notify<T, D> for TotalOrderFrontierNotificator<T, D> {
@@ -266,11 +297,126 @@ impl<T: Timestamp + TotalOrder, D> Notify<T, D> for TotalOrderFrontierNotificato
 
         if frontiers.iter().all(|f| f.is_empty()) {
             self.capability.take();
+            self.complete = true;
         }
         result
     }
 }
 
+/// Tracks requests for notification and delivers available notifications in partially ordered
+/// time domains.
+///
+/// A notification is available once no frontier is less or equal to its time. Unlike the
+/// `TotalOrderFrontierNotificator`, each pending notification retains a capability for its time,
+/// as the times of pending notifications need not have a single minimum. Available notifications
+/// are delivered in the order of their timestamps, which extends the partial order: a notification
+/// is never delivered after a notification at a greater time.
+pub struct PartialOrderFrontierNotificator<T: Timestamp, D = ()> {
+    pending: Vec<(Capability<T>, T, D)>,
+}
+
+impl<T: Timestamp> PartialOrderFrontierNotificator<T, ()> {
+
+    /// Requests a notification at the time associated with capability `cap`.
+    #[inline]
+    pub fn notify_at(&mut self, cap: &Capability<T>) {
+        self.notify_at_data(cap, cap.time().clone(), ());
+    }
+
+    /// Repeatedly calls `logic` till exhaustion of the notifications made available by inspecting
+    /// the frontiers.
+    ///
+    /// `logic` receives a capability for `t`, the timestamp being notified.
+    #[inline]
+    pub fn for_each<'a, F: FnMut(&Capability<T>, T, &mut Self)>(&mut self, frontiers: &'a [&'a MutableAntichain<T>], mut logic: F) {
+        let mut vec = Vec::new();
+        while let Some(cap) = self.drain(frontiers, &mut vec) {
+            for (time, _data) in vec.drain(..) {
+                logic(&cap.delayed(&time), time, self)
+            }
+        }
+    }
+}
+
+impl<T: Timestamp, D> PartialOrderFrontierNotificator<T, D> {
+    /// Allocates a new `PartialOrderFrontierNotificator`.
+    pub fn new() -> Self {
+        Self {
+            pending: Vec::new(),
+        }
+    }
+
+    /// Requests a notification at `time` with `data`, retaining a copy of `cap`, which must be
+    /// less or equal to `time`.
+    #[inline]
+    pub fn notify_at_data(&mut self, cap: &Capability<T>, time: T, data: D) {
+        assert!(cap.time().less_equal(&time), "provided capability must be <= notification time, found {:?} and {:?}", cap.time(), time);
+        self.pending.push((cap.clone(), time, data));
+    }
+
+    /// Repeatedly calls `logic` till exhaustion of the notifications made available by inspecting
+    /// the frontiers.
+    ///
+    /// `logic` receives a capability for `t`, the timestamp being notified.
+    #[inline]
+    pub fn for_each_data<'a, F: FnMut(&Capability<T>, T, D, &mut Self)>(&mut self, frontiers: &'a [&'a MutableAntichain<T>], mut logic: F) {
+        let mut vec = Vec::new();
+        while let Some(cap) = self.drain(frontiers, &mut vec) {
+            for (time, data) in vec.drain(..) {
+                logic(&cap.delayed(&time), time, data, self);
+            }
+        }
+    }
+
+    /// Iterate pending `(time, data)` pairs without consuming them, in no particular order.
+    pub fn iter_pending<'a>(&'a self) -> impl Iterator<Item=(&'a T, &'a D)> + 'a {
+        self.pending.iter().map(|e| (&e.1, &e.2))
+    }
+
+    /// Descructures the notificator to obtain pending `(time, data)` pairs.
+    pub fn pending(self) -> impl Iterator<Item=(T, D)> {
+        self.pending.into_iter().map(|e| (e.1, e.2))
+    }
+}
+
+impl<T: Timestamp, D> Default for PartialOrderFrontierNotificator<T, D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Timestamp, D> Notify<T, D> for PartialOrderFrontierNotificator<T, D> {
+
+    /// Drains available notifications in timestamp order, up to the first one the capability of the
+    /// earliest available notification does not cover. Call repeatedly until it returns `None` to
+    /// drain all available notifications.
+    #[inline]
+    fn drain(&mut self, frontiers: &[&MutableAntichain<T>], buffer: &mut Vec<(T, D)>) -> Option<Capability<T>> {
+        buffer.clear();
+        // Timestamp order extends the partial order. An unavailable notification is never less
+        // than an available one, as the frontier that holds it back holds back the other, too.
+        self.pending.sort_by(|a, b| a.1.cmp(&b.1));
+        let mut capability: Option<Capability<T>> = None;
+        let mut blocked = false;
+        let mut remaining = Vec::with_capacity(self.pending.len());
+        for (cap, time, data) in self.pending.drain(..) {
+            if !blocked && frontiers.iter().all(|f| !f.less_equal(&time)) {
+                if capability.is_none() {
+                    capability = Some(cap.clone());
+                }
+                if capability.as_ref().map_or(false, |c| c.time().less_equal(&time)) {
+                    buffer.push((time, data));
+                    continue;
+                }
+                blocked = true;
+            }
+            remaining.push((cap, time, data));
+        }
+        self.pending = remaining;
+        capability
+    }
+}
+
 struct OrderReversed<T, D> {
     pub element: T,
     pub data: D,
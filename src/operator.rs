@@ -1,22 +1,36 @@
 //! General purpose migratable operators.
 
+use std::cell::{RefCell, RefMut};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use fnv::FnvHashMap as HashMap;
+
 use timely::ExchangeData;
 use timely::dataflow::{Stream, Scope};
 use timely::communication::message::RefOrMut;
 use timely::dataflow::channels::pact::Exchange;
 use timely::dataflow::channels::pushers::Tee;
-use timely::dataflow::operators::{ConnectLoop, Filter, Map};
+use timely::dataflow::operators::{Concat, ConnectLoop, Filter, Map};
+use timely::dataflow::operators::feedback::Handle as FeedbackHandle;
 use timely::dataflow::operators::generic::builder_rc::OperatorBuilder;
 use timely::Data;
 use timely::dataflow::operators::Capability;
 use timely::dataflow::operators::generic::OutputHandle;
 use timely::order::TotalOrder;
+use timely::progress::Timestamp;
+use timely::progress::frontier::MutableAntichain;
 
-use ::{Bin, Control, Key, State};
-use stateful::{Stateful, apply_state_updates, Notificator};
+use ::{Bin, BinId, Control, Key, State, StatefulConfig};
+use stateful::{Stateful, apply_state_updates, Notificator, Rekey};
 use notificator::{Notify};
+use migratable::MigratableState;
+use tracked::Tracker;
 
 /// Building blocks for single- and dual-input stateful operators.
+///
+/// Each operator takes a `StatefulConfig`, which determines the number of bins. Operators sharing
+/// a control stream need to agree on the configuration.
 pub trait StatefulOperator<G, D1>
     where
         G: Scope,
@@ -27,13 +41,27 @@ pub trait StatefulOperator<G, D1>
     fn stateful_unary<
         D2: Data,                                    // output type
         B: Fn(&D1)->u64+'static,
-        S: Clone+IntoIterator<Item=W>+Extend<W>+Default+'static,
+        S: Clone+MigratableState<W>+'static,
+        W: ExchangeData,                            // State format on the wire
+        F: FnMut(&Capability<G::Timestamp>,
+            &mut Vec<(G::Timestamp, D1)>,
+            &mut Bin<G::Timestamp, S, D1>,
+            &mut OutputHandle<G::Timestamp, D2, Tee<G::Timestamp, D2>>) + 'static,    // state update logic
+    >(&self, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, key: B, name: &str, fold: F) -> Stream<G, D2>
+    ;
+
+    /// Stateful operator with a single input that supports splitting bins. `rekey` recovers the
+    /// keys of state and pending input data. A `tracker` enables pre-copying bins.
+    fn stateful_unary_rekey<
+        D2: Data,                                    // output type
+        B: Fn(&D1)->u64+'static,
+        S: Clone+MigratableState<W>+'static,
         W: ExchangeData,                            // State format on the wire
         F: FnMut(&Capability<G::Timestamp>,
             &mut Vec<(G::Timestamp, D1)>,
             &mut Bin<G::Timestamp, S, D1>,
             &mut OutputHandle<G::Timestamp, D2, Tee<G::Timestamp, D2>>) + 'static,    // state update logic
-    >(&self, control: &Stream<G, Control>, key: B, name: &str, fold: F) -> Stream<G, D2>
+    >(&self, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, key: B, rekey: Rekey<W, D1>, tracker: Option<Tracker<S, W>>, name: &str, fold: F) -> Stream<G, D2>
     ;
 
     /// Stateful operator with a single input and input transformation.
@@ -41,7 +69,7 @@ pub trait StatefulOperator<G, D1>
         D2: Data,                                    // output type
         N: ExchangeData+Eq,
         B: Fn(&D1)->u64+'static,                     // Key extraction function
-        S: Clone+IntoIterator<Item=W>+Extend<W>+Default+'static, // State type
+        S: Clone+MigratableState<W>+'static, // State type
         W: ExchangeData,                            // State format on the wire
         F: FnMut(&Capability<G::Timestamp>,
             &mut Vec<(G::Timestamp, N)>,
@@ -52,7 +80,49 @@ pub trait StatefulOperator<G, D1>
             G::Timestamp,
             RefOrMut<Vec<(usize, Key, D1)>>,
             &mut OutputHandle<G::Timestamp, D2, Tee<G::Timestamp, D2>>) + 'static,
-    >(&self, control: &Stream<G, Control>, key: B, name: &str, consume: C, fold: F) -> Stream<G, D2>
+    >(&self, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, key: B, name: &str, consume: C, fold: F) -> Stream<G, D2>
+    ;
+
+    /// Stateful operator with a single input and `sides` side outputs.
+    ///
+    /// `fold` receives a capability and output handle for the main output, and a capability and
+    /// output handle for each side output, at the time of the records' earliest notification.
+    /// Returns the main and the side outputs.
+    fn stateful_unary_side<
+        D2: Data,                                    // output type
+        D3: Data,                                    // side output type
+        B: Fn(&D1)->u64+'static,
+        S: Clone+MigratableState<W>+'static,
+        W: ExchangeData,                            // State format on the wire
+        F: FnMut(&Capability<G::Timestamp>,
+            &[Capability<G::Timestamp>],
+            &mut Vec<(G::Timestamp, D1)>,
+            &mut Bin<G::Timestamp, S, D1>,
+            &mut OutputHandle<G::Timestamp, D2, Tee<G::Timestamp, D2>>,
+            &mut [OutputHandle<G::Timestamp, D3, Tee<G::Timestamp, D3>>]) + 'static,    // state update logic
+    >(&self, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, key: B, name: &str, sides: usize, fold: F) -> (Stream<G, D2>, Vec<Stream<G, D3>>)
+    ;
+
+    /// Stateful operator with a single input that answers queries against its state.
+    ///
+    /// `queries` are routed with `query_key` by the same configuration as input records. `answer`
+    /// answers each query from the bin owning its key, once the bin reflects all records up to and
+    /// including the query's time. Queries for a bin in flight wait until its state is complete.
+    /// Returns the output and the stream of answers.
+    fn stateful_unary_query<
+        D2: Data,                                    // output type
+        Q: ExchangeData,                             // query type
+        A: Data,                                     // answer type
+        B: Fn(&D1)->u64+'static,
+        BQ: Fn(&Q)->u64+'static,                     // Key extraction function, queries
+        S: Clone+MigratableState<W>+'static,
+        W: ExchangeData,                            // State format on the wire
+        F: FnMut(&Capability<G::Timestamp>,
+            &mut Vec<(G::Timestamp, D1)>,
+            &mut Bin<G::Timestamp, S, D1>,
+            &mut OutputHandle<G::Timestamp, D2, Tee<G::Timestamp, D2>>) + 'static,    // state update logic
+        R: FnMut(Q, &mut S)->A+'static,             // query logic
+    >(&self, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, queries: &Stream<G, Q>, key: B, query_key: BQ, name: &str, fold: F, answer: R) -> (Stream<G, D2>, Stream<G, A>)
     ;
 
     /// Stateful operator with two inputs.
@@ -61,8 +131,8 @@ pub trait StatefulOperator<G, D1>
         D3: Data,                                    // output type
         B1: Fn(&D1)->u64+'static,                    // Key extraction function, input 1
         B2: Fn(&D2)->u64+'static,                    // Key extraction function, input 2
-        S1: Clone+IntoIterator<Item=W1>+Extend<W1>+Default+'static, // State type, input 1
-        S2: Clone+IntoIterator<Item=W2>+Extend<W2>+Default+'static, // State type, input 2
+        S1: Clone+MigratableState<W1>+'static, // State type, input 1
+        S2: Clone+MigratableState<W2>+'static, // State type, input 2
         W1: ExchangeData,                            // State format on the wire, input 1
         W2: ExchangeData,                            // State format on the wire, input 2
         F1: FnMut(&Capability<G::Timestamp>,
@@ -75,7 +145,7 @@ pub trait StatefulOperator<G, D1>
             &mut Bin<G::Timestamp, S1, D1>,
             &mut Bin<G::Timestamp, S2, D2>,
             &mut OutputHandle<G::Timestamp, D3, Tee<G::Timestamp, D3>>) + 'static,    // state update logic, input 2
-    >(&self, control: &Stream<G, Control>, other: &Stream<G, D2>, key1: B1, key2: B2, name: &str, fold1: F1, fold2: F2) -> Stream<G, D3>
+    >(&self, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, other: &Stream<G, D2>, key1: B1, key2: B2, name: &str, fold1: F1, fold2: F2) -> Stream<G, D3>
     ;
 
     /// Stateful operator with two inputs and input transformation.
@@ -86,8 +156,8 @@ pub trait StatefulOperator<G, D1>
         N2: ExchangeData,
         B1: Fn(&D1)->u64+'static,
         B2: Fn(&D2)->u64+'static,
-        S1: Clone+IntoIterator<Item=W1>+Extend<W1>+Default+'static,
-        S2: Clone+IntoIterator<Item=W2>+Extend<W2>+Default+'static,
+        S1: Clone+MigratableState<W1>+'static,
+        S2: Clone+MigratableState<W2>+'static,
         W1: ExchangeData,                            // State format on the wire
         W2: ExchangeData,                            // State format on the wire
         F1: FnMut(&Capability<G::Timestamp>,
@@ -110,11 +180,39 @@ pub trait StatefulOperator<G, D1>
             G::Timestamp,
             RefOrMut<Vec<(usize, Key, D2)>>,
             &mut OutputHandle<G::Timestamp, D3, Tee<G::Timestamp, D3>>) + 'static,
-    >(&self, control: &Stream<G, Control>, other: &Stream<G, D2>, key1: B1, key2: B2, name: &str, input1: C1, input2: C2, fold1: F1, fold2: F2) -> Stream<G, D3>
+    >(&self, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, other: &Stream<G, D2>, key1: B1, key2: B2, name: &str, input1: C1, input2: C2, fold1: F1, fold2: F2) -> Stream<G, D3>
+    ;
+
+    /// Stateful operator with two inputs and `sides` side outputs, see `stateful_unary_side`.
+    fn stateful_binary_side<
+        D2: ExchangeData+Eq,                         // input type
+        D3: Data,                                    // output type
+        D4: Data,                                    // side output type
+        B1: Fn(&D1)->u64+'static,                    // Key extraction function, input 1
+        B2: Fn(&D2)->u64+'static,                    // Key extraction function, input 2
+        S1: Clone+MigratableState<W1>+'static, // State type, input 1
+        S2: Clone+MigratableState<W2>+'static, // State type, input 2
+        W1: ExchangeData,                            // State format on the wire, input 1
+        W2: ExchangeData,                            // State format on the wire, input 2
+        F1: FnMut(&Capability<G::Timestamp>,
+            &[Capability<G::Timestamp>],
+            &mut Vec<(G::Timestamp, D1)>,
+            &mut Bin<G::Timestamp, S1, D1>,
+            &mut Bin<G::Timestamp, S2, D2>,
+            &mut OutputHandle<G::Timestamp, D3, Tee<G::Timestamp, D3>>,
+            &mut [OutputHandle<G::Timestamp, D4, Tee<G::Timestamp, D4>>]) + 'static,    // state update logic, input 1
+        F2: FnMut(&Capability<G::Timestamp>,
+            &[Capability<G::Timestamp>],
+            &mut Vec<(G::Timestamp, D2)>,
+            &mut Bin<G::Timestamp, S1, D1>,
+            &mut Bin<G::Timestamp, S2, D2>,
+            &mut OutputHandle<G::Timestamp, D3, Tee<G::Timestamp, D3>>,
+            &mut [OutputHandle<G::Timestamp, D4, Tee<G::Timestamp, D4>>]) + 'static,    // state update logic, input 2
+    >(&self, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, other: &Stream<G, D2>, key1: B1, key2: B2, name: &str, sides: usize, fold1: F1, fold2: F2) -> (Stream<G, D3>, Vec<Stream<G, D4>>)
     ;
 
     /// Move state to a worker as specified in the control input. Do not maintain state.
-    fn distribute<B1>(&self, control: &Stream<G, Control>, key: B1, name: &str) -> Stream<G, (usize, Key, D1)>
+    fn distribute<B1>(&self, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, key: B1, name: &str) -> Stream<G, (usize, Key, D1)>
     where
         B1: Fn(&D1)->u64+'static,
     ;
@@ -129,76 +227,36 @@ impl<G, D1> StatefulOperator<G, D1> for Stream<G, D1>
     fn stateful_unary<
         D2: Data,                                    // output type
         B: Fn(&D1)->u64+'static,
-        S: Clone+IntoIterator<Item=W>+Extend<W>+Default+'static,
+        S: Clone+MigratableState<W>+'static,
         W: ExchangeData,                            // State format on the wire
         F: FnMut(&Capability<G::Timestamp>,
             &mut Vec<(G::Timestamp, D1)>,
             &mut Bin<G::Timestamp, S, D1>,
             &mut OutputHandle<G::Timestamp, D2, Tee<G::Timestamp, D2>>) + 'static,    // state update logic
-    >(&self, control: &Stream<G, Control>, key: B, name: &str, mut fold: F) -> Stream<G, D2>
+    >(&self, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, key: B, name: &str, fold: F) -> Stream<G, D2>
     {
-        let stateful = self.stateful(key, control);
-        let states = stateful.state.clone();
-
-        let mut builder = OperatorBuilder::new(name.to_owned(), self.scope());
-
-        let mut input = builder.new_input(&stateful.stream, Exchange::new(move |&(target, _key, _)| target as u64));
-        let mut input_state = builder.new_input(&stateful.state_stream, Exchange::new(move |&(target, _)| target as u64));
-
-        let (mut output, stream) = builder.new_output();
-
-        let mut state_update_buffer = vec![];
-
-        let mut notificator = Notificator::new();
-
-        let mut not_drain = Vec::new();
-        let mut bin_drain = Vec::new();
-
-        // TODO: Should probably be written in terms of `stateful_unary_input`
-        builder.build(move |_capability| {
-            move |frontiers| {
-                let mut output_handle = output.activate();
-
-                let mut states = states.borrow_mut();
-                while let Some((time, data)) = input_state.next() {
-                    data.swap(&mut state_update_buffer);
-                    apply_state_updates(&mut states, &time.retain(), state_update_buffer.drain(..))
-                }
-                // stash each input and request a notification when ready
-                while let Some((time, data)) = input.next() {
-                    let mut data_buffer = vec![];
-                    data.swap(&mut data_buffer);
-                    let cap = time.retain();
-                    notificator.notify_at_data(&cap, cap.time().clone(), data_buffer);
-                }
-
-                if let Some(cap) = notificator.drain(&[&frontiers[0], &frontiers[1]], &mut not_drain) {
-                    for (time, mut keyed_data) in not_drain.drain(..) {
-                        for (_, key_id, d) in keyed_data.drain(..) {
-                            states.get(key_id).notificator.notify_at_data(&cap, time.clone(), d);
-                        }
-                    }
-                }
+        unary(self, control, config, key, None, None, name, fold)
+    }
 
-                // go through each time with data
-                for bin in states.bins.iter_mut().filter(|b| b.is_some()) {
-                    let bin = bin.as_mut().unwrap();
-                    if let Some(cap) = bin.notificator().drain(&[&frontiers[0], &frontiers[1]], &mut bin_drain) {
-                        fold(&cap, &mut bin_drain, bin, &mut output_handle);
-                    }
-                }
-            }
-        });
-        let progress_stream = stream.filter(|_| false).map(|_| ());
-        progress_stream.connect_loop(stateful.feedback);
-        stream
+    fn stateful_unary_rekey<
+        D2: Data,                                    // output type
+        B: Fn(&D1)->u64+'static,
+        S: Clone+MigratableState<W>+'static,
+        W: ExchangeData,                            // State format on the wire
+        F: FnMut(&Capability<G::Timestamp>,
+            &mut Vec<(G::Timestamp, D1)>,
+            &mut Bin<G::Timestamp, S, D1>,
+            &mut OutputHandle<G::Timestamp, D2, Tee<G::Timestamp, D2>>) + 'static,    // state update logic
+    >(&self, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, key: B, rekey: Rekey<W, D1>, tracker: Option<Tracker<S, W>>, name: &str, fold: F) -> Stream<G, D2>
+    {
+        unary(self, control, config, key, Some(rekey), tracker, name, fold)
     }
 
     fn stateful_unary_input<
         D2: Data,                                    // output type
         N: ExchangeData+Eq,
         B: Fn(&D1)->u64+'static,
-        S: Clone+IntoIterator<Item=W>+Extend<W>+Default+'static,
+        S: Clone+MigratableState<W>+'static,
         W: ExchangeData,                            // State format on the wire
         F: FnMut(&Capability<G::Timestamp>,
             &mut Vec<(G::Timestamp, N)>,
@@ -209,9 +267,50 @@ impl<G, D1> StatefulOperator<G, D1> for Stream<G, D1>
             G::Timestamp,
             RefOrMut<Vec<(usize, Key, D1)>>,
             &mut OutputHandle<G::Timestamp, D2, Tee<G::Timestamp, D2>>) + 'static,
-    >(&self, control: &Stream<G, Control>, key: B, name: &str, mut consume: C, mut fold: F) -> Stream<G, D2>
+    >(&self, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, key: B, name: &str, consume: C, mut fold: F) -> Stream<G, D2>
     {
-        let stateful = self.stateful(key, control);
+        unary_core(self, control, config, key, None, None, name, 0, consume,
+            move |cap, _side_caps, data, bin, output, _sides: &mut [OutputHandle<G::Timestamp, (), Tee<G::Timestamp, ()>>]| fold(cap, data, bin, output)).0
+    }
+
+    fn stateful_unary_side<
+        D2: Data,                                    // output type
+        D3: Data,                                    // side output type
+        B: Fn(&D1)->u64+'static,
+        S: Clone+MigratableState<W>+'static,
+        W: ExchangeData,                            // State format on the wire
+        F: FnMut(&Capability<G::Timestamp>,
+            &[Capability<G::Timestamp>],
+            &mut Vec<(G::Timestamp, D1)>,
+            &mut Bin<G::Timestamp, S, D1>,
+            &mut OutputHandle<G::Timestamp, D2, Tee<G::Timestamp, D2>>,
+            &mut [OutputHandle<G::Timestamp, D3, Tee<G::Timestamp, D3>>]) + 'static,    // state update logic
+    >(&self, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, key: B, name: &str, sides: usize, fold: F) -> (Stream<G, D2>, Vec<Stream<G, D3>>)
+    {
+        unary_core(self, control, config, key, None, None, name, sides, notify_bins(), fold)
+    }
+
+    fn stateful_unary_query<
+        D2: Data,                                    // output type
+        Q: ExchangeData,                             // query type
+        A: Data,                                     // answer type
+        B: Fn(&D1)->u64+'static,
+        BQ: Fn(&Q)->u64+'static,
+        S: Clone+MigratableState<W>+'static,
+        W: ExchangeData,                            // State format on the wire
+        F: FnMut(&Capability<G::Timestamp>,
+            &mut Vec<(G::Timestamp, D1)>,
+            &mut Bin<G::Timestamp, S, D1>,
+            &mut OutputHandle<G::Timestamp, D2, Tee<G::Timestamp, D2>>) + 'static,    // state update logic
+        R: FnMut(Q, &mut S)->A+'static,
+    >(&self, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, queries: &Stream<G, Q>, key: B, query_key: BQ, name: &str, mut fold: F, mut answer: R) -> (Stream<G, D2>, Stream<G, A>)
+    {
+        // Records and queries share a configuration by sharing the `stateful` operator
+        let routed = self.map(Routed::Record).concat(&queries.map(Routed::Query));
+        let stateful = routed.stateful::<W, S, _, D1>(move |routed: &Routed<D1, Q>| match *routed {
+            Routed::Record(ref d) => key(d),
+            Routed::Query(ref q) => query_key(q),
+        }, control, config, None, None);
         let states = stateful.state.clone();
 
         let mut builder = OperatorBuilder::new(name.to_owned(), self.scope());
@@ -220,52 +319,118 @@ impl<G, D1> StatefulOperator<G, D1> for Stream<G, D1>
         let mut input_state = builder.new_input(&stateful.state_stream, Exchange::new(move |&(target, _)| target as u64));
 
         let (mut output, stream) = builder.new_output();
+        let (mut answers_out, answers) = builder.new_output();
 
+        let mut routed_buffer = vec![];
         let mut state_update_buffer = vec![];
+
         let mut notificator = Notificator::new();
+        let mut query_notificator = Notificator::new();
 
         let mut not_drain = Vec::new();
+        let mut query_drain = Vec::new();
         let mut bin_drain = Vec::new();
 
+        // Queries whose time is complete, with a capability to answer them
+        let mut ready: Vec<(Capability<G::Timestamp>, Key, Q)> = Vec::new();
+
         builder.build(move |_capability| {
             move |frontiers| {
                 let mut output_handle = output.activate();
+                let mut answers_handle = answers_out.activate();
 
                 let mut states = states.borrow_mut();
                 while let Some((time, data)) = input_state.next() {
                     data.swap(&mut state_update_buffer);
-                    apply_state_updates(&mut states, &time.retain(), state_update_buffer.drain(..))
+                    apply_state_updates(&mut states, &time.retain(), state_update_buffer.drain(..), None)
                 }
-                // stash each input and request a notification when ready
-                while let Some((cap, data)) = input.next() {
-//                    if !frontiers[0].less_than(time.time()) && !frontiers[1].less_equal(time.time()) {
-//                        consume(&mut states, time.retain(), data, &mut output_handle);
-//                    } else {
-                        let mut data_buffer = vec![];
-                        data.swap(&mut data_buffer);
-                        let time = cap.time().clone();
-                        notificator.notify_at_data(&cap.retain(), time, data_buffer);
-//                    }
+                // stash records and queries separately, queries are answered on the second output
+                while let Some((time, data)) = input.next() {
+                    data.swap(&mut routed_buffer);
+                    let mut records = Vec::new();
+                    let mut queries = Vec::new();
+                    for (_, key_id, routed) in routed_buffer.drain(..) {
+                        match routed {
+                            Routed::Record(d) => records.push((key_id, d)),
+                            Routed::Query(q) => queries.push((key_id, q)),
+                        }
+                    }
+                    if !records.is_empty() {
+                        let cap = time.retain();
+                        notificator.notify_at_data(&cap, cap.time().clone(), records);
+                    }
+                    if !queries.is_empty() {
+                        let cap = time.retain_for_output(1);
+                        query_notificator.notify_at_data(&cap, cap.time().clone(), queries);
+                    }
                 }
 
                 if let Some(cap) = notificator.drain(&[&frontiers[0], &frontiers[1]], &mut not_drain) {
-                    for (time, mut data) in not_drain.drain(..) {
-                        consume(&mut states, &cap, time, RefOrMut::Mut(&mut data), &mut output_handle);
+                    for (time, mut records) in not_drain.drain(..) {
+                        for (key_id, d) in records.drain(..) {
+                            states.get(key_id).notificator.notify_at_data(&cap, time.clone(), d);
+                        }
+                    }
+                }
+
+                if let Some(cap) = query_notificator.drain(&[&frontiers[0], &frontiers[1]], &mut query_drain) {
+                    for (time, mut queries) in query_drain.drain(..) {
+                        ready.extend(queries.drain(..).map(|(key_id, q)| (cap.delayed(&time), key_id, q)));
                     }
                 }
 
-                // go through each time with data
-                for bin in states.bins.iter_mut().filter(|b| b.is_some()) {
-                    let bin = bin.as_mut().unwrap();
-                    if let Some(cap) = bin.notificator().drain(&[&frontiers[0], &frontiers[1]], &mut bin_drain) {
-                        fold(&cap, &mut bin_drain, bin, &mut output_handle);
+                // Group complete queries by bin, in time order
+                ready.sort_by(|a, b| a.0.time().cmp(b.0.time()));
+                let mut queries_by_bin: HashMap<usize, Vec<_>> = Default::default();
+                for (cap, key_id, q) in ready.drain(..) {
+                    queries_by_bin.entry(states.layout().bin(key_id)).or_insert_with(Vec::new).push((cap, key_id, q));
+                }
+
+                // go through each time with data, answering queries between the records of
+                // their time and later records
+                for index in 0..states.bins.len() {
+                    let queries = queries_by_bin.remove(&index).unwrap_or_else(Vec::new);
+                    if !queries.is_empty() {
+                        states.load(index);
+                    }
+                    // The bin's state is still arriving
+                    if states.bins[index].as_ref().map_or(true, |bin| bin.is_held()) {
+                        ready.extend(queries);
+                        continue;
+                    }
+                    let bin = states.bins[index].as_mut().unwrap();
+                    bin_drain.clear();
+                    let cap = bin.notificator().drain(&[&frontiers[0], &frontiers[1]], &mut bin_drain);
+                    for (query_cap, _, q) in queries {
+                        let applied = bin_drain.iter().take_while(|&&(ref time, _)| time.less_equal(query_cap.time())).count();
+                        if applied > 0 {
+                            let mut records: Vec<_> = bin_drain.drain(..applied).collect();
+                            let notifications = records.len();
+                            let start = Instant::now();
+                            fold(cap.as_ref().expect("Records without capability"), &mut records, bin, &mut output_handle);
+                            bin.record_fold(notifications, start.elapsed());
+                        }
+                        answers_handle.session(&query_cap).give(answer(q, bin.state()));
                     }
+                    if let Some(cap) = cap {
+                        if !bin_drain.is_empty() {
+                            let notifications = bin_drain.len();
+                            let start = Instant::now();
+                            fold(&cap, &mut bin_drain, bin, &mut output_handle);
+                            bin.record_fold(notifications, start.elapsed());
+                        }
+                    }
+                }
+                for (_, queries) in queries_by_bin.drain() {
+                    ready.extend(queries);
                 }
             }
         });
-        let progress_stream = stream.filter(|_| false).map(|_| ());
+        // Bins must not move while queries wait for them
+        let progress_stream = stream.filter(|_| false).map(|_| ())
+            .concat(&answers.filter(|_| false).map(|_| ()));
         progress_stream.connect_loop(stateful.feedback);
-        stream
+        (stream, answers)
     }
 
     fn stateful_binary<
@@ -273,8 +438,8 @@ impl<G, D1> StatefulOperator<G, D1> for Stream<G, D1>
         D3: Data,                                    // output type
         B1: Fn(&D1)->u64+'static,
         B2: Fn(&D2)->u64+'static,
-        S1: Clone+IntoIterator<Item=W1>+Extend<W1>+Default+'static,
-        S2: Clone+IntoIterator<Item=W2>+Extend<W2>+Default+'static,
+        S1: Clone+MigratableState<W1>+'static,
+        S2: Clone+MigratableState<W2>+'static,
         W1: ExchangeData,                            // State format on the wire
         W2: ExchangeData,                            // State format on the wire
         F1: FnMut(&Capability<G::Timestamp>,
@@ -287,25 +452,9 @@ impl<G, D1> StatefulOperator<G, D1> for Stream<G, D1>
             &mut Bin<G::Timestamp, S1, D1>,
             &mut Bin<G::Timestamp, S2, D2>,
             &mut OutputHandle<G::Timestamp, D3, Tee<G::Timestamp, D3>>) + 'static,    // state update logic
-    >(&self, control: &Stream<G, Control>, other: &Stream<G, D2>, key1: B1, key2: B2, name: &str, fold1: F1, fold2: F2) -> Stream<G, D3>
+    >(&self, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, other: &Stream<G, D2>, key1: B1, key2: B2, name: &str, fold1: F1, fold2: F2) -> Stream<G, D3>
     {
-
-        let mut data1_buffer = vec![];
-        let mut data2_buffer = vec![];
-
-        self.stateful_binary_input(control, other, key1, key2, name,
-            move |state, cap, time, data, _output| {
-                data.swap(&mut data1_buffer);
-                for (_worker, key_id, d) in data1_buffer.drain(..) {
-                    state.get(key_id).notificator().notify_at_data(&cap, time.clone(), d);
-                }
-            },
-            move |state, cap, time, data, _output| {
-               data.swap(&mut data2_buffer);
-               for (_worker, key_id, d) in data2_buffer.drain(..) {
-                   state.get(key_id).notificator().notify_at_data(&cap, time.clone(), d);
-               }
-           }, fold1, fold2)
+        self.stateful_binary_input(control, config, other, key1, key2, name, notify_bins(), notify_bins(), fold1, fold2)
     }
 
     fn stateful_binary_input<
@@ -315,8 +464,8 @@ impl<G, D1> StatefulOperator<G, D1> for Stream<G, D1>
         N2: ExchangeData,
         B1: Fn(&D1)->u64+'static,
         B2: Fn(&D2)->u64+'static,
-        S1: Clone+IntoIterator<Item=W1>+Extend<W1>+Default+'static,
-        S2: Clone+IntoIterator<Item=W2>+Extend<W2>+Default+'static,
+        S1: Clone+MigratableState<W1>+'static,
+        S2: Clone+MigratableState<W2>+'static,
         W1: ExchangeData,                            // State format on the wire
         W2: ExchangeData,                            // State format on the wire
         F1: FnMut(&Capability<G::Timestamp>,
@@ -339,102 +488,619 @@ impl<G, D1> StatefulOperator<G, D1> for Stream<G, D1>
             G::Timestamp,
             RefOrMut<Vec<(usize, Key, D2)>>,
             &mut OutputHandle<G::Timestamp, D3, Tee<G::Timestamp, D3>>) + 'static,
-    >(&self, control: &Stream<G, Control>, other: &Stream<G, D2>, key1: B1, key2: B2, name: &str, mut consume1: C1, mut consume2: C2, mut fold1: F1, mut fold2: F2) -> Stream<G, D3>
+    >(&self, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, other: &Stream<G, D2>, key1: B1, key2: B2, name: &str, consume1: C1, consume2: C2, mut fold1: F1, mut fold2: F2) -> Stream<G, D3>
     {
-        let stateful1 = self.stateful(key1, &control);
-        let stateful2 = other.stateful(key2, &control);
-        let states1 = stateful1.state.clone();
-        let states2 = stateful2.state.clone();
-
-        let mut builder = OperatorBuilder::new(name.to_owned(), self.scope());
+        binary_core(self, control, config, other, key1, key2, name, 0, consume1, consume2,
+            move |cap, _side_caps, data, bin1, bin2, output, _sides: &mut [OutputHandle<G::Timestamp, (), Tee<G::Timestamp, ()>>]| fold1(cap, data, bin1, bin2, output),
+            move |cap, _side_caps, data, bin1, bin2, output, _sides: &mut [OutputHandle<G::Timestamp, (), Tee<G::Timestamp, ()>>]| fold2(cap, data, bin1, bin2, output)).0
+    }
 
-        let mut input1 = builder.new_input(&stateful1.stream, Exchange::new(move |&(target, _key, _)| target as u64));
-        let mut input1_state = builder.new_input(&stateful1.state_stream, Exchange::new(move |&(target, _)| target as u64));
-        let mut input2 = builder.new_input(&stateful2.stream, Exchange::new(move |&(target, _key, _)| target as u64));
-        let mut input2_state = builder.new_input(&stateful2.state_stream, Exchange::new(move |&(target, _)| target as u64));
-        let (mut output, stream) = builder.new_output();
+    fn stateful_binary_side<
+        D2: ExchangeData+Eq,                         // input type
+        D3: Data,                                    // output type
+        D4: Data,                                    // side output type
+        B1: Fn(&D1)->u64+'static,                    // Key extraction function, input 1
+        B2: Fn(&D2)->u64+'static,                    // Key extraction function, input 2
+        S1: Clone+MigratableState<W1>+'static, // State type, input 1
+        S2: Clone+MigratableState<W2>+'static, // State type, input 2
+        W1: ExchangeData,                            // State format on the wire, input 1
+        W2: ExchangeData,                            // State format on the wire, input 2
+        F1: FnMut(&Capability<G::Timestamp>,
+            &[Capability<G::Timestamp>],
+            &mut Vec<(G::Timestamp, D1)>,
+            &mut Bin<G::Timestamp, S1, D1>,
+            &mut Bin<G::Timestamp, S2, D2>,
+            &mut OutputHandle<G::Timestamp, D3, Tee<G::Timestamp, D3>>,
+            &mut [OutputHandle<G::Timestamp, D4, Tee<G::Timestamp, D4>>]) + 'static,    // state update logic, input 1
+        F2: FnMut(&Capability<G::Timestamp>,
+            &[Capability<G::Timestamp>],
+            &mut Vec<(G::Timestamp, D2)>,
+            &mut Bin<G::Timestamp, S1, D1>,
+            &mut Bin<G::Timestamp, S2, D2>,
+            &mut OutputHandle<G::Timestamp, D3, Tee<G::Timestamp, D3>>,
+            &mut [OutputHandle<G::Timestamp, D4, Tee<G::Timestamp, D4>>]) + 'static,    // state update logic, input 2
+    >(&self, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, other: &Stream<G, D2>, key1: B1, key2: B2, name: &str, sides: usize, fold1: F1, fold2: F2) -> (Stream<G, D3>, Vec<Stream<G, D4>>)
+    {
+        binary_core(self, control, config, other, key1, key2, name, sides, notify_bins(), notify_bins(), fold1, fold2)
+    }
 
-        let mut not1_drain = Vec::new();
-        let mut not2_drain = Vec::new();
-        let mut bin1_drain = Vec::new();
-        let mut bin2_drain = Vec::new();
+    fn distribute<B1>(&self, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, key: B1, name: &str) -> Stream<G, (usize, Key, D1)>
+        where
+            B1: Fn(&D1)->u64+'static,
+    {
+        let mut data_vec = vec![];
+        self.stateful_unary_input::<_, (), _, Vec<()>, _, _, _>(control, config, key, name, move |_state, cap, _time, data, output| {
+            data.swap(&mut data_vec);
+            output.session(&cap).give_vec(&mut data_vec);
+        }, |_cap, _data, _bin, _output| {})
+    }
 
-        builder.build(move |_capability| {
-            let mut state1_update_buffer = vec![];
-            let mut state2_update_buffer = vec![];
+}
 
-            let mut notificator1 = Notificator::new();
-            let mut notificator2 = Notificator::new();
+/// Builds a stateful operator with any number of co-partitioned inputs.
+///
+/// Each input is keyed by its own function and keeps its own typed bins, routed by its own
+/// `stateful` operator. All inputs share the control stream and configuration, so bins with the
+/// same identifier migrate together. The fold is called for a bin once any input has records due
+/// in it, and accesses each input's records and bin through the handle `add_input` returned.
+///
+/// ```ignore
+/// let mut builder = StatefulBuilder::new("Q3", &control, &config);
+/// let people = builder.add_input::<_, HashMap<u64, Person>, _, _>(&people, |p: &Person| p.id);
+/// let auctions = builder.add_input::<_, Vec<Auction>, _, _>(&auctions, |a: &Auction| a.seller);
+/// let output = builder.build(move |cap, bin, output| {
+///     let mut known = people.bin(bin);
+///     for (time, auction) in auctions.data().drain(..) { ... }
+/// });
+/// ```
+pub struct StatefulBuilder<G: Scope> {
+    control: Stream<G, Control>,
+    config: StatefulConfig<G::Timestamp>,
+    builder: OperatorBuilder<G>,
+    inputs: Vec<Box<dyn BuilderInput<G::Timestamp>>>,
+    feedbacks: Vec<FeedbackHandle<G, ()>>,
+}
 
-            move |frontiers| {
-                let mut output_handle = output.activate();
+impl<G> StatefulBuilder<G>
+    where
+        G: Scope,
+        G::Timestamp: TotalOrder,
+{
+    /// Start a stateful operator called `name` whose inputs share `control` and `config`.
+    pub fn new(name: &str, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>) -> Self {
+        Self {
+            control: control.clone(),
+            config: config.clone(),
+            builder: OperatorBuilder::new(name.to_owned(), control.scope()),
+            inputs: Vec::new(),
+            feedbacks: Vec::new(),
+        }
+    }
 
-                let mut states1 = states1.borrow_mut();
-                let mut states2 = states2.borrow_mut();
+    /// Add `stream` as an input keyed by `key`, whose bins hold state of type `S`. Returns the
+    /// handle accessing the input's records and bins while folding.
+    pub fn add_input<D, S, W, B>(&mut self, stream: &Stream<G, D>, key: B) -> StatefulInput<G::Timestamp, S, D>
+        where
+            D: ExchangeData+Eq,
+            S: Clone+MigratableState<W>+'static,
+            W: ExchangeData,                            // State format on the wire
+            B: Fn(&D)->u64+'static,
+    {
+        let stateful = stream.stateful::<W, S, _, D>(key, &self.control, &self.config, None, None);
+        let input = StatefulInput { states: stateful.state.clone(), buffer: Default::default() };
 
-                while let Some((time, data)) = input1_state.next() {
-                    data.swap(&mut state1_update_buffer);
-                    apply_state_updates(&mut states1, &time.retain(), state1_update_buffer.drain(..))
-                }
-                while let Some((time, data)) = input2_state.next() {
-                    data.swap(&mut state2_update_buffer);
-                    apply_state_updates(&mut states2, &time.retain(), state2_update_buffer.drain(..))
-                }
+        // The index of the input's first frontier
+        let first = 2 * self.inputs.len();
+        let mut data_in = self.builder.new_input(&stateful.stream, Exchange::new(move |&(target, _key, _)| target as u64));
+        let mut state_in = self.builder.new_input(&stateful.state_stream, Exchange::new(move |&(target, _)| target as u64));
 
-                // stash each input and request a notification when ready
-                while let Some((cap, data)) = input1.next() {
-                    let mut data1_buffer = vec![];
-                    data.swap(&mut data1_buffer);
-                    let time = cap.time().clone();
-                    notificator1.notify_at_data(&cap.retain(), time, data1_buffer);
-                }
+        let mut state_update_buffer = vec![];
+        let mut notificator = Notificator::new();
+        let mut not_drain = Vec::new();
 
-                while let Some((cap, data)) = input2.next() {
-                    let mut data2_buffer = vec![];
-                    data.swap(&mut data2_buffer);
-                    let time = cap.time().clone();
-                    notificator2.notify_at_data(&cap.retain(), time, data2_buffer);
-                }
+        let receive = move |states: &mut State<G::Timestamp, S, D>, frontiers: &[&MutableAntichain<G::Timestamp>]| {
+            while let Some((time, data)) = state_in.next() {
+                data.swap(&mut state_update_buffer);
+                apply_state_updates(states, &time.retain(), state_update_buffer.drain(..), None)
+            }
+            // stash each input and request a notification when ready
+            while let Some((time, data)) = data_in.next() {
+                let mut data_buffer = vec![];
+                data.swap(&mut data_buffer);
+                let cap = time.retain();
+                notificator.notify_at_data(&cap, cap.time().clone(), data_buffer);
+            }
 
-                if let Some(cap) = notificator1.drain(&[&frontiers[0], &frontiers[1]], &mut not1_drain) {
-                    for (time, mut data) in not1_drain.drain(..) {
-                        consume1(&mut states1, &cap, time, RefOrMut::Mut(&mut data), &mut output_handle);
+            if let Some(cap) = notificator.drain(&frontiers[first..first + 2], &mut not_drain) {
+                for (time, mut keyed_data) in not_drain.drain(..) {
+                    for (_, key_id, d) in keyed_data.drain(..) {
+                        states.get(key_id).notificator().notify_at_data(&cap, time.clone(), d);
                     }
                 }
+            }
+        };
 
-                if let Some(cap) = notificator2.drain(&[&frontiers[2], &frontiers[3]], &mut not2_drain) {
-                    for (time, mut data) in not2_drain.drain(..) {
-                        consume2(&mut states2, &cap, time, RefOrMut::Mut(&mut data), &mut output_handle);
-                    }
+        self.inputs.push(Box::new(TypedInput { input: input.clone(), receive: Box::new(receive), drained: 0 }));
+        self.feedbacks.push(stateful.feedback);
+        input
+    }
+
+    /// Construct the operator, applying `fold` to each bin with records due in any input.
+    pub fn build<
+        D2: Data,                                    // output type
+        F: FnMut(&Capability<G::Timestamp>,
+            &FoldedBin,
+            &mut OutputHandle<G::Timestamp, D2, Tee<G::Timestamp, D2>>) + 'static,    // state update logic
+    >(self, mut fold: F) -> Stream<G, D2>
+    {
+        assert!(!self.inputs.is_empty(), "Stateful operator without inputs");
+        let StatefulBuilder { mut builder, mut inputs, feedbacks, .. } = self;
+
+        let (mut output, stream) = builder.new_output();
+
+        builder.build(move |_capability| {
+            move |frontiers| {
+                let mut output_handle = output.activate();
+                let frontiers: Vec<_> = frontiers.iter().collect();
+
+                for input in inputs.iter_mut() {
+                    input.receive(&frontiers);
                 }
 
-                // go through each time with data
-                for (bin1, bin2) in states1.bins.iter_mut().zip(states2.bins.iter_mut()).filter(|(b1, b2)| b1.is_some() && b2.is_some()) {
-                    let (bin1, bin2) = (bin1.as_mut().unwrap(), bin2.as_mut().unwrap());
-                    if let Some(cap) = bin1.notificator().drain(&[&frontiers[0], &frontiers[1], &frontiers[2], &frontiers[3]], &mut bin1_drain) {
-                        fold1(&cap, &mut bin1_drain, bin1, bin2, &mut output_handle);
+                // go through each bin available in all inputs, folding at the earliest capability.
+                // Inputs learn of split bins independently.
+                let bins = inputs.iter().map(|input| input.bins()).max().unwrap_or(0);
+                for bin in 0..bins {
+                    if !inputs.iter().all(|input| input.is_ready(bin)) {
+                        continue;
+                    }
+                    let mut cap: Option<Capability<G::Timestamp>> = None;
+                    for input in inputs.iter_mut() {
+                        if let Some(input_cap) = input.drain(bin, &frontiers) {
+                            if cap.as_ref().map_or(true, |cap| input_cap.time().less_than(cap.time())) {
+                                cap = Some(input_cap);
+                            }
+                        }
                     }
-                    if let Some(cap) = bin2.notificator().drain(&[&frontiers[0], &frontiers[1], &frontiers[2], &frontiers[3]], &mut bin2_drain) {
-                        fold2(&cap, &mut bin2_drain, bin1, bin2, &mut output_handle);
+                    if let Some(cap) = cap {
+                        let start = Instant::now();
+                        fold(&cap, &FoldedBin { bin }, &mut output_handle);
+                        let elapsed = start.elapsed();
+                        for input in inputs.iter_mut() {
+                            input.record_fold(bin, elapsed);
+                        }
                     }
                 }
             }
         });
         let progress_stream = stream.filter(|_| false).map(|_| ());
-        progress_stream.connect_loop(stateful1.feedback);
-        progress_stream.connect_loop(stateful2.feedback);
+        for feedback in feedbacks {
+            progress_stream.connect_loop(feedback);
+        }
         stream
     }
+}
 
-    fn distribute<B1>(&self, control: &Stream<G, Control>, key: B1, name: &str) -> Stream<G, (usize, Key, D1)>
-        where
-            B1: Fn(&D1)->u64+'static,
-    {
-        let mut data_vec = vec![];
-        self.stateful_unary_input::<_, (), _, Vec<()>, _, _, _>(control, key, name, move |_state, cap, _time, data, output| {
-            data.swap(&mut data_vec);
-            output.session(&cap).give_vec(&mut data_vec);
-        }, |_cap, _data, _bin, _output| {})
+/// The bin a `StatefulBuilder` operator folds, see `StatefulInput`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FoldedBin {
+    bin: usize,
+}
+
+impl FoldedBin {
+    /// The identifier of the bin.
+    pub fn id(&self) -> BinId {
+        BinId(self.bin)
     }
+}
+
+/// An input of a `StatefulBuilder`, giving typed access to its records and bins while folding.
+pub struct StatefulInput<T: Timestamp+TotalOrder, S, D> {
+    states: Rc<RefCell<State<T, S, D>>>,
+    // The input's records due in the folded bin
+    buffer: Rc<RefCell<Vec<(T, D)>>>,
+}
 
+impl<T: Timestamp+TotalOrder, S, D> StatefulInput<T, S, D> {
+    /// The input's records due in the folded bin, in time order. Records left in the buffer are
+    /// dropped after the fold.
+    pub fn data(&self) -> RefMut<Vec<(T, D)>> {
+        self.buffer.borrow_mut()
+    }
+
+    /// The input's bin with the identifier of `bin`.
+    pub fn bin(&self, bin: &FoldedBin) -> RefMut<Bin<T, S, D>> {
+        let mut states = self.states.borrow_mut();
+        states.load(bin.bin);
+        RefMut::map(states, |states| states.bins[bin.bin].as_mut().expect("Folding missing bin"))
+    }
+}
+
+impl<T: Timestamp+TotalOrder, S, D> Clone for StatefulInput<T, S, D> {
+    fn clone(&self) -> Self {
+        Self {
+            states: Rc::clone(&self.states),
+            buffer: Rc::clone(&self.buffer),
+        }
+    }
+}
+
+/// The operations of a `StatefulBuilder` input, independent of its record and state types.
+trait BuilderInput<T: Timestamp> {
+    /// Apply state updates and route records of complete times to their bins.
+    fn receive(&mut self, frontiers: &[&MutableAntichain<T>]);
+    /// The number of bins.
+    fn bins(&self) -> usize;
+    /// Test if `bin` is present and its state complete.
+    fn is_ready(&self, bin: usize) -> bool;
+    /// Move the notifications of `bin` due at `frontiers` to the input's buffer.
+    fn drain(&mut self, bin: usize, frontiers: &[&MutableAntichain<T>]) -> Option<Capability<T>>;
+    /// Record folding the drained notifications of `bin` and clear the buffer.
+    fn record_fold(&mut self, bin: usize, elapsed: Duration);
+}
+
+/// An input of a `StatefulBuilder` with its record and state types.
+struct TypedInput<T: Timestamp+TotalOrder, S, D> {
+    input: StatefulInput<T, S, D>,
+    // Applies state updates and routes records of complete times to their bins
+    receive: Box<dyn FnMut(&mut State<T, S, D>, &[&MutableAntichain<T>])>,
+    // The number of notifications drained for the folded bin
+    drained: usize,
+}
+
+impl<T: Timestamp+TotalOrder, S, D> BuilderInput<T> for TypedInput<T, S, D> {
+    fn receive(&mut self, frontiers: &[&MutableAntichain<T>]) {
+        let mut states = self.input.states.borrow_mut();
+        (self.receive)(&mut *states, frontiers);
+    }
+
+    fn bins(&self) -> usize {
+        self.input.states.borrow().bins.len()
+    }
+
+    fn is_ready(&self, bin: usize) -> bool {
+        self.input.states.borrow().bins.get(bin).map_or(false, is_ready)
+    }
+
+    fn drain(&mut self, bin: usize, frontiers: &[&MutableAntichain<T>]) -> Option<Capability<T>> {
+        let mut buffer = self.input.buffer.borrow_mut();
+        buffer.clear();
+        let mut states = self.input.states.borrow_mut();
+        let cap = states.bins.get_mut(bin).and_then(Option::as_mut).and_then(|bin| bin.notificator().drain(frontiers, &mut buffer));
+        self.drained = buffer.len();
+        cap
+    }
+
+    fn record_fold(&mut self, bin: usize, elapsed: Duration) {
+        if self.drained > 0 {
+            if let Some(bin) = self.input.states.borrow_mut().bins.get_mut(bin).and_then(Option::as_mut) {
+                bin.record_fold(self.drained, elapsed);
+            }
+            self.drained = 0;
+        }
+        self.input.buffer.borrow_mut().clear();
+    }
+}
+
+/// Input records and queries of a queryable operator, routed together.
+#[derive(Abomonation, Clone, Debug)]
+enum Routed<D, Q> {
+    Record(D),
+    Query(Q),
+}
+
+/// Single-input stateful operator, optionally supporting splitting and pre-copying bins.
+fn unary<
+    G: Scope,
+    D1: ExchangeData+Eq,                         // input type
+    D2: Data,                                    // output type
+    B: Fn(&D1)->u64+'static,
+    S: Clone+MigratableState<W>+'static,
+    W: ExchangeData,                            // State format on the wire
+    F: FnMut(&Capability<G::Timestamp>,
+        &mut Vec<(G::Timestamp, D1)>,
+        &mut Bin<G::Timestamp, S, D1>,
+        &mut OutputHandle<G::Timestamp, D2, Tee<G::Timestamp, D2>>) + 'static,    // state update logic
+>(source: &Stream<G, D1>, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, key: B, rekey: Option<Rekey<W, D1>>, tracker: Option<Tracker<S, W>>, name: &str, mut fold: F) -> Stream<G, D2>
+    where
+        G::Timestamp: TotalOrder,
+{
+    unary_core(source, control, config, key, rekey, tracker, name, 0, notify_bins(),
+        move |cap, _side_caps, data, bin, output, _sides: &mut [OutputHandle<G::Timestamp, (), Tee<G::Timestamp, ()>>]| fold(cap, data, bin, output)).0
+}
+
+/// Consume routed records by notifying the bins of their keys at the records' time.
+fn notify_bins<T, S, D, D2>() -> impl FnMut(&mut State<T, S, D>, &Capability<T>, T, RefOrMut<Vec<(usize, Key, D)>>, &mut OutputHandle<T, D2, Tee<T, D2>>)
+    where
+        T: Timestamp+TotalOrder,
+        D: Clone,
+        D2: Data,
+{
+    let mut data_buffer = vec![];
+    move |state, cap, time, data, _output| {
+        data.swap(&mut data_buffer);
+        for (_worker, key_id, d) in data_buffer.drain(..) {
+            state.get(key_id).notificator().notify_at_data(cap, time.clone(), d);
+        }
+    }
+}
+
+/// The single-input operators: `consume` receives routed records once their time is complete,
+/// and `fold` the notifications of each bin, with a capability and output handle for each of the
+/// `sides` side outputs.
+fn unary_core<
+    G: Scope,
+    D1: ExchangeData+Eq,                         // input type
+    N: ExchangeData,                             // notification type
+    D2: Data,                                    // output type
+    D3: Data,                                    // side output type
+    B: Fn(&D1)->u64+'static,
+    S: Clone+MigratableState<W>+'static,
+    W: ExchangeData,                            // State format on the wire
+    C: FnMut(&mut State<G::Timestamp, S, N>,
+        &Capability<G::Timestamp>,
+        G::Timestamp,
+        RefOrMut<Vec<(usize, Key, D1)>>,
+        &mut OutputHandle<G::Timestamp, D2, Tee<G::Timestamp, D2>>) + 'static,
+    F: FnMut(&Capability<G::Timestamp>,
+        &[Capability<G::Timestamp>],
+        &mut Vec<(G::Timestamp, N)>,
+        &mut Bin<G::Timestamp, S, N>,
+        &mut OutputHandle<G::Timestamp, D2, Tee<G::Timestamp, D2>>,
+        &mut [OutputHandle<G::Timestamp, D3, Tee<G::Timestamp, D3>>]) + 'static,    // state update logic
+>(source: &Stream<G, D1>, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, key: B, rekey: Option<Rekey<W, N>>, tracker: Option<Tracker<S, W>>, name: &str, sides: usize, mut consume: C, mut fold: F) -> (Stream<G, D2>, Vec<Stream<G, D3>>)
+    where
+        G::Timestamp: TotalOrder,
+{
+    let stateful = source.stateful(key, control, config, rekey, tracker);
+    let states = stateful.state.clone();
+
+    let mut builder = OperatorBuilder::new(name.to_owned(), source.scope());
+
+    let mut input = builder.new_input(&stateful.stream, Exchange::new(move |&(target, _key, _)| target as u64));
+    let mut input_state = builder.new_input(&stateful.state_stream, Exchange::new(move |&(target, _)| target as u64));
+
+    let (mut output, stream) = builder.new_output();
+    let (mut side_outputs, side_streams): (Vec<_>, Vec<_>) = (0..sides).map(|_| builder.new_output()).unzip();
+
+    let mut state_update_buffer = vec![];
+
+    let mut notificator = Notificator::new();
+
+    let mut not_drain = Vec::new();
+    let mut bin_drain = Vec::new();
+
+    builder.build(move |mut capabilities| {
+        // Capabilities for the side outputs, never after a capability held for the main output
+        let mut side_caps = capabilities.split_off(1);
+        move |frontiers| {
+            let mut output_handle = output.activate();
+            let mut side_handles: Vec<_> = side_outputs.iter_mut().map(|side| side.activate()).collect();
+
+            let mut states = states.borrow_mut();
+            while let Some((time, data)) = input_state.next() {
+                data.swap(&mut state_update_buffer);
+                apply_state_updates(&mut states, &time.retain(), state_update_buffer.drain(..), tracker)
+            }
+            // stash each input and request a notification when ready
+            while let Some((time, data)) = input.next() {
+                let mut data_buffer = vec![];
+                data.swap(&mut data_buffer);
+                let cap = time.retain();
+                notificator.notify_at_data(&cap, cap.time().clone(), data_buffer);
+            }
+
+            if let Some(cap) = notificator.drain(&[&frontiers[0], &frontiers[1]], &mut not_drain) {
+                for (time, mut data) in not_drain.drain(..) {
+                    consume(&mut states, &cap, time, RefOrMut::Mut(&mut data), &mut output_handle);
+                }
+            }
+
+            // go through each time with data
+            for bin in states.bins.iter_mut().filter(|b| b.as_ref().map_or(false, |b| !b.is_held())) {
+                let bin = bin.as_mut().unwrap();
+                if let Some(cap) = bin.notificator().drain(&[&frontiers[0], &frontiers[1]], &mut bin_drain) {
+                    let side: Vec<_> = side_caps.iter().map(|side| side.delayed(cap.time())).collect();
+                    let notifications = bin_drain.len();
+                    let start = Instant::now();
+                    fold(&cap, &side, &mut bin_drain, bin, &mut output_handle, &mut side_handles[..]);
+                    bin.record_fold(notifications, start.elapsed());
+                }
+            }
+
+            if !side_caps.is_empty() {
+                let held = notificator.capability().into_iter()
+                    .chain(states.bins.iter().filter_map(|b| b.as_ref().and_then(|b| b.notificator.capability())));
+                downgrade_sides(&mut side_caps, &[&frontiers[0], &frontiers[1]], held);
+            }
+        }
+    });
+    let progress_stream = side_streams.iter()
+        .fold(stream.filter(|_| false).map(|_| ()), |progress, side| progress.concat(&side.filter(|_| false).map(|_| ())));
+    progress_stream.connect_loop(stateful.feedback);
+    (stream, side_streams)
+}
+
+/// The dual-input operators, see `unary_core`. Bins of both inputs are folded while neither is
+/// held.
+fn binary_core<
+    G: Scope,
+    D1: ExchangeData+Eq,                         // input type, input 1
+    D2: ExchangeData+Eq,                         // input type, input 2
+    D3: Data,                                    // output type
+    D4: Data,                                    // side output type
+    N1: ExchangeData,                            // notification type, input 1
+    N2: ExchangeData,                            // notification type, input 2
+    B1: Fn(&D1)->u64+'static,
+    B2: Fn(&D2)->u64+'static,
+    S1: Clone+MigratableState<W1>+'static,
+    S2: Clone+MigratableState<W2>+'static,
+    W1: ExchangeData,                            // State format on the wire
+    W2: ExchangeData,                            // State format on the wire
+    C1: FnMut(&mut State<G::Timestamp, S1, N1>,
+        &Capability<G::Timestamp>,
+        G::Timestamp,
+        RefOrMut<Vec<(usize, Key, D1)>>,
+        &mut OutputHandle<G::Timestamp, D3, Tee<G::Timestamp, D3>>) + 'static,
+    C2: FnMut(&mut State<G::Timestamp, S2, N2>,
+        &Capability<G::Timestamp>,
+        G::Timestamp,
+        RefOrMut<Vec<(usize, Key, D2)>>,
+        &mut OutputHandle<G::Timestamp, D3, Tee<G::Timestamp, D3>>) + 'static,
+    F1: FnMut(&Capability<G::Timestamp>,
+        &[Capability<G::Timestamp>],
+        &mut Vec<(G::Timestamp, N1)>,
+        &mut Bin<G::Timestamp, S1, N1>,
+        &mut Bin<G::Timestamp, S2, N2>,
+        &mut OutputHandle<G::Timestamp, D3, Tee<G::Timestamp, D3>>,
+        &mut [OutputHandle<G::Timestamp, D4, Tee<G::Timestamp, D4>>]) + 'static,    // state update logic, input 1
+    F2: FnMut(&Capability<G::Timestamp>,
+        &[Capability<G::Timestamp>],
+        &mut Vec<(G::Timestamp, N2)>,
+        &mut Bin<G::Timestamp, S1, N1>,
+        &mut Bin<G::Timestamp, S2, N2>,
+        &mut OutputHandle<G::Timestamp, D3, Tee<G::Timestamp, D3>>,
+        &mut [OutputHandle<G::Timestamp, D4, Tee<G::Timestamp, D4>>]) + 'static,    // state update logic, input 2
+>(source: &Stream<G, D1>, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, other: &Stream<G, D2>, key1: B1, key2: B2, name: &str, sides: usize, mut consume1: C1, mut consume2: C2, mut fold1: F1, mut fold2: F2) -> (Stream<G, D3>, Vec<Stream<G, D4>>)
+    where
+        G::Timestamp: TotalOrder,
+{
+    let stateful1 = source.stateful(key1, &control, config, None, None);
+    let stateful2 = other.stateful(key2, &control, config, None, None);
+    let states1 = stateful1.state.clone();
+    let states2 = stateful2.state.clone();
+
+    let mut builder = OperatorBuilder::new(name.to_owned(), source.scope());
+
+    let mut input1 = builder.new_input(&stateful1.stream, Exchange::new(move |&(target, _key, _)| target as u64));
+    let mut input1_state = builder.new_input(&stateful1.state_stream, Exchange::new(move |&(target, _)| target as u64));
+    let mut input2 = builder.new_input(&stateful2.stream, Exchange::new(move |&(target, _key, _)| target as u64));
+    let mut input2_state = builder.new_input(&stateful2.state_stream, Exchange::new(move |&(target, _)| target as u64));
+    let (mut output, stream) = builder.new_output();
+    let (mut side_outputs, side_streams): (Vec<_>, Vec<_>) = (0..sides).map(|_| builder.new_output()).unzip();
+
+    let mut not1_drain = Vec::new();
+    let mut not2_drain = Vec::new();
+    let mut bin1_drain = Vec::new();
+    let mut bin2_drain = Vec::new();
+
+    builder.build(move |mut capabilities| {
+        let mut state1_update_buffer = vec![];
+        let mut state2_update_buffer = vec![];
+
+        let mut notificator1 = Notificator::new();
+        let mut notificator2 = Notificator::new();
+
+        // Capabilities for the side outputs, never after a capability held for the main output
+        let mut side_caps = capabilities.split_off(1);
+
+        move |frontiers| {
+            let mut output_handle = output.activate();
+            let mut side_handles: Vec<_> = side_outputs.iter_mut().map(|side| side.activate()).collect();
+
+            let mut states1 = states1.borrow_mut();
+            let mut states2 = states2.borrow_mut();
+
+            while let Some((time, data)) = input1_state.next() {
+                data.swap(&mut state1_update_buffer);
+                apply_state_updates(&mut states1, &time.retain(), state1_update_buffer.drain(..), None)
+            }
+            while let Some((time, data)) = input2_state.next() {
+                data.swap(&mut state2_update_buffer);
+                apply_state_updates(&mut states2, &time.retain(), state2_update_buffer.drain(..), None)
+            }
+
+            // stash each input and request a notification when ready
+            while let Some((cap, data)) = input1.next() {
+                let mut data1_buffer = vec![];
+                data.swap(&mut data1_buffer);
+                let time = cap.time().clone();
+                notificator1.notify_at_data(&cap.retain(), time, data1_buffer);
+            }
+
+            while let Some((cap, data)) = input2.next() {
+                let mut data2_buffer = vec![];
+                data.swap(&mut data2_buffer);
+                let time = cap.time().clone();
+                notificator2.notify_at_data(&cap.retain(), time, data2_buffer);
+            }
+
+            if let Some(cap) = notificator1.drain(&[&frontiers[0], &frontiers[1]], &mut not1_drain) {
+                for (time, mut data) in not1_drain.drain(..) {
+                    consume1(&mut states1, &cap, time, RefOrMut::Mut(&mut data), &mut output_handle);
+                }
+            }
+
+            if let Some(cap) = notificator2.drain(&[&frontiers[2], &frontiers[3]], &mut not2_drain) {
+                for (time, mut data) in not2_drain.drain(..) {
+                    consume2(&mut states2, &cap, time, RefOrMut::Mut(&mut data), &mut output_handle);
+                }
+            }
+
+            // go through each time with data. Folds access the bins of both inputs, the bin without
+            // notifications may have been spilled.
+            let all_frontiers = [&frontiers[0], &frontiers[1], &frontiers[2], &frontiers[3]];
+            for index in 0..::std::cmp::min(states1.bins.len(), states2.bins.len()) {
+                if !is_ready(&states1.bins[index]) || !is_ready(&states2.bins[index]) {
+                    continue;
+                }
+                if let Some(cap) = states1.bins[index].as_mut().unwrap().notificator().drain(&all_frontiers, &mut bin1_drain) {
+                    states1.load(index);
+                    states2.load(index);
+                    let (bin1, bin2) = (states1.bins[index].as_mut().unwrap(), states2.bins[index].as_mut().unwrap());
+                    let side: Vec<_> = side_caps.iter().map(|side| side.delayed(cap.time())).collect();
+                    let notifications = bin1_drain.len();
+                    let start = Instant::now();
+                    fold1(&cap, &side, &mut bin1_drain, bin1, bin2, &mut output_handle, &mut side_handles[..]);
+                    bin1.record_fold(notifications, start.elapsed());
+                }
+                if let Some(cap) = states2.bins[index].as_mut().unwrap().notificator().drain(&all_frontiers, &mut bin2_drain) {
+                    states1.load(index);
+                    states2.load(index);
+                    let (bin1, bin2) = (states1.bins[index].as_mut().unwrap(), states2.bins[index].as_mut().unwrap());
+                    let side: Vec<_> = side_caps.iter().map(|side| side.delayed(cap.time())).collect();
+                    let notifications = bin2_drain.len();
+                    let start = Instant::now();
+                    fold2(&cap, &side, &mut bin2_drain, bin1, bin2, &mut output_handle, &mut side_handles[..]);
+                    bin2.record_fold(notifications, start.elapsed());
+                }
+            }
+
+            if !side_caps.is_empty() {
+                let held = notificator1.capability().into_iter()
+                    .chain(notificator2.capability())
+                    .chain(states1.bins.iter().filter_map(|b| b.as_ref().and_then(|b| b.notificator.capability())))
+                    .chain(states2.bins.iter().filter_map(|b| b.as_ref().and_then(|b| b.notificator.capability())));
+                downgrade_sides(&mut side_caps, &[&frontiers[0], &frontiers[1], &frontiers[2], &frontiers[3]], held);
+            }
+        }
+    });
+    let progress_stream = side_streams.iter()
+        .fold(stream.filter(|_| false).map(|_| ()), |progress, side| progress.concat(&side.filter(|_| false).map(|_| ())));
+    progress_stream.connect_loop(stateful1.feedback);
+    progress_stream.connect_loop(stateful2.feedback);
+    (stream, side_streams)
+}
+
+/// Test if `bin` is present and its state complete.
+fn is_ready<T: Timestamp+TotalOrder, S, D>(bin: &Option<Bin<T, S, D>>) -> bool {
+    bin.as_ref().map_or(false, |bin| !bin.is_held())
+}
+
+/// Downgrade the side outputs' capabilities to the earliest time the operator can still fold
+/// records at: the earliest of the input `frontiers` and the `held` capabilities of notificators.
+/// Drops the capabilities once there is no such time.
+fn downgrade_sides<'a, T: Timestamp+TotalOrder, I: Iterator<Item=&'a Capability<T>>>(side_caps: &mut Vec<Capability<T>>, frontiers: &[&MutableAntichain<T>], held: I) {
+    let earliest = frontiers.iter()
+        .filter_map(|f| f.frontier().iter().min().cloned())
+        .chain(held.map(|cap| cap.time().clone()))
+        .min();
+    match earliest {
+        Some(earliest) => for cap in side_caps.iter_mut() {
+            if cap.time().less_than(&earliest) {
+                cap.downgrade(&earliest);
+            }
+        },
+        None => side_caps.clear(),
+    }
 }
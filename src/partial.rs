@@ -0,0 +1,463 @@
+//! Stateful operators for partially ordered timestamps.
+//!
+//! The operators in this module mirror `Stateful::stateful` and `StatefulOperator::stateful_unary`
+//! for timestamps that are only partially ordered, such as `Product` timestamps in iterative
+//! scopes. A configuration applies to records at times greater or equal to its frontier. It is
+//! installed once every element of the feedback frontier is greater or equal to its frontier, as
+//! only then no records remain that the previous configuration applies to. Each configuration's
+//! frontier must be in advance of the frontier of the configuration it follows, otherwise it is
+//! rejected with `ControlError::UnorderedFrontier`. Per-bin notifications are delivered by a
+//! `PartialOrderFrontierNotificator`.
+//!
+//! Bins move with `Map` and `Move` instructions. Batches that change the assignment of keys to
+//! bins or request checkpoints are rejected. Moved bins are sent at once when their configuration
+//! is installed, encoded with the configured `Codec`. Migration budgets, pre-copying, periodic
+//! checkpoints, restoring, spilling and statistics are not supported: the operator reports each
+//! such setting of its `StatefulConfig` as `ControlError::SettingUnsupported` when it starts, and
+//! otherwise ignores it. It has no acknowledgement or statistics streams. Records are routed like by `Stateful::stateful`. The receiving side accepts the
+//! state updates `stateful` produces, except retractions of pre-copied entries, which require
+//! change tracking. Records for bins held by `StateProtocol::Hold` or `StateProtocol::PreCopy` wait
+//! until the bin is complete.
+
+use std::cell::RefCell;
+use std::hash::Hash;
+use std::rc::Rc;
+
+use fnv::FnvHashMap as HashMap;
+
+use timely::{Data, ExchangeData};
+use timely::dataflow::{Stream, Scope};
+use timely::dataflow::channels::pact::{Exchange, Pipeline};
+use timely::dataflow::channels::pushers::Tee;
+use timely::dataflow::operators::{Capability, ConnectLoop, Feedback, Filter, Map};
+use timely::dataflow::operators::feedback::Handle as FeedbackHandle;
+use timely::dataflow::operators::generic::OutputHandle;
+use timely::dataflow::operators::generic::builder_rc::OperatorBuilder;
+use timely::progress::Timestamp;
+use timely::progress::frontier::Antichain;
+
+use codec::CodecStats;
+use logging::{self, MegaphoneEvent, LOGGER_NAME};
+use migratable::MigratableState;
+use notificator::{Notify, PartialOrderFrontierNotificator};
+use stateful::{configuration_at, route, state_message, StateProtocol};
+use ::{BinId, BinLayout, Control, ControlError, ControlSetBuilder, ControlSet, Key, MigrationBudget, StatefulConfig, WorkerPolicy};
+
+/// A bin with data and a notificator for partially ordered times.
+pub struct PartialBin<T: Timestamp, D, N> {
+    data: D,
+    notificator: PartialOrderFrontierNotificator<T, N>,
+    // The bin's state is still arriving, records need to wait
+    held: bool,
+}
+
+impl<T: Timestamp, D, N> PartialBin<T, D, N> {
+    /// Obtain a mutable reference to the associated state object.
+    pub fn state(&mut self) -> &mut D {
+        &mut self.data
+    }
+
+    /// Obtain a mutable reference to the notificator.
+    pub fn notificator(&mut self) -> &mut PartialOrderFrontierNotificator<T, N> {
+        &mut self.notificator
+    }
+}
+
+impl<T: Timestamp, D: Default, N> Default for PartialBin<T, D, N> {
+    fn default() -> Self {
+        Self {
+            data: Default::default(),
+            notificator: PartialOrderFrontierNotificator::new(),
+            held: false,
+        }
+    }
+}
+
+/// The bins of a worker for partially ordered times.
+pub struct PartialState<T: Timestamp, D, N> {
+    bins: Vec<Option<PartialBin<T, D, N>>>,
+    layout: BinLayout,
+}
+
+impl<T: Timestamp, D: Default, N> PartialState<T, D, N> {
+    /// Get the bin associated with a key. Asserts if the bin is not available.
+    pub fn get(&mut self, key: Key) -> &mut PartialBin<T, D, N> {
+        let bin = self.layout.bin(key);
+        self.bins[bin].as_mut().unwrap_or_else(|| panic!("Accessing bin {} for key {:?}", bin, key))
+    }
+
+    /// Make sure `bin` exists.
+    fn bin(&mut self, bin: usize) -> &mut PartialBin<T, D, N> {
+        while self.bins.len() <= bin {
+            self.bins.push(None);
+        }
+        self.bins[bin].get_or_insert_with(Default::default)
+    }
+}
+
+/// A stateful stream for partially ordered times, see `StateStream`.
+pub struct PartialStateStream<S, V, D, W, M>
+    where
+        S: Scope,
+        V: ExchangeData,
+        D: MigratableState<W>,
+        W: ExchangeData,
+        M: ExchangeData,
+{
+    /// The wrapped stream. The stream provides tuples of the form `(usize, Key, V)`. The first two
+    /// parameters are the target worker and the key identifier. Implementations are encouraged to
+    /// discard the key identifier after the exchange.
+    pub stream: Stream<S, (usize, Key, V)>,
+    /// The state update stream, containing bins moving between workers.
+    pub state_stream: Stream<S, (usize, StateProtocol<S::Timestamp, W, M>)>,
+    /// The shared state of this worker.
+    pub state: Rc<RefCell<PartialState<S::Timestamp, D, M>>>,
+    /// Feedback handle to be connected to the stream of the last operator sharing the state.
+    pub feedback: FeedbackHandle<S, ()>,
+    /// Stream of rejected control batches, by sequence number, and of unsupported settings.
+    pub diagnostics: Stream<S, (u64, ControlError)>,
+}
+
+/// Provides the `stateful_partial` method.
+pub trait StatefulPartial<S: Scope, V: ExchangeData> {
+    /// Route records to the workers owning their keys' bins, moving bins as instructed by
+    /// `control`, for partially ordered times.
+    fn stateful_partial<W, D, B, M>(&self, key: B, control: &Stream<S, Control>, config: &StatefulConfig<S::Timestamp>) -> PartialStateStream<S, V, D, W, M>
+        where
+            S::Timestamp: Hash+Eq,
+            // State format on the wire
+            W: ExchangeData,
+            // per-key state (data)
+            D: MigratableState<W>,
+            // "hash" function for values
+            B: Fn(&V)->u64+'static,
+            M: ExchangeData,
+    ;
+}
+
+/// Test if `layout` assigns keys to bins like `previous`.
+fn same_assignment(previous: &BinLayout, layout: &BinLayout) -> bool {
+    previous.generation() == layout.generation()
+        && previous.bins() == layout.bins()
+        && (0..layout.bins()).all(|bin| previous.prefix(bin) == layout.prefix(bin))
+}
+
+impl<S: Scope, V: ExchangeData> StatefulPartial<S, V> for Stream<S, V> {
+    fn stateful_partial<W, D, B, M>(&self, key: B, control: &Stream<S, Control>, config: &StatefulConfig<S::Timestamp>) -> PartialStateStream<S, V, D, W, M>
+        where
+            S::Timestamp: Hash+Eq,
+            W: ExchangeData,
+            D: MigratableState<W>,
+            B: Fn(&V)->u64+'static,
+            M: ExchangeData,
+    {
+        let index = self.scope().index();
+        let peers = self.scope().peers();
+        let worker_policy = config.worker_policy();
+        let chunk_size = config.chunk_size();
+        let codec = config.codec();
+
+        let mut active_configuration = ControlSet::initial(config, peers);
+
+        // Settings this operator does not support, reported once it runs
+        let mut unsupported = Vec::new();
+        if config.migration_budget() != MigrationBudget::Unlimited {
+            unsupported.push("migration budget");
+        }
+        if config.pre_copy() {
+            unsupported.push("pre-copy");
+        }
+        if config.checkpoint_interval().is_some() {
+            unsupported.push("checkpoint interval");
+        }
+        if config.restore().is_some() {
+            unsupported.push("restore");
+        }
+        if config.spill().is_some() {
+            unsupported.push("spill");
+        }
+        if config.stats_interval().is_some() {
+            unsupported.push("statistics interval");
+        }
+
+        let bins = (0..active_configuration.layout().bins()).map(|bin| {
+            if active_configuration.map()[bin] == index { Some(Default::default()) } else { None }
+        }).collect();
+        let states = Rc::new(RefCell::new(PartialState { bins, layout: active_configuration.layout().clone() }));
+        let states_f = Rc::clone(&states);
+
+        let logger = self.scope().log_register().get::<MegaphoneEvent<S::Timestamp>>(LOGGER_NAME);
+
+        let mut builder = OperatorBuilder::new("StateMachine F partial".into(), self.scope());
+
+        let mut data_in = builder.new_input(self, Pipeline);
+        let mut control_in = builder.new_input(control, Pipeline);
+        let (mut data_out, stream) = builder.new_output();
+        let (mut state_out, state) = builder.new_output_connection(vec![Antichain::new(), Antichain::from_elem(Default::default())]);
+        let (mut diagnostics_out, diagnostics) = builder.new_output_connection(vec![Antichain::new(), Antichain::from_elem(Default::default())]);
+
+        let (feedback_handle, feedback_stream) = self.scope().feedback(Default::default());
+        let _feedback_in = builder.new_input_connection(&feedback_stream, Pipeline, vec![Antichain::new(); 3]);
+
+        builder.build(move |capability| {
+
+            // Capability to report unsupported settings
+            let mut unsupported_cap = if unsupported.is_empty() { None } else { Some(capability[2].clone()) };
+
+            let mut data_notificator = PartialOrderFrontierNotificator::new();
+            let mut control_notificator = PartialOrderFrontierNotificator::new();
+
+            let mut data_stash: HashMap<S::Timestamp, Vec<Vec<V>>> = Default::default();
+
+            // Configurations waiting to be installed, sorted by sequence number, with the
+            // capability to send their state
+            let mut pending_configurations: Vec<(Capability<S::Timestamp>, ControlSet<S::Timestamp>)> = Vec::new();
+            // Control set builders and capabilities to report diagnostics, by time
+            let mut pending_configuration_data: HashMap<S::Timestamp, (ControlSetBuilder<S::Timestamp>, Capability<S::Timestamp>)> = Default::default();
+
+            let mut control_data_buffer = Vec::new();
+            let mut data_buffer = Vec::new();
+
+            move |frontiers| {
+                let mut data_out = data_out.activate();
+                let mut state_out = state_out.activate();
+                let mut diagnostics_out = diagnostics_out.activate();
+
+                if let Some(cap) = unsupported_cap.take() {
+                    let mut session = diagnostics_out.session(&cap);
+                    for setting in unsupported.drain(..) {
+                        session.give((active_configuration.sequence, ControlError::SettingUnsupported(setting.to_string())));
+                    }
+                }
+
+                control_in.for_each(|time, data| {
+                    data.swap(&mut control_data_buffer);
+                    let builder = &mut pending_configuration_data.entry(time.time().clone()).or_insert_with(|| {
+                        let mut builder: ControlSetBuilder<S::Timestamp> = Default::default();
+                        builder.frontier(vec![time.time().clone()].into_iter());
+                        (builder, time.retain_for_output(2))
+                    }).0;
+                    for update in control_data_buffer.drain(..) {
+                        // Errors are retained by the builder and reported once it is built
+                        let _ = builder.apply(update);
+                    }
+                    control_notificator.notify_at(&time.retain_for_output(1));
+                });
+
+                control_notificator.for_each(&[&frontiers[1]], |cap, time, _not| {
+                    if let Some((builder, diagnostics_cap)) = pending_configuration_data.remove(&time) {
+                        let sequence = builder.sequence().unwrap_or(0);
+                        let previous = pending_configurations.last().map_or(&active_configuration, |pending| &pending.1);
+                        let clamped = if worker_policy == WorkerPolicy::Clamp { builder.workers_out_of_range(peers) } else { Vec::new() };
+                        let result = builder.build(previous, peers, worker_policy).and_then(|config| {
+                            if !same_assignment(previous.layout(), config.layout()) {
+                                Err(ControlError::RekeyUnsupported)
+                            } else if config.checkpoint {
+                                Err(ControlError::CheckpointUnsupported)
+                            } else if !previous.frontier.dominates(&config.frontier) {
+                                Err(ControlError::UnorderedFrontier)
+                            } else {
+                                Ok(config)
+                            }
+                        });
+                        match result {
+                            Ok(config) => {
+                                for worker in clamped {
+                                    logging::log(&logger, MegaphoneEvent::WorkerClamped { time: time.clone(), sequence, worker, peers });
+                                }
+                                pending_configurations.push((cap.clone(), config));
+                                pending_configurations.sort_by_key(|pending| pending.1.sequence);
+                            },
+                            Err(error) => diagnostics_out.session(&diagnostics_cap).give((sequence, error)),
+                        }
+                    }
+                });
+
+                // Install configurations once no records remain the previous one applies to
+                while pending_configurations.first().map_or(false, |pending| frontiers[2].frontier().iter().all(|time| pending.1.frontier.less_equal(time))) {
+                    let (cap, new) = pending_configurations.remove(0);
+                    {
+                        let old = &active_configuration;
+                        let mut states = states_f.borrow_mut();
+                        let mut session = state_out.session(&cap);
+                        let mut codec_stats = CodecStats::default();
+                        for bin in old.layout().active() {
+                            if old.map()[bin] == index && new.map()[bin] != index {
+                                let target = new.map()[bin];
+                                let PartialBin { data, notificator, .. } = states.bins[bin].take().expect("Instructed to move bin but it is None");
+                                session.give((target, StateProtocol::Prepare(BinId(bin))));
+                                let mut chunk = Vec::new();
+                                let mut bytes = 0;
+                                for item in data.snapshot() {
+                                    bytes += ::abomonation::measure(&item);
+                                    chunk.push(item);
+                                    if bytes >= chunk_size {
+                                        session.give((target, state_message(bin, ::std::mem::replace(&mut chunk, Vec::new()), codec, false, &mut codec_stats)));
+                                        bytes = 0;
+                                    }
+                                }
+                                if !chunk.is_empty() {
+                                    session.give((target, state_message(bin, chunk, codec, false, &mut codec_stats)));
+                                }
+                                for (time, data) in notificator.pending() {
+                                    session.give((target, StateProtocol::Pending(BinId(bin), time, data)));
+                                }
+                                session.give((target, StateProtocol::Complete(BinId(bin))));
+                            }
+                        }
+                    }
+                    active_configuration = new;
+                }
+
+                // Route records, or stash them while configurations for their time may be missing
+                data_in.for_each(|time, data| {
+                    data.swap(&mut data_buffer);
+                    if frontiers[1].less_equal(time.time()) {
+                        data_stash.entry(time.time().clone()).or_insert_with(Vec::new).push(::std::mem::replace(&mut data_buffer, Vec::new()));
+                        data_notificator.notify_at(&time.retain_for_output(0));
+                    } else {
+                        let control_set = configuration_at(pending_configurations.iter().map(|c| &c.1), &active_configuration, time.time());
+                        data_out.session(&time).give_iterator(route(control_set, &key, data_buffer.drain(..), None));
+                    }
+                });
+
+                data_notificator.for_each(&[&frontiers[0], &frontiers[1]], |cap, time, _not| {
+                    if let Some(stash) = data_stash.remove(&time) {
+                        let control_set = configuration_at(pending_configurations.iter().map(|c| &c.1), &active_configuration, &time);
+                        let mut session = data_out.session(cap);
+                        for mut data in stash {
+                            session.give_iterator(route(control_set, &key, data.drain(..), None));
+                        }
+                    }
+                });
+            }
+        });
+
+        PartialStateStream { stream, state_stream: state, state: states, feedback: feedback_handle, diagnostics }
+    }
+}
+
+/// Apply state updates for partially ordered times, see `stateful::apply_state_updates`.
+/// Notifications are requested with `cap`.
+fn apply_partial_updates<T, D, W, M, I>(states: &mut PartialState<T, D, M>, cap: &Capability<T>, data: I)
+    where
+        T: Timestamp,
+        D: MigratableState<W>,
+        W: ExchangeData,
+        I: Iterator<Item=(usize, StateProtocol<T, W, M>)>,
+{
+    for (_target, state) in data {
+        match state {
+            StateProtocol::Prepare(bin) => { states.bin(*bin); },
+            // All state from one source was applied
+            StateProtocol::Complete(bin) => states.bin(*bin).held = false,
+            StateProtocol::State(bin, s) => states.bin(*bin).data.restore(s),
+            StateProtocol::Encoded(bin, codec, bytes) => states.bin(*bin).data.restore(codec.decode(&bytes)),
+            StateProtocol::Pending(bin, t, data) => states.bin(*bin).notificator.notify_at_data(cap, t, data),
+            // State is sent in the background, records need to wait
+            StateProtocol::Hold(bin) => states.bin(*bin).held = true,
+            // Stage a snapshot, the bin becomes active once complete
+            StateProtocol::PreCopy(bin, s) => {
+                let bin = states.bin(*bin);
+                bin.held = true;
+                bin.data.restore(s);
+            },
+            // Nothing changed since the snapshot, otherwise entries cannot be removed without
+            // change tracking
+            StateProtocol::Retract(_, ref s) if s.is_empty() => {},
+            StateProtocol::Retract(..) => panic!("Retracting state requires change tracking"),
+        }
+    }
+}
+
+/// Provides the `stateful_unary_partial` method.
+pub trait StatefulPartialOperator<G: Scope, D1: ExchangeData> {
+    /// Stateful operator with a single input for partially ordered times, see
+    /// `StatefulOperator::stateful_unary`.
+    ///
+    /// `fold` receives the available notifications of a bin, in an order that respects the partial
+    /// order, together with a capability for the first notification's time.
+    fn stateful_unary_partial<
+        D2: Data,                                    // output type
+        B: Fn(&D1)->u64+'static,
+        S: MigratableState<W>+'static,
+        W: ExchangeData,                            // State format on the wire
+        F: FnMut(&Capability<G::Timestamp>,
+            &mut Vec<(G::Timestamp, D1)>,
+            &mut PartialBin<G::Timestamp, S, D1>,
+            &mut OutputHandle<G::Timestamp, D2, Tee<G::Timestamp, D2>>) + 'static,    // state update logic
+    >(&self, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, key: B, name: &str, fold: F) -> Stream<G, D2>
+    ;
+}
+
+impl<G: Scope, D1: ExchangeData> StatefulPartialOperator<G, D1> for Stream<G, D1> {
+    fn stateful_unary_partial<
+        D2: Data,                                    // output type
+        B: Fn(&D1)->u64+'static,
+        S: MigratableState<W>+'static,
+        W: ExchangeData,                            // State format on the wire
+        F: FnMut(&Capability<G::Timestamp>,
+            &mut Vec<(G::Timestamp, D1)>,
+            &mut PartialBin<G::Timestamp, S, D1>,
+            &mut OutputHandle<G::Timestamp, D2, Tee<G::Timestamp, D2>>) + 'static,    // state update logic
+    >(&self, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, key: B, name: &str, mut fold: F) -> Stream<G, D2>
+    {
+        let stateful = self.stateful_partial(key, control, config);
+        let states = stateful.state.clone();
+
+        let mut builder = OperatorBuilder::new(name.to_owned(), self.scope());
+
+        let mut input = builder.new_input(&stateful.stream, Exchange::new(move |&(target, _key, _)| target as u64));
+        let mut input_state = builder.new_input(&stateful.state_stream, Exchange::new(move |&(target, _)| target as u64));
+
+        let (mut output, stream) = builder.new_output();
+
+        let mut state_update_buffer = vec![];
+        let mut notificator = PartialOrderFrontierNotificator::new();
+
+        let mut not_drain = Vec::new();
+        let mut bin_drain = Vec::new();
+        let mut batches = Vec::new();
+
+        builder.build(move |_capability| {
+            move |frontiers| {
+                let mut output_handle = output.activate();
+
+                let mut states = states.borrow_mut();
+                while let Some((time, data)) = input_state.next() {
+                    data.swap(&mut state_update_buffer);
+                    apply_partial_updates(&mut states, &time.retain(), state_update_buffer.drain(..));
+                }
+                // stash each input and request a notification when ready
+                while let Some((time, data)) = input.next() {
+                    let mut data_buffer = vec![];
+                    data.swap(&mut data_buffer);
+                    let cap = time.retain();
+                    notificator.notify_at_data(&cap, cap.time().clone(), data_buffer);
+                }
+
+                while let Some(cap) = notificator.drain(&[&frontiers[0], &frontiers[1]], &mut not_drain) {
+                    for (time, mut keyed_data) in not_drain.drain(..) {
+                        for (_, key_id, d) in keyed_data.drain(..) {
+                            states.get(key_id).notificator.notify_at_data(&cap, time.clone(), d);
+                        }
+                    }
+                }
+
+                // Drain each bin before folding, notifications requested by `fold` wait for the
+                // next activation. Held bins wait for their state.
+                for bin in states.bins.iter_mut().filter_map(|bin| bin.as_mut()).filter(|bin| !bin.held) {
+                    while let Some(cap) = bin.notificator.drain(&[&frontiers[0], &frontiers[1]], &mut bin_drain) {
+                        batches.push((cap, ::std::mem::replace(&mut bin_drain, Vec::new())));
+                    }
+                    for (cap, mut batch) in batches.drain(..) {
+                        fold(&cap, &mut batch, bin, &mut output_handle);
+                    }
+                }
+            }
+        });
+        let progress_stream = stream.filter(|_| false).map(|_| ());
+        progress_stream.connect_loop(stateful.feedback);
+        stream
+    }
+}
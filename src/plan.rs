@@ -0,0 +1,237 @@
+//! Replaying migration plans as control streams.
+//!
+//! A [`Plan`] is a list of steps, each of which is a batch of control instructions to apply at a
+//! time. Plans are read from a line-based format. Version 1 starts with the line `version 1`,
+//! followed by one step per line; empty lines and lines starting with `#` are ignored:
+//!
+//! ```text
+//! version 1
+//! # At time 1000, install a map assigning bin `i` to the `i`th worker listed
+//! M 1000 0 1 0 1
+//! # At time 2000, move bin 0 to worker 1, and bin 3 to worker 0
+//! D 2000 0 1 3 0
+//! # At time 3000, migrate to a target map, following a named `MigrationPattern`
+//! P 3000 batched-fluid 0 0 1 1
+//! ```
+//!
+//! Files without a version line are read as version 0, which only knows `M` and `D` lines and
+//! applies each step at its time. Empty lines are ignored in both versions. Pattern names are
+//! `sudden`, `fluid`, `batched-fluid` and `optimized`, a pattern results in one step per batch. In
+//! version 1, each step happens after the previous one: steps at or before the previous step's time
+//! are delayed to the time after it.
+//!
+//! [`ReplayPlan::replay_plan`] turns a plan into a stream of `Control` messages.
+//!
+//! [`Plan`]: struct.Plan.html
+//! [`ReplayPlan::replay_plan`]: trait.ReplayPlan.html#tymethod.replay_plan
+
+use std::io::BufRead;
+use std::path::Path;
+
+use timely::Data;
+use timely::dataflow::{Stream, Scope};
+use timely::dataflow::channels::pact::Pipeline;
+use timely::dataflow::operators::generic::operator::Operator;
+use timely::order::TotalOrder;
+
+use planner::{self, MigrationPattern};
+use ::{BinId, Control, ControlInst};
+
+/// Errors encountered while reading a plan.
+#[derive(Debug)]
+pub enum PlanError {
+    /// Reading the plan failed.
+    Io(::std::io::Error),
+    /// The plan has a version this library does not understand.
+    UnsupportedVersion(String),
+    /// A line of the plan is malformed.
+    Malformed(/*line*/ usize, String),
+}
+
+impl ::std::fmt::Display for PlanError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+            PlanError::Io(ref error) => write!(f, "failed to read plan: {}", error),
+            PlanError::UnsupportedVersion(ref version) => write!(f, "unsupported plan version: {}", version),
+            PlanError::Malformed(line, ref message) => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl ::std::error::Error for PlanError {}
+
+impl From<::std::io::Error> for PlanError {
+    fn from(error: ::std::io::Error) -> Self {
+        PlanError::Io(error)
+    }
+}
+
+/// A sequence of configurations, each applied at a time.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Plan {
+    steps: Vec<(u64, Vec<ControlInst>)>,
+}
+
+impl Plan {
+    /// Construct an empty plan.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Append a step applying `instructions` at `time`, or after the previous step. Steps without
+    /// instructions are ignored.
+    pub fn push(&mut self, time: u64, instructions: Vec<ControlInst>) {
+        if instructions.is_empty() {
+            return;
+        }
+        let time = self.steps.last().map_or(time, |&(last, _)| ::std::cmp::max(time, last + 1));
+        self.steps.push((time, instructions));
+    }
+
+    /// Append a step applying `instructions` at `time`, as version 0 plans do. Steps without
+    /// instructions are ignored.
+    fn push_at(&mut self, time: u64, instructions: Vec<ControlInst>) {
+        if !instructions.is_empty() {
+            self.steps.push((time, instructions));
+        }
+    }
+
+    /// The steps of the plan as `(time, instructions)`, in the order they were added.
+    pub fn steps(&self) -> &[(u64, Vec<ControlInst>)] {
+        &self.steps[..]
+    }
+
+    /// Read a plan from the file at `path`, see `parse`.
+    pub fn read<P: AsRef<Path>>(path: P, bins: usize, peers: usize) -> Result<Self, PlanError> {
+        let file = ::std::fs::File::open(path)?;
+        Self::parse(::std::io::BufReader::new(file), bins, peers)
+    }
+
+    /// Parse a plan. Patterns start from the map the previous steps result in, or the default
+    /// map for `bins` and `peers` at the start.
+    pub fn parse<R: BufRead>(reader: R, bins: usize, peers: usize) -> Result<Self, PlanError> {
+        let mut plan = Plan::new();
+        let mut map = planner::uniform(bins, peers);
+        let mut version = None;
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            let number = number + 1;
+            let malformed = |message: &str| PlanError::Malformed(number, message.to_string());
+            let line = line.trim();
+            if line.is_empty() || (version == Some(1) && line.starts_with('#')) {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let indicator = parts.next().ok_or_else(|| malformed("missing indicator"))?;
+            if version.is_none() {
+                if indicator == "version" {
+                    match parts.next() {
+                        Some("1") => version = Some(1),
+                        other => return Err(PlanError::UnsupportedVersion(other.unwrap_or("").to_string())),
+                    }
+                    continue;
+                }
+                version = Some(0);
+            }
+            let time: u64 = parts.next().ok_or_else(|| malformed("missing time"))?
+                .parse().map_err(|_| malformed("invalid time"))?;
+            match indicator {
+                "M" => {
+                    let target = parse_numbers(parts).map_err(|_| malformed("invalid worker"))?;
+                    if target.len() != map.len() {
+                        return Err(malformed("map does not have one worker per bin"));
+                    }
+                    map = target.clone();
+                    if version == Some(1) {
+                        plan.push(time, vec![ControlInst::Map(target)]);
+                    } else {
+                        plan.push_at(time, vec![ControlInst::Map(target)]);
+                    }
+                },
+                "D" => {
+                    let diff = parse_numbers(parts).map_err(|_| malformed("invalid bin or worker"))?;
+                    if diff.len() % 2 != 0 {
+                        return Err(malformed("diff does not consist of bin and worker pairs"));
+                    }
+                    let mut instructions = Vec::new();
+                    for pair in diff.chunks(2) {
+                        *map.get_mut(pair[0]).ok_or_else(|| malformed("bin out of range"))? = pair[1];
+                        instructions.push(ControlInst::Move(BinId(pair[0]), pair[1]));
+                    }
+                    if version == Some(1) {
+                        plan.push(time, instructions);
+                    } else {
+                        plan.push_at(time, instructions);
+                    }
+                },
+                "P" if version == Some(1) => {
+                    let pattern: MigrationPattern = parts.next().ok_or_else(|| malformed("missing pattern"))?
+                        .parse().map_err(|error: String| malformed(&error))?;
+                    let target = parse_numbers(parts).map_err(|_| malformed("invalid worker"))?;
+                    if target.len() != map.len() {
+                        return Err(malformed("map does not have one worker per bin"));
+                    }
+                    for batch in pattern.plan(&map, &target) {
+                        plan.push(time, batch);
+                    }
+                    map = target;
+                },
+                _ => return Err(malformed("unknown indicator")),
+            }
+        }
+        Ok(plan)
+    }
+}
+
+fn parse_numbers<'a, I: Iterator<Item=&'a str>>(parts: I) -> Result<Vec<usize>, ::std::num::ParseIntError> {
+    parts.map(|part| part.parse()).collect()
+}
+
+/// Provides the `replay_plan` method.
+pub trait ReplayPlan<G: Scope> {
+    /// Replay `plan` as a control stream for stateful operators.
+    ///
+    /// Each step becomes a batch of `Control` messages at the time `time` maps the step's time to,
+    /// with sequence numbers counting up from zero. The control frontier follows the frontier of
+    /// the stream the method is called on, whose data is discarded. Every worker emits the whole
+    /// plan, the result can be connected to the operators' control inputs directly.
+    fn replay_plan<F: Fn(u64)->G::Timestamp+'static>(&self, plan: &Plan, time: F) -> Stream<G, Control>;
+}
+
+impl<G: Scope, D: Data> ReplayPlan<G> for Stream<G, D>
+    where
+        G::Timestamp: TotalOrder,
+{
+    fn replay_plan<F: Fn(u64)->G::Timestamp+'static>(&self, plan: &Plan, time: F) -> Stream<G, Control> {
+        let mut steps = Some(plan.steps.clone());
+        self.unary_frontier(Pipeline, "ReplayPlan", move |cap, _info| {
+            let mut cap = Some(cap);
+            move |input, output| {
+                input.for_each(|_time, _data| {});
+
+                // Emit the whole plan once, before the capability advances
+                if let Some(steps) = steps.take() {
+                    let cap = cap.as_ref().expect("Replaying plan without capability");
+                    for (sequence, (step_time, instructions)) in steps.into_iter().enumerate() {
+                        let count = instructions.len();
+                        let mut session = output.session(&cap.delayed(&time(step_time)));
+                        for instruction in instructions {
+                            session.give(Control::new(sequence as u64, count, instruction));
+                        }
+                    }
+                }
+
+                // Follow the reference frontier
+                let frontier = input.frontier().frontier().iter().min().cloned();
+                match frontier {
+                    Some(frontier) => if let Some(ref mut cap) = cap {
+                        if cap.time().less_than(&frontier) {
+                            cap.downgrade(&frontier);
+                        }
+                    },
+                    None => cap = None,
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,224 @@
+//! Planning migrations between assignments of bins to workers.
+//!
+//! The module provides generators for initial maps and [`MigrationPattern`]s, which turn a current
+//! and a target map into a sequence of batches of control instructions. Each batch is meant to be
+//! sent as one configuration.
+//!
+//! [`MigrationPattern`]: enum.MigrationPattern.html
+
+use std::collections::{BTreeMap, VecDeque};
+
+use ::{BinId, ControlInst};
+
+/// Assign bin `i` to worker `i % peers`.
+pub fn uniform(bins: usize, peers: usize) -> Vec<usize> {
+    (0..bins).map(|bin| bin % peers).collect()
+}
+
+/// Assign pairs of bins to the first half of the workers, placing the second bin of each pair
+/// half the workers apart.
+pub fn half(bins: usize, peers: usize) -> Vec<usize> {
+    (0..bins).map(|bin| (bin / 2 * 2 + bin % 2 * peers / 2) % peers).collect()
+}
+
+/// Assign the first half of the bins like `half`, and the second half like `uniform`.
+pub fn uniform_skew(bins: usize, peers: usize) -> Vec<usize> {
+    let mut map = half(bins, peers);
+    map.truncate(bins / 2);
+    map.extend((bins / 2..bins).map(|bin| bin % peers));
+    map
+}
+
+/// Strategies to move bins from a current to a target map.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MigrationPattern {
+    /// Install the target map at once.
+    Sudden,
+    /// Move one bin at a time.
+    Fluid,
+    /// Move bins in batches, each of which is a maximum matching of source to target workers, i.e.,
+    /// each worker sends and receives at most one bin per batch.
+    BatchedFluid,
+    /// Like `BatchedFluid`, but choose matchings such that the number of batches is minimal, i.e.,
+    /// the largest number of bins a worker sends or receives.
+    OptimizedMatching,
+}
+
+impl MigrationPattern {
+    /// Plan the batches of instructions moving from `current` to `target`.
+    pub fn plan(self, current: &[usize], target: &[usize]) -> Vec<Vec<ControlInst>> {
+        assert_eq!(current.len(), target.len(), "Maps differ in length");
+        match self {
+            MigrationPattern::Sudden => vec![vec![ControlInst::Map(target.to_vec())]],
+            MigrationPattern::Fluid => {
+                moves(current, target).map(|(bin, _, dst)| vec![ControlInst::Move(BinId(bin), dst)]).collect()
+            },
+            MigrationPattern::BatchedFluid => batched_fluid(current, target),
+            MigrationPattern::OptimizedMatching => optimized_matching(current, target),
+        }
+    }
+}
+
+impl ::std::str::FromStr for MigrationPattern {
+    type Err = String;
+
+    /// Parse `sudden`, `fluid`, `batched-fluid` or `optimized`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sudden" => Ok(MigrationPattern::Sudden),
+            "fluid" => Ok(MigrationPattern::Fluid),
+            "batched-fluid" => Ok(MigrationPattern::BatchedFluid),
+            "optimized" => Ok(MigrationPattern::OptimizedMatching),
+            _ => Err(format!("unknown migration pattern: {}", s)),
+        }
+    }
+}
+
+/// The bins that move as `(bin, source, destination)`.
+fn moves<'a>(current: &'a [usize], target: &'a [usize]) -> impl Iterator<Item=(usize, usize, usize)> + 'a {
+    current.iter().zip(target.iter()).enumerate()
+        .filter(|&(_, (src, dst))| src != dst)
+        .map(|(bin, (src, dst))| (bin, *src, *dst))
+}
+
+/// The bins moving between each pair of workers, and the number of workers.
+fn labels(current: &[usize], target: &[usize]) -> (BTreeMap<(usize, usize), Vec<usize>>, usize) {
+    let mut labels: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for (bin, src, dst) in moves(current, target) {
+        labels.entry((src, dst)).or_insert_with(Vec::new).push(bin);
+    }
+    let workers = current.iter().chain(target.iter()).max().map_or(0, |max| max + 1);
+    (labels, workers)
+}
+
+fn batched_fluid(current: &[usize], target: &[usize]) -> Vec<Vec<ControlInst>> {
+    let (mut labels, workers) = labels(current, target);
+    let mut batches = Vec::new();
+    while !labels.is_empty() {
+        let mut adjacency = vec![Vec::new(); workers];
+        for &(src, dst) in labels.keys() {
+            adjacency[src].push(dst);
+        }
+        let mut batch = Vec::new();
+        for (src, dst) in maximum_matching(&adjacency, workers).into_iter().enumerate() {
+            if let Some(dst) = dst {
+                let bins = labels.get_mut(&(src, dst)).expect("Matched a worker pair without bins");
+                batch.push(ControlInst::Move(BinId(bins.pop().expect("Empty worker pair")), dst));
+                if bins.is_empty() {
+                    labels.remove(&(src, dst));
+                }
+            }
+        }
+        batches.push(batch);
+    }
+    batches
+}
+
+fn optimized_matching(current: &[usize], target: &[usize]) -> Vec<Vec<ControlInst>> {
+    let (mut labels, workers) = labels(current, target);
+    let mut sent = vec![0; workers];
+    let mut received = vec![0; workers];
+    for (&(src, dst), bins) in labels.iter() {
+        sent[src] += bins.len();
+        received[dst] += bins.len();
+    }
+    let degree = sent.iter().chain(received.iter()).cloned().max().unwrap_or(0);
+
+    // Pad the migration graph with dummy moves until each worker sends and receives `degree`
+    // bins. A regular bipartite graph has a perfect matching, removing it leaves a regular graph.
+    let mut dummies = vec![vec![0; workers]; workers];
+    for src in 0..workers {
+        for dst in 0..workers {
+            while sent[src] < degree && received[dst] < degree {
+                dummies[src][dst] += 1;
+                sent[src] += 1;
+                received[dst] += 1;
+            }
+        }
+    }
+
+    let mut batches = Vec::new();
+    for _ in 0..degree {
+        let mut adjacency = vec![Vec::new(); workers];
+        for src in 0..workers {
+            for dst in 0..workers {
+                if dummies[src][dst] > 0 || labels.contains_key(&(src, dst)) {
+                    adjacency[src].push(dst);
+                }
+            }
+        }
+        let mut batch = Vec::new();
+        for (src, dst) in maximum_matching(&adjacency, workers).into_iter().enumerate() {
+            let dst = dst.expect("Regular migration graph without perfect matching");
+            if let Some(bin) = labels.get_mut(&(src, dst)).and_then(|bins| bins.pop()) {
+                batch.push(ControlInst::Move(BinId(bin), dst));
+                if labels[&(src, dst)].is_empty() {
+                    labels.remove(&(src, dst));
+                }
+            } else {
+                dummies[src][dst] -= 1;
+            }
+        }
+        if !batch.is_empty() {
+            batches.push(batch);
+        }
+    }
+    debug_assert!(labels.is_empty());
+    batches
+}
+
+/// Compute a maximum matching of a bipartite graph with the Hopcroft-Karp algorithm. `adjacency`
+/// lists the right vertices of each left vertex. Returns the partner of each left vertex.
+fn maximum_matching(adjacency: &[Vec<usize>], right: usize) -> Vec<Option<usize>> {
+    let mut match_left = vec![None; adjacency.len()];
+    let mut match_right = vec![None; right];
+    loop {
+        // Layer the graph by the length of alternating paths from free left vertices
+        let mut layers = vec![usize::max_value(); adjacency.len()];
+        let mut queue = VecDeque::new();
+        for left in 0..adjacency.len() {
+            if match_left[left].is_none() {
+                layers[left] = 0;
+                queue.push_back(left);
+            }
+        }
+        let mut found = false;
+        while let Some(left) = queue.pop_front() {
+            for &right in &adjacency[left] {
+                match match_right[right] {
+                    None => found = true,
+                    Some(next) => if layers[next] == usize::max_value() {
+                        layers[next] = layers[left] + 1;
+                        queue.push_back(next);
+                    },
+                }
+            }
+        }
+        if !found {
+            return match_left;
+        }
+        for left in 0..adjacency.len() {
+            if match_left[left].is_none() {
+                augment(left, adjacency, &mut layers, &mut match_left, &mut match_right);
+            }
+        }
+    }
+}
+
+/// Find an augmenting path from `left` along the layers, and flip it.
+fn augment(left: usize, adjacency: &[Vec<usize>], layers: &mut [usize], match_left: &mut [Option<usize>], match_right: &mut [Option<usize>]) -> bool {
+    for &right in &adjacency[left] {
+        let augmented = match match_right[right] {
+            None => true,
+            Some(next) => layers[next] == layers[left] + 1 && augment(next, adjacency, layers, match_left, match_right),
+        };
+        if augmented {
+            match_left[left] = Some(right);
+            match_right[right] = Some(left);
+            return true;
+        }
+    }
+    // No path from `left` in this phase
+    layers[left] = usize::max_value();
+    false
+}
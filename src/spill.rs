@@ -0,0 +1,152 @@
+//! Spilling cold bins to disk.
+//!
+//! A stateful operator configured with `StatefulConfig::with_spill` keeps the estimated size of
+//! its bins' state within a memory budget. Once per completed time, it writes the least recently
+//! used bins without pending notifications to a file, encoded like migrated state. A spilled bin
+//! is read back and its file removed when a record for it is processed, or when it is migrated.
+//! Checkpoints copy the items of spilled bins from their files. Failures to spill bins are logged
+//! as `MegaphoneEvent::SpillFailed`, the bins stay in memory.
+//!
+//! The size of a bin is `MigratableState::size_hint_bytes`, or, if the state does not provide a
+//! hint, the measured size of its items. Measuring requires a snapshot of the state, states kept
+//! in spilling operators should provide a hint.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use timely::ExchangeData;
+use timely::order::TotalOrder;
+use timely::progress::Timestamp;
+
+use checkpoint::{read_file, write_file};
+use codec::{Codec, CodecStats};
+use migratable::MigratableState;
+use ::Bin;
+
+/// Storage for the data of cold bins.
+pub(crate) struct Spill<D> {
+    directory: PathBuf,
+    // Bytes of state to keep in memory
+    budget: usize,
+    codec: Codec,
+    // Logical time of the last access, by bin
+    clock: u64,
+    accessed: Vec<u64>,
+    write: fn(&Path, D, Codec) -> Result<(), (D, io::Error)>,
+    read: fn(&Path, Codec) -> io::Result<D>,
+    measure: fn(&mut D) -> usize,
+}
+
+impl<D: Default> Spill<D> {
+    /// Spill bins to `directory`, keeping about `budget` bytes of state in memory.
+    pub(crate) fn new<W: ExchangeData>(directory: PathBuf, budget: usize, codec: Codec) -> Self where D: MigratableState<W> {
+        Self {
+            directory,
+            budget,
+            codec,
+            clock: 0,
+            accessed: Vec::new(),
+            write: write_data::<D, W>,
+            read: read_data::<D, W>,
+            measure: measure_data::<D, W>,
+        }
+    }
+
+    fn path(&self, bin: usize) -> PathBuf {
+        self.directory.join(format!("bin-{}", bin))
+    }
+
+    /// The items of spilled bin `index`, encoded with `codec`. The bin stays spilled.
+    pub(crate) fn encoded<W: ExchangeData>(&self, index: usize, codec: Codec, stats: &mut CodecStats) -> io::Result<Vec<u8>> {
+        let bytes = read_file(&self.path(index))?;
+        if codec == self.codec {
+            Ok(bytes)
+        } else {
+            Ok(codec.encode(&self.codec.decode::<W>(&bytes), stats))
+        }
+    }
+
+    /// Mark bin `index` as used, reading its data if it was spilled.
+    pub(crate) fn access<T: Timestamp+TotalOrder, N>(&mut self, index: usize, bin: &mut Bin<T, D, N>) {
+        if index >= self.accessed.len() {
+            self.accessed.resize(index + 1, 0);
+        }
+        self.clock += 1;
+        self.accessed[index] = self.clock;
+        if bin.spilled {
+            let path = self.path(index);
+            bin.data = (self.read)(&path, self.codec).expect("Failed to read spilled bin");
+            bin.spilled = false;
+            // The bin may move to another worker, evicting it again writes a new file
+            ::std::fs::remove_file(&path).expect("Failed to remove spilled bin");
+        }
+    }
+
+    /// Spill the least recently used bins until the state in memory fits the budget. Bins that
+    /// are held, have pending notifications, or are listed in `exclude` stay in memory.
+    pub(crate) fn evict<T: Timestamp+TotalOrder, N>(&mut self, bins: &mut [Option<Bin<T, D, N>>], exclude: &[usize]) -> io::Result<()> {
+        if self.accessed.len() < bins.len() {
+            self.accessed.resize(bins.len(), 0);
+        }
+        let mut total = 0;
+        let mut candidates = Vec::new();
+        for (index, bin) in bins.iter_mut().enumerate() {
+            if let Some(ref mut bin) = *bin {
+                if !bin.spilled {
+                    let size = (self.measure)(&mut bin.data);
+                    total += size;
+                    if !bin.held && bin.notificator.iter_pending().next().is_none() && !exclude.contains(&index) {
+                        candidates.push((self.accessed[index], index, size));
+                    }
+                }
+            }
+        }
+        // Least recently used first
+        candidates.sort();
+        for (_, index, size) in candidates {
+            if total <= self.budget {
+                break;
+            }
+            let bin = bins[index].as_mut().expect("Spilling bin but it is None");
+            let data = ::std::mem::replace(&mut bin.data, Default::default());
+            if let Err((data, error)) = (self.write)(&self.path(index), data, self.codec) {
+                bin.data = data;
+                return Err(error);
+            }
+            bin.spilled = true;
+            total -= size;
+        }
+        Ok(())
+    }
+}
+
+/// Write the items of `data` to `path`, returning `data` if writing fails.
+fn write_data<D: MigratableState<W>, W: ExchangeData>(path: &Path, data: D, codec: Codec) -> Result<(), (D, io::Error)> {
+    let items: Vec<W> = data.snapshot().collect();
+    match write_file(path, &codec.encode(&items, &mut CodecStats::default())) {
+        Ok(()) => Ok(()),
+        Err(error) => {
+            let mut data = D::default();
+            data.restore(items);
+            Err((data, error))
+        },
+    }
+}
+
+/// Read data written by `write_data` from `path`.
+fn read_data<D: MigratableState<W>, W: ExchangeData>(path: &Path, codec: Codec) -> io::Result<D> {
+    let mut data = D::default();
+    data.restore(codec.decode::<W>(&read_file(path)?));
+    Ok(data)
+}
+
+/// The size of `data`'s items, unless `data` provides a hint.
+fn measure_data<D: MigratableState<W>, W: ExchangeData>(data: &mut D) -> usize {
+    if let Some(size) = data.size_hint_bytes() {
+        return size;
+    }
+    let items: Vec<W> = ::std::mem::replace(data, Default::default()).snapshot().collect();
+    let size = items.iter().map(|item| ::abomonation::measure(item)).sum();
+    data.restore(items);
+    size
+}
@@ -1,14 +1,15 @@
 //! General purpose state transition operator, implemented with Megaphone.
 use std::hash::Hash;
-
-use fnv::FnvHashMap as HashMap;
+use std::sync::Arc;
 
 use timely::ExchangeData;
 use timely::dataflow::{Stream, Scope};
 use timely::Data;
 
 use operator::StatefulOperator;
-use ::Control;
+use stateful::Rekey;
+use tracked::{tracker, TrackedMap};
+use ::{Control, StatefulConfig};
 
 /// Provide a general-purpose state machine operator that can be migrated without changes to the
 /// `fold` implementation.
@@ -26,6 +27,9 @@ where
     /// a `bool` indicating that it is appropriate to deregister the state, cleaning up once
     /// the state is no longer helpful.
     ///
+    /// Bins of the state machine can be split, `hash` determines the keys of the state. The state
+    /// tracks changes, so bins can be pre-copied.
+    ///
     /// #Examples
     /// ```
     /// ```
@@ -33,8 +37,8 @@ where
         R: Data,                                    // output type
         I: IntoIterator<Item=R>,                    // type of output iterator
         F: Fn(&K, V, &mut D)->(bool, I)+'static,    // state update logic
-        H: Fn(&K)->u64+'static,                     // "hash" function for keys
-    >(&self, fold: F, hash: H, control: &Stream<S, Control>) -> Stream<S, R> where S::Timestamp : Hash+Eq;
+        H: Fn(&K)->u64+Send+Sync+'static,           // "hash" function for keys
+    >(&self, fold: F, hash: H, control: &Stream<S, Control>, config: &StatefulConfig<S::Timestamp>) -> Stream<S, R> where S::Timestamp : Hash+Eq;
 }
 
 impl<S, K, V, D> BinnedStateMachine<S, K, V, D> for Stream<S, (K, V)>
@@ -49,12 +53,19 @@ where
         R: Data,                                    // output type
         I: IntoIterator<Item=R>,                    // type of output iterator
         F: Fn(&K, V, &mut D) -> (bool, I) + 'static,    // state update logic
-        H: Fn(&K)->u64+'static,                     // "hash" function for keys
-    >(&self, fold: F, hash: H, control: &Stream<S, Control>) -> Stream<S, R> where S::Timestamp : Hash+Eq {
+        H: Fn(&K)->u64+Send+Sync+'static,           // "hash" function for keys
+    >(&self, fold: F, hash: H, control: &Stream<S, Control>, config: &StatefulConfig<S::Timestamp>) -> Stream<S, R> where S::Timestamp : Hash+Eq {
+
+        let hash = Arc::new(hash);
+        let (hash_state, hash_notification) = (Arc::clone(&hash), Arc::clone(&hash));
+        let rekey = Rekey {
+            state: Box::new(move |&(ref k, _): &(K, D)| hash_state(k)),
+            notification: Box::new(move |&(ref k, _): &(K, V)| hash_notification(k)),
+        };
 
-        self.stateful_unary(control, move |(k, _v)| hash(&k), "StateMachine", move |cap, iter, bin, output| {
+        self.stateful_unary_rekey(control, config, move |(k, _v)| hash(&k), rekey, Some(tracker), "StateMachine", move |cap, iter, bin, output| {
             let mut session = output.session(&cap);
-            let states: &mut HashMap<_, _> = bin.state();
+            let states: &mut TrackedMap<_, _> = bin.state();
             for (_time, (key, val)) in iter.drain(..) {
                 let (remove, output) = {
                     if !states.contains_key(&key) {
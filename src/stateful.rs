@@ -7,7 +7,9 @@
 //!
 use std::hash::Hash;
 use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::rc::Rc;
+use std::time::Instant;
 
 use std::marker::PhantomData;
 
@@ -21,13 +23,20 @@ use timely::dataflow::operators::generic::builder_rc::OperatorBuilder;
 use timely::dataflow::operators::Feedback;
 use timely::dataflow::operators::feedback::Handle as FeedbackHandle;
 use timely::order::TotalOrder;
-use timely::progress::Timestamp;
+use timely::progress::{PathSummary, Timestamp};
 use timely::progress::frontier::Antichain;
 
-use ::{BIN_SHIFT, Bin, BinId, Control, ControlSetBuilder, ControlSet, Key, key_to_bin, State};
+use checkpoint;
+use codec::{Codec, CodecStats};
+use logging::{self, MegaphoneEvent, LOGGER_NAME};
+use migratable::MigratableState;
+use spill::Spill;
+use tracked::Tracker;
+use ::{Bin, BinId, BinLayout, Control, ControlError, ControlSetBuilder, ControlSet, Key, MigrationBudget, State, StatefulConfig, WorkerPolicy};
 
 const BUFFER_CAP: usize = 16;
 
+/// The notificator used by stateful operators.
 pub type Notificator<T, D> = ::notificator::TotalOrderFrontierNotificator<T, D>;
 
 /// Generic state-transition machinery: each key has a state, and receives a sequence of events.
@@ -48,6 +57,81 @@ pub enum StateProtocol<T, S, D> {
     Pending(BinId, T, D),
     /// Prepare for receiving state
     Prepare(BinId),
+    /// Terminate the state sent for a bin by one of its sources
+    Complete(BinId),
+    /// Hold records for a bin until its state is `Complete`
+    Hold(BinId),
+    /// Provide a snapshot of a bin's state ahead of a migration, holding records until `Complete`
+    PreCopy(BinId, Vec<S>),
+    /// Remove entries of a bin's state that changed since its snapshot
+    Retract(BinId, Vec<S>),
+    /// Provide a piece of state for a bin, encoded with a `Codec`
+    Encoded(BinId, Codec, Vec<u8>),
+}
+
+/// The phase of a migration reported by a `MigrationAck`.
+#[derive(Abomonation, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MigrationPhase {
+    /// The configuration became active and its state was sent.
+    Installed,
+    /// All state sent for the configuration has been applied at the receivers.
+    Applied,
+}
+
+/// Acknowledges the progress of a configuration change on a worker.
+///
+/// Each worker reports the bins it sent to other workers, and the number of bytes sent, once for
+/// each `MigrationPhase`.
+#[derive(Abomonation, Clone, Debug, Eq, PartialEq)]
+pub struct MigrationAck<T> {
+    /// The sequence number of the configuration
+    pub sequence: u64,
+    /// The frontier at which the configuration became active
+    pub frontier: Vec<T>,
+    /// The bins this worker moved to other workers
+    pub moved_bins: Vec<BinId>,
+    /// The number of bytes of state and pending notifications this worker sent. State sent in the
+    /// background is only included once `Applied`.
+    pub bytes_moved: usize,
+    /// The sizes of and time spent encoding state sent to other workers
+    pub codec: CodecStats,
+    /// The phase of the migration
+    pub phase: MigrationPhase,
+}
+
+/// Work and state of a bin on a worker since the previous report.
+///
+/// `records` counts the records the worker routed to the bin, regardless of the bin's owner. The
+/// remaining counters are reported by the bin's owner.
+#[derive(Abomonation, Clone, Debug, Eq, PartialEq)]
+pub struct BinStats {
+    /// The reporting worker
+    pub worker: usize,
+    /// The bin
+    pub bin: BinId,
+    /// The number of records this worker routed to the bin
+    pub records: usize,
+    /// The number of notifications delivered to the bin's folds
+    pub notifications: usize,
+    /// The number of fold invocations
+    pub folds: usize,
+    /// The time spent in folds, in nanoseconds
+    pub fold_nanos: u64,
+    /// The size of the bin's state, if it is owned and provides `MigratableState::size_hint_bytes`
+    pub state_bytes: Option<usize>,
+}
+
+/// Functions to recover the keys of state and pending notifications.
+///
+/// Splitting a bin distributes its contents to two bins, reassigning keys may distribute a bin's
+/// contents to any bin. As bins only store data, `stateful`
+/// needs to determine the key of each piece of state, i.e., `W`, and each pending notification,
+/// i.e., `M`. The keys need to agree with the key function used to route data.
+pub struct Rekey<W, M> {
+    /// Extract the key from a piece of state.
+    pub state: Box<dyn Fn(&W)->u64 + Send + Sync>,
+    /// Extract the key from a pending notification.
+    pub notification: Box<dyn Fn(&M)->u64 + Send + Sync>,
 }
 
 /// A timely `Stream` with an additional state handle and a probe.
@@ -55,7 +139,7 @@ pub struct StateStream<S, V, D, W, M> where
     S: Scope, // The containing scope
     S::Timestamp: TotalOrder,
     V: ExchangeData, // Input data
-    D: IntoIterator<Item=W>+Extend<W>+Default+'static,    // per-bin state (data)
+    D: MigratableState<W>+'static,    // per-bin state (data)
     W: ExchangeData,                            // State format on the wire
     M: ExchangeData,
 {
@@ -72,6 +156,12 @@ pub struct StateStream<S, V, D, W, M> where
     pub state: Rc<RefCell<State<S::Timestamp, D, M>>>,
     /// The probe `stateful` uses to determine completion.
     pub feedback: FeedbackHandle<S, ()>,
+    /// Stream of rejected control batches, as `(sequence, reason)` pairs.
+    pub diagnostics: Stream<S, (u64, ControlError)>,
+    /// Stream of acknowledgements for configuration changes.
+    pub acks: Stream<S, MigrationAck<S::Timestamp>>,
+    /// Stream of per-bin statistics, if enabled by `StatefulConfig::with_stats_interval`.
+    pub stats: Stream<S, BinStats>,
     _phantom: PhantomData<(*const W)>,
 }
 
@@ -80,45 +170,162 @@ impl<S, V, D, W, M> StateStream<S, V, D, W, M>
         S: Scope, // The containing scope
         S::Timestamp: TotalOrder,
         V: ExchangeData, // Input data
-        D: IntoIterator<Item=W>+Extend<W>+Default,    // per-key state (data)
+        D: MigratableState<W>,    // per-key state (data)
         W: ExchangeData,
         M: ExchangeData,
 {
-    pub fn new(stream: Stream<S, (usize, Key, V)>, state_stream: Stream<S, (usize, StateProtocol<S::Timestamp, W, M>)>, state: Rc<RefCell<State<S::Timestamp, D, M>>>, feedback: FeedbackHandle<S, ()>) -> Self {
+    /// Construct a new `StateStream` from its parts.
+    pub fn new(stream: Stream<S, (usize, Key, V)>, state_stream: Stream<S, (usize, StateProtocol<S::Timestamp, W, M>)>, state: Rc<RefCell<State<S::Timestamp, D, M>>>, feedback: FeedbackHandle<S, ()>, diagnostics: Stream<S, (u64, ControlError)>, acks: Stream<S, MigrationAck<S::Timestamp>>, stats: Stream<S, BinStats>) -> Self {
         StateStream {
             stream,
             state_stream,
             state,
             feedback,
+            diagnostics,
+            acks,
+            stats,
             _phantom: PhantomData,
         }
     }
 
 }
 
+/// Construct the message providing a `chunk` of state for `bin`. State sent to other workers is
+/// encoded with `codec`.
+pub(crate) fn state_message<T, W: ExchangeData, M>(bin: usize, chunk: Vec<W>, codec: Codec, local: bool, stats: &mut CodecStats) -> StateProtocol<T, W, M> {
+    if local || codec == Codec::None {
+        StateProtocol::State(BinId(bin), chunk)
+    } else {
+        StateProtocol::Encoded(BinId(bin), codec, codec.encode(&chunk, stats))
+    }
+}
+
+/// Count a record routed to `bin`.
+fn count_routed(routed: &mut Vec<usize>, bin: usize) {
+    if bin >= routed.len() {
+        routed.resize(bin + 1, 0);
+    }
+    routed[bin] += 1;
+}
+
+/// The entries of pre-copied `bins` that changed since the previous round, as state updates for
+/// their owners in `next`, and their size in bytes.
+fn pre_copy_delta<T, D, W, M>(tracker: Tracker<D, W>, states: &mut State<T, D, M>, bins: &[usize], next: &ControlSet<T>) -> (Vec<(usize, StateProtocol<T, W, M>)>, usize)
+    where
+        T: Timestamp+TotalOrder,
+        D: MigratableState<W>,
+        W: ExchangeData,
+{
+    let mut updates = Vec::new();
+    let mut bytes = 0;
+    for &bin in bins {
+        let target = next.map()[bin];
+        states.load(bin);
+        let (changed, removed) = tracker(&mut states.bins[bin].as_mut().expect("Pre-copied bin but it is None").data).delta();
+        if !changed.is_empty() || !removed.is_empty() {
+            bytes += ::abomonation::measure(&changed) + ::abomonation::measure(&removed);
+            updates.push((target, StateProtocol::Retract(BinId(bin), removed)));
+            updates.push((target, StateProtocol::PreCopy(BinId(bin), changed)));
+        }
+    }
+    (updates, bytes)
+}
+
+/// The configuration applying to records at `time`: the last pending configuration whose frontier
+/// is less or equal to `time`, or else the active configuration.
+pub(crate) fn configuration_at<'a, T, I>(pending: I, active: &'a ControlSet<T>, time: &T) -> &'a ControlSet<T>
+    where
+        T: Timestamp,
+        I: DoubleEndedIterator<Item=&'a ControlSet<T>>,
+{
+    pending.rev().find(|c| c.frontier.less_equal(time)).unwrap_or(active)
+}
+
+/// Pair each record with the worker owning its key's bin in `control_set`, and its key. Records
+/// are counted per bin in `routed`, if provided.
+pub(crate) fn route<'a, T, V, B, I>(control_set: &'a ControlSet<T>, key: &'a B, data: I, mut routed: Option<&'a mut Vec<usize>>) -> impl Iterator<Item=(usize, Key, V)>+'a
+    where
+        T: 'a,
+        V: 'a,
+        B: Fn(&V)->u64+'a,
+        I: Iterator<Item=V>+'a,
+{
+    data.map(move |d| {
+        let key_id = Key(key(&d));
+        if let Some(ref mut routed) = routed {
+            count_routed(routed, control_set.layout().bin(key_id));
+        }
+        (control_set.worker(key_id), key_id, d)
+    })
+}
+
+/// Apply a sequence of state updates, as received on a `StateStream`'s state stream, to `states`.
+///
+/// State arrives in chunks, each of which is applied as it is received. Pre-copied state requires
+/// a `tracker` to retract entries that changed since the snapshot.
 pub fn apply_state_updates<
     T: Timestamp+TotalOrder, // The containing scope
-    D: IntoIterator<Item=W>+Extend<W>+Default,    // per-key state (data)
+    D: MigratableState<W>,    // per-key state (data)
     W: ExchangeData,
     M: ExchangeData,
-    I: Iterator<Item=(usize, StateProtocol<T, W, M>)>>(states: &mut State<T, D, M>, cap: &Capability<T>, data: I) {
+    I: Iterator<Item=(usize, StateProtocol<T, W, M>)>>(states: &mut State<T, D, M>, cap: &Capability<T>, data: I, tracker: Option<Tracker<D, W>>) {
 
     // Apply each state update
     for (_target, state) in data {
         match state {
+            // Reassigning keys can send state for a bin from several workers, which might overtake
+            // the bin's `Prepare`. Splitting bins introduces new bins.
             StateProtocol::Prepare(bin) => {
-                assert!(states.bins[*bin].is_none());
-                states.bins[*bin] = Some(Default::default());
+                states.reserve_bins(*bin + 1);
+                states.receive(*bin, cap.time(), &());
+                states.bins[*bin].get_or_insert_with(Default::default);
             }
             // Extend state
             StateProtocol::State(bin, s) => {
-                if let Some(bin) = states.bins[*bin].as_mut() {
-                    bin.data.extend(s.into_iter());
-                }
+                states.reserve_bins(*bin + 1);
+                states.receive(*bin, cap.time(), &s);
+                states.bins[*bin].get_or_insert_with(Default::default).data.restore(s);
+            },
+            // Request notification, the bin's data needs to be in memory once notified
+            StateProtocol::Pending(bin, t, data) => {
+                states.reserve_bins(*bin + 1);
+                states.receive(*bin, cap.time(), &data);
+                states.load(*bin);
+                states.bins[*bin].get_or_insert_with(Default::default).notificator().notify_at_data(cap, t, data)
+            },
+            // All state from one source was applied
+            StateProtocol::Complete(bin) => {
+                states.reserve_bins(*bin + 1);
+                states.received(*bin, cap.time());
+                states.bins[*bin].get_or_insert_with(Default::default).held = false;
+            },
+            // State is sent in the background, records need to wait
+            StateProtocol::Hold(bin) => {
+                states.reserve_bins(*bin + 1);
+                states.receive(*bin, cap.time(), &());
+                states.bins[*bin].get_or_insert_with(Default::default).held = true;
+            },
+            // Stage a snapshot, the bin becomes active once complete
+            StateProtocol::PreCopy(bin, s) => {
+                states.reserve_bins(*bin + 1);
+                states.receive(*bin, cap.time(), &s);
+                let bin = states.bins[*bin].get_or_insert_with(Default::default);
+                bin.held = true;
+                bin.data.restore(s);
+            },
+            // Decode and extend state
+            StateProtocol::Encoded(bin, codec, bytes) => {
+                states.reserve_bins(*bin + 1);
+                states.receive(*bin, cap.time(), &bytes);
+                states.bins[*bin].get_or_insert_with(Default::default).data.restore(codec.decode(&bytes));
+            },
+            // Remove stale entries of a snapshot
+            StateProtocol::Retract(bin, s) => {
+                let tracker = tracker.expect("Retracting state requires change tracking");
+                states.reserve_bins(*bin + 1);
+                states.receive(*bin, cap.time(), &s);
+                tracker(&mut states.bins[*bin].get_or_insert_with(Default::default).data).retract(s);
             },
-            // Request notification
-            StateProtocol::Pending(bin, t, data) =>
-                states.bins[*bin].as_mut().unwrap().notificator().notify_at_data(cap, t, data),
         }
     }
 
@@ -133,17 +340,48 @@ pub trait Stateful<S: Scope, V: ExchangeData> {
     /// stream provides key-to-worker assignments. `stateful` applies the configuration changes such
     /// that a correctly-written downstream operator will still function correctly.
     ///
+    /// Malformed batches of control messages are rejected and reported on the `diagnostics`
+    /// stream, the active configuration remains in place. This includes batches assigning bins to
+    /// workers beyond the number of peers, unless the configuration's `WorkerPolicy` says to clamp
+    /// them.
+    ///
+    /// Configuration changes are acknowledged on the `acks` stream, once when a configuration
+    /// becomes active, and once when all state sent for it has been applied downstream.
+    ///
+    /// With a stats interval configured, each worker reports the records it routed to each bin,
+    /// and the work and state size of the bins it owns, on the `stats` stream. Bins without
+    /// activity since the last report are omitted.
+    ///
+    /// Migration events are logged to the worker's logger registered as `logging::LOGGER_NAME`, if
+    /// any.
+    ///
+    /// With a limited `MigrationBudget`, moved bins are sent in the background, at most the budget
+    /// per activation, while records for other bins are processed. Records for a bin in flight are
+    /// held at the receiver until the bin is complete. The next configuration is installed once all
+    /// bins have been sent.
+    ///
+    /// Splitting bins and reassigning keys requires `rekey` functions to determine the keys of the
+    /// affected bins' contents. Batches containing such instructions are rejected if there are
+    /// none.
+    ///
+    /// If the configuration enables pre-copy and a `tracker` is provided, moved bins are copied to
+    /// their new owner as soon as the configuration is known, while records are still processed.
+    /// Later activations send the entries that changed since the previous round. Once the
+    /// configuration becomes active, only the entries that changed since the last round are sent.
+    ///
     /// # Parameters
     /// * `W`: State serialization format
     /// * `D`: Data associated with keys
     /// * `B`: Key function
-    fn stateful<W, D, B, M>(&self, key: B, control: &Stream<S, Control>) -> StateStream<S, V, D, W, M>
+    ///
+    /// The number of bins and the assignment of keys to bins are determined by `config`.
+    fn stateful<W, D, B, M>(&self, key: B, control: &Stream<S, Control>, config: &StatefulConfig<S::Timestamp>, rekey: Option<Rekey<W, M>>, tracker: Option<Tracker<D, W>>) -> StateStream<S, V, D, W, M>
         where
             S::Timestamp: Hash+Eq+TotalOrder,
             // State format on the wire
             W: ExchangeData,
             // per-key state (data)
-            D: IntoIterator<Item=W>+Extend<W>+Default,
+            D: MigratableState<W>,
             // "hash" function for values
             B: Fn(&V)->u64+'static,
             M: ExchangeData,
@@ -153,31 +391,76 @@ pub trait Stateful<S: Scope, V: ExchangeData> {
 #[cfg(not(feature = "fake_stateful"))]
 impl<S: Scope, V: ExchangeData> Stateful<S, V> for Stream<S, V> {
 
-    fn stateful<W, D, B, M>(&self, key: B, control: &Stream<S, Control>) -> StateStream<S, V, D, W, M>
+    fn stateful<W, D, B, M>(&self, key: B, control: &Stream<S, Control>, config: &StatefulConfig<S::Timestamp>, rekey: Option<Rekey<W, M>>, tracker: Option<Tracker<D, W>>) -> StateStream<S, V, D, W, M>
         where
             S::Timestamp: Hash+Eq+TotalOrder,
             // State format on the wire
             W: ExchangeData,
             // per-key state (data)
-            D: IntoIterator<Item=W>+Extend<W>+Default,
+            D: MigratableState<W>,
             // "hash" function for values
             B: Fn(&V)->u64+'static,
             M: ExchangeData,
     {
         let index = self.scope().index();
         let peers = self.scope().peers();
+        let worker_policy = config.worker_policy();
+        let chunk_size = config.chunk_size();
+        let migration_budget = config.migration_budget();
+        let codec = config.codec();
+        // Pre-copy needs to track changes
+        let tracker = if config.pre_copy() { tracker } else { None };
+        let checkpoint_directory = config.checkpoint_directory().map(|directory| directory.to_path_buf());
+        let checkpoint_interval = config.checkpoint_interval().cloned();
+        assert!(checkpoint_interval.is_none() || checkpoint_directory.is_some(), "Periodic checkpoints require a checkpoint directory");
+        let stats_interval = config.stats_interval();
+        let report_stats = stats_interval.is_some();
+
+        // TODO : default configuration may be poorly chosen.
+        // A checkpoint that fails to restore is reported, the operator starts from the initial
+        // configuration instead
+        let mut restore_error = None;
+        let (mut active_configuration, mut checkpoint_round, restored) = match config.restore().map(|checkpoint| checkpoint::restore(checkpoint, index, peers, config.assigner().clone_box())) {
+            Some(Ok(restored)) => restored,
+            Some(Err(error)) => {
+                restore_error = Some(ControlError::RestoreFailed(error.to_string()));
+                (ControlSet::initial(config, peers), 0, Vec::new())
+            },
+            None => (ControlSet::initial(config, peers), 0, Vec::new()),
+        };
 
-        let map: Vec<usize> = (0..peers).cycle().take(1 << BIN_SHIFT).collect();
         // worker-local state, maps bins to state
-        let default_elements: Vec<Option<_>> = map.iter().map(|i| if *i == index {
-            Some(Default::default())
-        } else {
-            None
+        let mut default_elements: Vec<Option<Bin<_, D, _>>> = (0..active_configuration.layout().bins()).map(|bin| {
+            if active_configuration.layout().is_active(bin) && active_configuration.map()[bin] == index {
+                Some(Default::default())
+            } else {
+                None
+            }
         }).collect();
-        let states: Rc<RefCell<State<S::Timestamp, D, M>>> = Rc::new(RefCell::new(State::new(default_elements)));
+        // Restored notifications are requested once the operator runs
+        let mut restored_pending = Vec::new();
+        for (bin, items, pending) in restored {
+            default_elements[bin].as_mut().expect("Restored bin but it is None").data.restore(items);
+            restored_pending.extend(pending.into_iter().map(|(time, data)| (bin, time, data)));
+        }
+        let states: Rc<RefCell<State<S::Timestamp, D, M>>> = Rc::new(RefCell::new(State::new(active_configuration.layout().clone(), default_elements)));
         let states_f = Rc::clone(&states);
 
+        let logger = self.scope().log_register().get::<MegaphoneEvent<S::Timestamp>>(LOGGER_NAME);
+        states.borrow_mut().set_logger(logger.clone());
+
         let mut builder = OperatorBuilder::new("StateMachine F".into(), self.scope());
+        // Reschedule the operator while sending state in the background
+        let activator = self.scope().activator_for(&builder.operator_info().address[..]);
+
+        let spill = config.spill().is_some();
+        if let Some((directory, budget)) = config.spill() {
+            // Each instance of the operator spills to its own directory
+            let address: Vec<_> = builder.operator_info().address.iter().map(|a| a.to_string()).collect();
+            let directory = directory.join(format!("{}-{}", address.join("-"), index));
+            ::std::fs::create_dir_all(&directory).expect("Failed to create spill directory");
+            states.borrow_mut().set_spill(Spill::new::<W>(directory, budget, codec));
+        }
 
         // The data input
         let mut data_in = builder.new_input(self, Pipeline);
@@ -187,9 +470,15 @@ impl<S: Scope, V: ExchangeData> Stateful<S, V> for Stream<S, V> {
         let (mut data_out, stream) = builder.new_output();
         // State output of the F operator
         let (mut state_out, state) = builder.new_output_connection(vec![Antichain::new(), Antichain::from_elem(Default::default())]);
+        // Diagnostics output of the F operator, reporting rejected control batches
+        let (mut diagnostics_out, diagnostics) = builder.new_output_connection(vec![Antichain::new(), Antichain::from_elem(Default::default())]);
+        // Acknowledgement output of the F operator, reporting installed and applied configurations
+        let (mut acks_out, acks) = builder.new_output_connection(vec![Antichain::new(), Antichain::from_elem(Default::default())]);
+        // Statistics output of the F operator, reporting per-bin counters
+        let (mut stats_out, stats) = builder.new_output_connection(vec![Antichain::from_elem(Default::default()), Antichain::new()]);
 
         let (feedback_handle, feedback_stream) = self.scope().feedback(Default::default());
-        let feedback_in_connection = vec![Antichain::new(); 2];
+        let feedback_in_connection = vec![Antichain::new(); 5];
         let _feedback_in = builder.new_input_connection(&feedback_stream, Pipeline, feedback_in_connection);
 
         // Probe to be attached after the last stateful operator
@@ -197,7 +486,25 @@ impl<S: Scope, V: ExchangeData> Stateful<S, V> for Stream<S, V> {
 //        let probe2 = probe1.clone();
 
         // Construct F operator
-        builder.build(move |_capability| {
+        builder.build(move |capability| {
+
+            // Capability to request restored notifications
+            let mut restore_cap = if restored_pending.is_empty() { None } else { Some(capability[1].clone()) };
+            // Capability to report a failed restore
+            let mut restore_failure = restore_error.take().map(|error| (capability[2].clone(), error));
+
+            // Capability to report statistics, and the time of the last report
+            let mut stats_cap = if report_stats { Some(capability[4].clone()) } else { None };
+            let mut last_report = Instant::now();
+
+            // Time of the next periodic checkpoint, with capabilities to hold back the state and
+            // diagnostics outputs until its configuration is in place
+            let mut next_checkpoint = checkpoint_interval.as_ref().and_then(|interval| {
+                active_configuration.frontier.elements().first().and_then(|time| interval.results_in(time))
+            });
+            let mut checkpoint_caps = next_checkpoint.as_ref().map(|time| (capability[1].delayed(time), capability[2].delayed(time)));
+            // Records routed to each bin since the last report
+            let mut routed: Vec<usize> = Vec::new();
 
             // distinct notificators for data and control input
             let mut data_notificator = Notificator::new();
@@ -208,16 +515,27 @@ impl<S: Scope, V: ExchangeData> Stateful<S, V> for Stream<S, V> {
 
             // Active configurations: Vec<(T, ControlInstr)> sorted by increasing T. Note that
             // we assume the Ts form a total order, i.e. they must dominate each other.
-            let mut pending_configurations: Vec<(Capability<S::Timestamp>, ControlSet<S::Timestamp>)> = Vec::new();
+            // Checkpointing configurations carry a capability to report failures, and all but
+            // periodic checkpoints a capability to acknowledge them.
+            let mut pending_configurations: Vec<(Capability<S::Timestamp>, ControlSet<S::Timestamp>, Option<Capability<S::Timestamp>>, Option<Capability<S::Timestamp>>)> = Vec::new();
 
-            let mut pending_configuration_data: HashMap<S::Timestamp, ControlSetBuilder<S::Timestamp>> = Default::default();
+            // Control set builders, and capabilities to report diagnostics and acknowledgements, by time
+            let mut pending_configuration_data: HashMap<S::Timestamp, (ControlSetBuilder<S::Timestamp>, Capability<S::Timestamp>, Capability<S::Timestamp>, usize)> = Default::default();
 
-            // TODO : default configuration may be poorly chosen.
-            let mut active_configuration: ControlSet<S::Timestamp> = ControlSet { 
-                sequence: 0,
-                frontier: Antichain::from_elem(Default::default()),
-                map,
-            };
+            // Installed configurations waiting for their state to be applied after a time
+            let mut pending_acks: Vec<(Capability<S::Timestamp>, S::Timestamp, MigrationAck<S::Timestamp>)> = Vec::new();
+
+            // Bins sent in the background as (target worker, bin, remaining state, bytes sent), the
+            // capability to send them, and the acknowledgement to issue once done
+            let mut outbox: VecDeque<(usize, usize, D::Snapshot, usize)> = VecDeque::new();
+            let mut migration_cap: Option<Capability<S::Timestamp>> = None;
+            let mut background_ack: Option<(Capability<S::Timestamp>, MigrationAck<S::Timestamp>)> = None;
+
+            // Sequence number of the pre-copied configuration, the bins copied, and the bytes sent
+            let mut pre_copied: Option<(u64, Vec<usize>, usize)> = None;
+
+            // The data frontier at the last time bins were spilled
+            let mut spill_frontier = Vec::new();
 
             // Stash for consumed input buffers
             let mut data_return_buffer = vec![];
@@ -228,19 +546,35 @@ impl<S: Scope, V: ExchangeData> Stateful<S, V> for Stream<S, V> {
             move |frontiers| {
                 let mut data_out = data_out.activate();
                 let mut state_out = state_out.activate();
+                let mut diagnostics_out = diagnostics_out.activate();
+                let mut acks_out = acks_out.activate();
+                let mut stats_out = stats_out.activate();
+
+                // Request the notifications of restored bins
+                if let Some(cap) = restore_cap.take() {
+                    let mut session = state_out.session(&cap);
+                    for (bin, time, data) in restored_pending.drain(..) {
+                        session.give((index, StateProtocol::Pending(BinId(bin), time, data)));
+                    }
+                }
+                if let Some((cap, error)) = restore_failure.take() {
+                    diagnostics_out.session(&cap).give((active_configuration.sequence, error));
+                }
 
                 // Read control input
                 control_in.for_each(|time, data| {
                     data.swap(&mut control_data_buffer);
                     // Append to pending control instructions
-                    let builder = pending_configuration_data.entry(time.time().clone()).or_insert_with(|| {
+                    let &mut (ref mut builder, _, _, ref mut bytes) = pending_configuration_data.entry(time.time().clone()).or_insert_with(|| {
                         let mut builder: ControlSetBuilder<S::Timestamp> = Default::default();
                         // TODO: We don't know the frontier at the time the command was received.
                         builder.frontier(vec![time.time().clone()].into_iter());
-                        builder
+                        (builder, time.retain_for_output(2), time.retain_for_output(3), 0)
                     });
+                    *bytes += control_data_buffer.iter().map(::abomonation::measure).sum::<usize>();
                     for update in control_data_buffer.drain(..) {
-                        builder.apply(update);
+                        // Errors are retained by the builder and reported once it is built
+                        let _ = builder.apply(update);
                     }
                     control_notificator.notify_at(&time.retain_for_output(1));
                 });
@@ -248,11 +582,33 @@ impl<S: Scope, V: ExchangeData> Stateful<S, V> for Stream<S, V> {
                 // Analyze control frontier
                 control_notificator.for_each(&[&frontiers[1]], |cap, time, _not| {
                     // Check if there are pending control instructions
-                    if let Some(builder) = pending_configuration_data.remove(&time) {
+                    if let Some((builder, diagnostics_cap, ack_cap, bytes)) = pending_configuration_data.remove(&time) {
+                        let sequence = builder.sequence().unwrap_or(0);
+                        if builder.requires_rekey() && rekey.is_none() {
+                            diagnostics_out.session(&diagnostics_cap).give((sequence, ControlError::RekeyUnsupported));
+                            return;
+                        }
                         // Build new configuration
-                        let config = builder.build(pending_configurations.last().map_or(&active_configuration, |pending| &pending.1));
+                        let clamped = if worker_policy == WorkerPolicy::Clamp { builder.workers_out_of_range(peers) } else { Vec::new() };
+                        let config = match builder.build(pending_configurations.last().map_or(&active_configuration, |pending| &pending.1), peers, worker_policy) {
+                            Ok(config) => config,
+                            Err(error) => {
+                                // Reject the configuration, the previous one remains in place
+                                diagnostics_out.session(&diagnostics_cap).give((sequence, error));
+                                return;
+                            }
+                        };
+                        if config.checkpoint && checkpoint_directory.is_none() {
+                            diagnostics_out.session(&diagnostics_cap).give((sequence, ControlError::CheckpointUnsupported));
+                            return;
+                        }
+                        logging::log(&logger, MegaphoneEvent::ConfigurationReceived { time: time.clone(), sequence: config.sequence, bytes });
+                        for worker in clamped {
+                            logging::log(&logger, MegaphoneEvent::WorkerClamped { time: time.clone(), sequence: config.sequence, worker, peers });
+                        }
+                        let diagnostics_cap = if config.checkpoint { Some(diagnostics_cap) } else { None };
                         // Append to list of compiled configuration
-                        pending_configurations.push((cap.delayed(&time), config));
+                        pending_configurations.push((cap.delayed(&time), config, diagnostics_cap, Some(ack_cap)));
                         // Sort by provided sequence number
                         pending_configurations.sort_by_key(|d| d.1.sequence);
 
@@ -269,6 +625,35 @@ impl<S: Scope, V: ExchangeData> Stateful<S, V> for Stream<S, V> {
                     }
                 });
 
+                // Once the control frontier passes the time of the next periodic checkpoint, the
+                // configuration in place at that time is repeated with a checkpoint. Checkpoints
+                // stop once neither records nor configurations can arrive.
+                if frontiers[0].frontier().is_empty() && frontiers[1].frontier().is_empty() {
+                    next_checkpoint = None;
+                    checkpoint_caps = None;
+                }
+                while let Some(time) = next_checkpoint.take() {
+                    if frontiers[1].less_equal(&time) || frontiers[0].less_than(&time) {
+                        next_checkpoint = Some(time);
+                        break;
+                    }
+                    let (state_cap, diagnostics_cap) = checkpoint_caps.take().expect("Periodic checkpoint without capabilities");
+                    let position = pending_configurations.iter().rposition(|pending| pending.1.frontier.less_than(&time)).map_or(0, |position| position + 1);
+                    let config = {
+                        let previous = if position > 0 { &pending_configurations[position - 1].1 } else { &active_configuration };
+                        ControlSet {
+                            sequence: previous.sequence,
+                            frontier: Antichain::from_elem(time.clone()),
+                            map: previous.map().clone(),
+                            layout: previous.layout().clone(),
+                            checkpoint: true,
+                        }
+                    };
+                    pending_configurations.insert(position, (state_cap.delayed(&time), config, Some(diagnostics_cap.delayed(&time)), None));
+                    next_checkpoint = checkpoint_interval.as_ref().and_then(|interval| interval.results_in(&time));
+                    checkpoint_caps = next_checkpoint.as_ref().map(|next| (state_cap.delayed(next), diagnostics_cap.delayed(next)));
+                }
+
                 // Did we cross a frontier?
                 // Here we can't really express frontier equality yet ):
                 // What we really want is to know if we can apply a configuration change or not.
@@ -281,63 +666,416 @@ impl<S: Scope, V: ExchangeData> Stateful<S, V> for Stream<S, V> {
 
                 // If the next configuration to install is no longer at all ahead of the state machine output,
                 // then there can be no more records or state updates for any configuration prior to the next.
-                if pending_configurations.get(0).is_some() {
+                // Copy bins moving with the next configuration while records are still processed.
+                // Periodic checkpoints keep the configuration in place and carry no acknowledgement.
+                if let Some(tracker) = tracker {
+                    if let Some(&(ref cap, ref next, _, _)) = pending_configurations.iter().find(|pending| pending.3.is_some()) {
+                        if pre_copied.as_ref().map_or(true, |&(sequence, _, _)| sequence != next.sequence) {
+                            let old = &active_configuration;
+                            let mut bins = Vec::new();
+                            let mut bytes_sent = 0;
+                            // Only bins keeping their keys can be copied ahead. Checkpoints may
+                            // rebuild bins, which discards tracked changes.
+                            if old.layout().generation() == next.layout().generation() && !next.checkpoint {
+                                let mut states = states_f.borrow_mut();
+                                let mut session = state_out.session(cap);
+                                for bin in old.layout().active() {
+                                    if old.map()[bin] == index && next.map()[bin] != index && old.layout().prefix(bin) == next.layout().prefix(bin) {
+                                        let target = next.map()[bin];
+                                        states.load(bin);
+                                        let data = &mut states.bins[bin].as_mut().expect("Pre-copying bin but it is None").data;
+                                        logging::log(&logger, MegaphoneEvent::BinSendStart { time: cap.time().clone(), bin: BinId(bin), bytes: data.size_hint_bytes() });
+                                        let snapshot = tracker(data).snapshot();
+                                        let mut chunk = Vec::new();
+                                        let mut bytes = 0;
+                                        for item in snapshot {
+                                            bytes += ::abomonation::measure(&item);
+                                            chunk.push(item);
+                                            if bytes >= chunk_size {
+                                                session.give((target, StateProtocol::PreCopy(BinId(bin), ::std::mem::replace(&mut chunk, Vec::new()))));
+                                                bytes_sent += bytes;
+                                                bytes = 0;
+                                            }
+                                        }
+                                        session.give((target, StateProtocol::PreCopy(BinId(bin), chunk)));
+                                        bytes_sent += bytes;
+                                        bins.push(bin);
+                                    }
+                                }
+                            }
+                            pre_copied = Some((next.sequence, bins, bytes_sent));
+                        } else if let Some((_, ref bins, ref mut bytes_sent)) = pre_copied {
+                            // Later rounds only send the entries that changed since the last round
+                            let (updates, bytes) = pre_copy_delta(tracker, &mut states_f.borrow_mut(), bins, next);
+                            state_out.session(cap).give_iterator(updates.into_iter());
+                            *bytes_sent += bytes;
+                        }
+                    }
+                }
+
+                // Checkpoints need bins other workers send in the background to be complete
+                let checkpoint_blocked = pending_configurations.first().map_or(false, |&(_, ref next, _, _)| {
+                    let states = states_f.borrow();
+                    next.checkpoint && active_configuration.layout().active().any(|bin| {
+                        active_configuration.map()[bin] == index && states.bins[bin].as_ref().map_or(true, |bin| bin.is_held())
+                    })
+                });
+
+                // Background migrations need to complete first.
+                if pending_configurations.get(0).is_some() && outbox.is_empty() && !checkpoint_blocked {
                     if pending_configurations[0].1.frontier.elements().iter().all(|t| !frontiers[2].less_than(t)) {
 
                         // We should now install `pending_configurations[0]` into `active_configuration`!
-                        let (time, to_install) = pending_configurations.remove(0);
+                        let (time, to_install, diagnostics_cap, ack_cap) = pending_configurations.remove(0);
+                        let mut moved_bins = Vec::new();
+                        let mut bytes_moved = 0;
+                        let mut codec_stats = CodecStats::default();
+                        let pre_copied_bins = match pre_copied.take() {
+                            // Periodic checkpoints keep the bins pre-copied for a later configuration
+                            Some(pre_copy) if ack_cap.is_none() => {
+                                pre_copied = Some(pre_copy);
+                                Vec::new()
+                            },
+                            Some((sequence, bins, bytes)) if sequence == to_install.sequence => {
+                                bytes_moved += bytes;
+                                bins
+                            },
+                            _ => Vec::new(),
+                        };
 
-                        {   // Scoped to let `old_map` and `new_map` borrows drop.
-                            let old_map = active_configuration.map();
-                            let new_map = to_install.map();
+                        {   // Scoped to let `old` and `new` borrows drop.
+                            let old = &active_configuration;
+                            let new = &to_install;
+                            let (old_layout, new_layout) = (old.layout(), new.layout());
 
                             // Grab states
                             let mut states = states_f.borrow_mut();
+
+                            // All records before the configuration's time are applied, none after
+                            if new.checkpoint {
+                                let directory = checkpoint_directory.as_ref().expect("Checkpoint without directory");
+                                // Periodic checkpoints carry no acknowledgement
+                                let path = if ack_cap.is_some() {
+                                    checkpoint::path(directory, new.sequence)
+                                } else {
+                                    checkpoint_round += 1;
+                                    checkpoint::periodic_path(directory, new.sequence, checkpoint_round - 1)
+                                };
+                                // Writing may rebuild bins, discarding their tracked changes.
+                                // Pre-copied bins send their changes first and track changes again
+                                // afterwards.
+                                let mut tracked = None;
+                                if let (Some(tracker), Some(&mut (sequence, ref bins, ref mut bytes_sent))) = (tracker, pre_copied.as_mut()) {
+                                    if let Some(&(_, ref next, _, _)) = pending_configurations.iter().find(|pending| pending.1.sequence == sequence && pending.3.is_some()) {
+                                        let (updates, bytes) = pre_copy_delta(tracker, &mut states, bins, next);
+                                        state_out.session(&time).give_iterator(updates.into_iter());
+                                        *bytes_sent += bytes;
+                                        tracked = Some((tracker, bins.clone()));
+                                    }
+                                }
+                                if let Err(error) = checkpoint::write(&path, new.sequence, checkpoint_round, &new.frontier, index, peers, old, &mut states, codec) {
+                                    let diagnostics_cap = diagnostics_cap.as_ref().expect("Checkpoint without diagnostics capability");
+                                    diagnostics_out.session(diagnostics_cap).give((new.sequence, ControlError::CheckpointFailed(error.to_string())));
+                                }
+                                if let Some((tracker, bins)) = tracked {
+                                    for bin in bins {
+                                        tracker(&mut states.bins[bin].as_mut().expect("Pre-copied bin but it is None").data).delta();
+                                    }
+                                }
+                            }
+
+                            states.set_layout(new_layout.clone());
                             let mut session = state_out.session(&time);
-                            // Determine if we're to move state
-                            for (bin, (old, new)) in old_map.iter().zip(new_map.iter()).enumerate() {
-                                // Migration is needed if a bin is to be moved (`old != new`) and the state
-                                // actually contains data. Also, we must be the current owner of the bin.
-                                if (*old % peers == index) && (old != new) {
-                                    // Capture bin's values as a stream of data
-                                    let mut state = states.bins[bin].take().expect("Instructed to move bin but it is None");
-                                    let Bin { data, notificator } = state;
-                                    session.give((*new, StateProtocol::Prepare(BinId(bin))));
-                                    let chunk: Vec<_> = data.into_iter().collect();
-                                    println!("migration\t{}\t{}\t{}\t{}", bin, old, new, chunk.len());
-                                    session.give((*new, StateProtocol::State(BinId(bin), chunk)));
-                                    session.give_iterator(notificator.pending().map(|(t, d)| (*new, StateProtocol::Pending(BinId(bin), t, d))));
+
+                            // Keys change bins if the assigner was updated
+                            let reassigned = old_layout.generation() != new_layout.generation();
+
+                            // Prepare bins that change shape or move. The worker previously
+                            // owning a bin's first position is responsible.
+                            for bin in new_layout.active() {
+                                let owner = old.worker_at(new_layout.start(bin).expect("Active bin without keys"));
+                                let changed = reassigned || old_layout.prefix(bin) != new_layout.prefix(bin);
+                                if owner == index && (changed || old.map()[bin] != new.map()[bin]) {
+                                    session.give((new.map()[bin], StateProtocol::Prepare(BinId(bin))));
+                                }
+                            }
+
+                            // Migration is needed if a bin is to be moved or changes shape. Also,
+                            // we must be the current owner of the bin.
+                            let mut sources = Vec::new();
+                            for bin in old_layout.active() {
+                                let changed = reassigned || old_layout.prefix(bin) != new_layout.prefix(bin);
+                                if old.map()[bin] == index && (changed || new.map()[bin] != index) {
+                                    states.load(bin);
+                                    sources.push((bin, states.bins[bin].take().expect("Instructed to move bin but it is None")));
+                                }
+                            }
+
+                            for (bin, state) in sources {
+                                // Capture bin's values as a stream of data
+                                let Bin { mut data, notificator, .. } = state;
+
+                                // Pre-copied bins only need their changes
+                                if pre_copied_bins.contains(&bin) {
+                                    let tracker = tracker.expect("Pre-copied bin without tracker");
+                                    let target = new.map()[bin];
+                                    let (changed, removed) = tracker(&mut data).changes();
+                                    let mut bytes = ::abomonation::measure(&changed) + ::abomonation::measure(&removed);
+                                    session.give((target, StateProtocol::Retract(BinId(bin), removed)));
+                                    session.give((target, state_message(bin, changed, codec, false, &mut codec_stats)));
+                                    let (mut count, mut pending_bytes) = (0, 0);
+                                    for entry in notificator.pending() {
+                                        count += 1;
+                                        pending_bytes += ::abomonation::measure(&entry);
+                                        session.give((target, StateProtocol::Pending(BinId(bin), entry.0, entry.1)));
+                                    }
+                                    bytes += pending_bytes;
+                                    bytes_moved += bytes;
+                                    session.give((target, StateProtocol::Complete(BinId(bin))));
+                                    if count > 0 {
+                                        logging::log(&logger, MegaphoneEvent::NotificationsTransferred { time: time.time().clone(), bin: BinId(bin), target, count, bytes: pending_bytes });
+                                    }
+                                    logging::log(&logger, MegaphoneEvent::BinSendEnd { time: time.time().clone(), bin: BinId(bin), target, bytes });
+                                    moved_bins.push(BinId(bin));
+                                    continue;
+                                }
+
+                                // Moves of unchanged bins can be sent in the background
+                                if migration_budget != MigrationBudget::Unlimited && !reassigned && old_layout.prefix(bin) == new_layout.prefix(bin) {
+                                    let target = new.map()[bin];
+                                    logging::log(&logger, MegaphoneEvent::BinSendStart { time: time.time().clone(), bin: BinId(bin), bytes: data.size_hint_bytes() });
+                                    session.give((target, StateProtocol::Hold(BinId(bin))));
+                                    let (mut count, mut pending_bytes) = (0, 0);
+                                    for entry in notificator.pending() {
+                                        count += 1;
+                                        pending_bytes += ::abomonation::measure(&entry);
+                                        session.give((target, StateProtocol::Pending(BinId(bin), entry.0, entry.1)));
+                                    }
+                                    bytes_moved += pending_bytes;
+                                    if count > 0 {
+                                        logging::log(&logger, MegaphoneEvent::NotificationsTransferred { time: time.time().clone(), bin: BinId(bin), target, count, bytes: pending_bytes });
+                                    }
+                                    moved_bins.push(BinId(bin));
+                                    outbox.push_back((target, bin, data.snapshot(), pending_bytes));
+                                    continue;
+                                }
+
+                                logging::log(&logger, MegaphoneEvent::BinSendStart { time: time.time().clone(), bin: BinId(bin), bytes: data.size_hint_bytes() });
+
+                                // The bin's keys either end up in a single bin, or need re-keying
+                                let len = old_layout.prefix(bin).expect("Active bin without prefix").1;
+                                let first = new_layout.bin_at(old_layout.start(bin).expect("Active bin without keys"));
+                                let rekey_fns = if !reassigned && new_layout.prefix(first).map_or(false, |(_, first_len)| first_len <= len) {
+                                    None
+                                } else {
+                                    Some(rekey.as_ref().expect("Moving keys between bins requires re-keying"))
+                                };
+
+                                // Bins merging into a bin of this worker are combined in place
+                                if rekey_fns.is_none() && new.map()[first] == index {
+                                    let data = ::std::mem::replace(&mut data, Default::default());
+                                    states.bins[first].get_or_insert_with(Default::default).data.merge(data);
+                                }
+
+                                // States that know their size and fit in a chunk are not measured
+                                let size_hint = if rekey_fns.is_none() {
+                                    data.size_hint_bytes().filter(|size| *size < chunk_size)
+                                } else {
+                                    None
+                                };
+
+                                // Chunks under construction by target bin, with their size in bytes
+                                let mut chunks: HashMap<usize, (Vec<W>, usize)> = Default::default();
+                                if rekey_fns.is_none() {
+                                    chunks.insert(first, Default::default());
+                                }
+                                let mut moved = false;
+                                // Bytes sent to each target bin
+                                let mut sent: HashMap<usize, usize> = Default::default();
+                                {
+                                    let mut send = |target_bin: usize, message: StateProtocol<_, _, _>, bytes| {
+                                        let target = new.map()[target_bin];
+                                        if target != index {
+                                            bytes_moved += bytes;
+                                            moved = true;
+                                        }
+                                        *sent.entry(target_bin).or_insert(0) += bytes;
+                                        session.give((target, message));
+                                    };
+
+                                    // Send full chunks while draining the bin
+                                    for item in data.snapshot() {
+                                        let target_bin = rekey_fns.map_or(first, |rekey| new_layout.bin(Key((rekey.state)(&item))));
+                                        let bytes = if size_hint.is_some() { 0 } else { ::abomonation::measure(&item) };
+                                        let chunk = chunks.entry(target_bin).or_insert_with(Default::default);
+                                        chunk.0.push(item);
+                                        chunk.1 += bytes;
+                                        if chunk.1 >= chunk_size {
+                                            let local = new.map()[target_bin] == index;
+                                            let message = state_message(target_bin, ::std::mem::replace(&mut chunk.0, Vec::new()), codec, local, &mut codec_stats);
+                                            send(target_bin, message, chunk.1);
+                                            chunk.1 = 0;
+                                        }
+                                    }
+                                    if let Some(size) = size_hint {
+                                        chunks.get_mut(&first).expect("Missing chunk").1 = size;
+                                    }
+
+                                    let mut pending: HashMap<usize, Vec<(S::Timestamp, M)>> = Default::default();
+                                    for (t, d) in notificator.pending() {
+                                        let target_bin = rekey_fns.map_or(first, |rekey| new_layout.bin(Key((rekey.notification)(&d))));
+                                        pending.entry(target_bin).or_insert_with(Vec::new).push((t, d));
+                                        chunks.entry(target_bin).or_insert_with(Default::default);
+                                    }
+
+                                    // Send the remainder and terminate each target bin's transfer
+                                    for (target_bin, (chunk, bytes)) in chunks {
+                                        if !chunk.is_empty() {
+                                            let local = new.map()[target_bin] == index;
+                                            let message = state_message(target_bin, chunk, codec, local, &mut codec_stats);
+                                            send(target_bin, message, bytes);
+                                        }
+                                        let entries = pending.remove(&target_bin).unwrap_or_else(Vec::new);
+                                        let (count, mut pending_bytes) = (entries.len(), 0);
+                                        for entry in entries {
+                                            let bytes = ::abomonation::measure(&entry);
+                                            pending_bytes += bytes;
+                                            send(target_bin, StateProtocol::Pending(BinId(target_bin), entry.0, entry.1), bytes);
+                                        }
+                                        send(target_bin, StateProtocol::Complete(BinId(target_bin)), 0);
+                                        if count > 0 {
+                                            logging::log(&logger, MegaphoneEvent::NotificationsTransferred { time: time.time().clone(), bin: BinId(target_bin), target: new.map()[target_bin], count, bytes: pending_bytes });
+                                        }
+                                    }
+                                }
+                                for (target_bin, bytes) in sent {
+                                    logging::log(&logger, MegaphoneEvent::BinSendEnd { time: time.time().clone(), bin: BinId(bin), target: new.map()[target_bin], bytes });
+                                }
+                                if moved {
+                                    moved_bins.push(BinId(bin));
                                 }
                             }
                         }
 
+                        let ack = MigrationAck {
+                            sequence: to_install.sequence,
+                            frontier: to_install.frontier.elements().to_vec(),
+                            moved_bins,
+                            bytes_moved,
+                            codec: codec_stats,
+                            phase: MigrationPhase::Installed,
+                        };
+                        logging::log(&logger, MegaphoneEvent::ConfigurationInstalled { time: time.time().clone(), sequence: ack.sequence, moved_bins: ack.moved_bins.len(), bytes: ack.bytes_moved });
+                        // Periodic checkpoints move no bins and are not acknowledged
+                        if let Some(ack_cap) = ack_cap {
+                            acks_out.session(&ack_cap).give(ack.clone());
+                            let ack = MigrationAck { phase: MigrationPhase::Applied, ..ack };
+                            if outbox.is_empty() {
+                                let time = time.time().clone();
+                                pending_acks.push((ack_cap, time, ack));
+                            } else {
+                                migration_cap = Some(time.clone());
+                                background_ack = Some((ack_cap, ack));
+                            }
+                        }
+
                         // Promote the pending config to active
                         active_configuration = to_install;
                     }
                 }
 
+                // Send state of bins migrating in the background, within the budget
+                if let Some(mut cap) = migration_cap.take() {
+                    // Let records for other bins pass
+                    if let Some(time) = frontiers[0].frontier().iter().min() {
+                        if cap.time().less_than(time) {
+                            cap.downgrade(time);
+                        }
+                    }
+                    let mut budget = migration_budget.limit();
+                    let mut bytes_sent = 0;
+                    let mut codec_stats = CodecStats::default();
+                    {
+                        let mut session = state_out.session(&cap);
+                        while budget > 0 {
+                            let exhausted = match outbox.front_mut() {
+                                Some(&mut (target, bin, ref mut data, ref mut sent)) => {
+                                    let mut chunk = Vec::new();
+                                    let mut bytes = 0;
+                                    let mut exhausted = false;
+                                    while budget > 0 && bytes < chunk_size {
+                                        if let Some(item) = data.next() {
+                                            let size = ::abomonation::measure(&item);
+                                            budget = budget.saturating_sub(migration_budget.cost(size));
+                                            bytes += size;
+                                            chunk.push(item);
+                                        } else {
+                                            exhausted = true;
+                                            break;
+                                        }
+                                    }
+                                    bytes_sent += bytes;
+                                    *sent += bytes;
+                                    if !chunk.is_empty() {
+                                        session.give((target, state_message(bin, chunk, codec, false, &mut codec_stats)));
+                                    }
+                                    if exhausted {
+                                        session.give((target, StateProtocol::Complete(BinId(bin))));
+                                        logging::log(&logger, MegaphoneEvent::BinSendEnd { time: cap.time().clone(), bin: BinId(bin), target, bytes: *sent });
+                                    }
+                                    exhausted
+                                },
+                                None => break,
+                            };
+                            if exhausted {
+                                outbox.pop_front();
+                            }
+                        }
+                    }
+                    if let Some((_, ref mut ack)) = background_ack {
+                        ack.bytes_moved += bytes_sent;
+                        ack.codec += codec_stats;
+                    }
+                    if outbox.is_empty() {
+                        if let Some((ack_cap, ack)) = background_ack.take() {
+                            pending_acks.push((ack_cap, cap.time().clone(), ack));
+                        }
+                    } else {
+                        migration_cap = Some(cap);
+                        activator.activate();
+                    }
+                }
+
+                // State for a configuration is sent at the configuration's time, or later if sent in
+                // the background. Once the feedback frontier passed this time, the receivers have
+                // applied all of it.
+                while pending_acks.first().map_or(false, |ack| !frontiers[2].less_equal(&ack.1)) {
+                    let (ack_cap, _, ack) = pending_acks.remove(0);
+                    acks_out.session(&ack_cap).give(ack);
+                }
+
+                // Spill cold bins once per completed time. Pre-copied bins track their changes and
+                // stay in memory.
+                if spill && frontiers[0].frontier() != &spill_frontier[..] {
+                    spill_frontier = frontiers[0].frontier().to_vec();
+                    let exclude = pre_copied.as_ref().map_or(&[][..], |&(_, ref bins, _)| &bins[..]);
+                    if let Err(error) = states_f.borrow_mut().evict(exclude) {
+                        logging::log(&logger, MegaphoneEvent::SpillFailed { frontier: spill_frontier.clone(), error: error.to_string() });
+                    }
+                }
+
                 data_notificator.for_each(&[&frontiers[0], &frontiers[1]], |cap, time, _not| {
                     // Check for stashed data - now control input has to have advanced
                     if let Some(vec) = data_stash.remove(&time) {
 
-                        let map =
-                            pending_configurations
-                                .iter()
-                                .rev()
-                                .map(|c| &c.1)
-                                .find(|&c| c.frontier.less_equal(&time))
-                                .unwrap_or(&active_configuration)
-                                .map();
+                        let control_set = configuration_at(pending_configurations.iter().map(|c| &c.1), &active_configuration, &time);
 
                         let session_cap = cap.delayed(&time);
                         let mut session = data_out.session(&session_cap);
                         for mut data in vec {
                             {
-                                let data_iter = data.drain(..).map(|d| {
-                                    let key_id = Key(key(&d));
-                                    (map[key_to_bin(key_id)], key_id, d)
-                                });
-                                session.give_iterator(data_iter);
+                                let routed = if report_stats { Some(&mut routed) } else { None };
+                                session.give_iterator(route(control_set, &key, data.drain(..), routed));
                             }
                             if data_return_buffer.len() < BUFFER_CAP {
                                 data_return_buffer.push(data);
@@ -356,56 +1094,88 @@ impl<S: Scope, V: ExchangeData> Stateful<S, V> for Stream<S, V> {
                         }
                         let mut data_vec = data_return_buffer.pop().unwrap_or_else(Vec::new);
                         data.swap(&mut data_vec);
+                        if logger.is_some() {
+                            logging::log(&logger, MegaphoneEvent::RecordsStashed { time: time.time().clone(), records: data_vec.len(), bytes: data_vec.iter().map(::abomonation::measure).sum() });
+                        }
                         data_stash.get_mut(time.time()).unwrap().push(data_vec);
                         data_notificator.notify_at(&time.retain_for_output(0));
                     } else {
                         // Yes, control frontier not <= `time`, process right-away
 
                         // Find the configuration that applies to the input time
-                        let map =
-                            pending_configurations
-                                .iter()
-                                .rev()
-                                .map(|c| &c.1)
-                                .find(|&c| c.frontier.less_equal(time.time()))
-                                .unwrap_or(&active_configuration)
-                                .map();
+                        let control_set = configuration_at(pending_configurations.iter().map(|c| &c.1), &active_configuration, time.time());
 
                         let mut session = data_out.session(&time);
 
                         let mut data_vec = data_return_buffer.pop().unwrap_or_else(Vec::new);
                         data.swap(&mut data_vec);
-                        let data_iter = data_vec.drain(..).map(|d| {
-                            let key_id = Key(key(&d));
-                            (map[key_to_bin(key_id)], key_id, d)
-                        });
-                        session.give_iterator(data_iter);
+                        let routed = if report_stats { Some(&mut routed) } else { None };
+                        session.give_iterator(route(control_set, &key, data_vec.drain(..), routed));
                     }
                 });
 
+                // Report statistics about every interval, at the data frontier
+                if let Some(mut cap) = stats_cap.take() {
+                    if let Some(time) = frontiers[0].frontier().iter().min() {
+                        if cap.time().less_than(time) {
+                            cap.downgrade(time);
+                        }
+                        if last_report.elapsed() >= stats_interval.expect("Reporting statistics without interval") {
+                            last_report = Instant::now();
+                            let mut states = states_f.borrow_mut();
+                            let mut session = stats_out.session(&cap);
+                            let bins = ::std::cmp::max(routed.len(), states.bins.len());
+                            for bin in 0..bins {
+                                let records = routed.get_mut(bin).map_or(0, |records| ::std::mem::replace(records, 0));
+                                let (counters, state_bytes) = match states.bins.get_mut(bin) {
+                                    Some(&mut Some(ref mut state)) => {
+                                        let counters = ::std::mem::replace(&mut state.counters, Default::default());
+                                        let state_bytes = if state.spilled { None } else { state.data.size_hint_bytes() };
+                                        (counters, state_bytes)
+                                    },
+                                    _ => (Default::default(), None),
+                                };
+                                if records > 0 || counters.folds > 0 {
+                                    session.give(BinStats {
+                                        worker: index,
+                                        bin: BinId(bin),
+                                        records,
+                                        notifications: counters.notifications,
+                                        folds: counters.folds,
+                                        fold_nanos: counters.fold_nanos,
+                                        state_bytes,
+                                    });
+                                }
+                            }
+                        }
+                        stats_cap = Some(cap);
+                    }
+                }
+
             }
         });
 
         // `stream` is the stateful output stream where data is already correctly partitioned.
-        StateStream::new(stream, state, states, feedback_handle)
+        StateStream::new(stream, state, states, feedback_handle, diagnostics, acks, stats)
     }
 }
 
 #[cfg(feature = "fake_stateful")]
 impl<S: Scope, V: ExchangeData> Stateful<S, V> for Stream<S, V> {
-    fn stateful<W, D, B, M>(&self, key: B, _control: &Stream<S, Control>) -> StateStream<S, V, D, W, M>
+    fn stateful<W, D, B, M>(&self, key: B, _control: &Stream<S, Control>, config: &StatefulConfig<S::Timestamp>, _rekey: Option<Rekey<W, M>>, _tracker: Option<Tracker<D, W>>) -> StateStream<S, V, D, W, M>
         where
             S::Timestamp : Hash+Eq+TotalOrder,
         // State format on the wire
             W: ExchangeData,
         // per-key state (data)
-            D: IntoIterator<Item=W>+Extend<W>+Default,
+            D: MigratableState<W>,
         // "hash" function for values
             B: Fn(&V)->u64+'static,
             M: ExchangeData,
     {
         // construct states, we simply construct all bins on each worker
-        let states: Rc<RefCell<State<S::Timestamp, D, M>>> = Rc::new(RefCell::new(State::new(::std::iter::repeat_with(|| Some(Default::default())).take(1 << BIN_SHIFT).collect())));
+        let bin_shift = config.bin_shift();
+        let states: Rc<RefCell<State<S::Timestamp, D, M>>> = Rc::new(RefCell::new(State::new(BinLayout::new(bin_shift, config.assigner().clone_box()), ::std::iter::repeat_with(|| Some(Default::default())).take(config.bins()).collect())));
 
         // Feedback handle to be attached after the last stateful operator
         let (feedback_handle, feedback_stream) = self.scope().feedback(Default::default());
@@ -418,10 +1188,19 @@ impl<S: Scope, V: ExchangeData> Stateful<S, V> for Stream<S, V> {
                 let key = Key(key(&d));
                 (key.0 as usize, key, d)
             })
-            .exchange(|d| (d.0 ^ d.0.rotate_left(BIN_SHIFT as u32)) as u64);
+            .exchange(move |d| (d.0 ^ d.0.rotate_left(bin_shift as u32)) as u64);
         let state_stream = _control
             .filter(|_| false)
             .map(|_| (0, StateProtocol::Prepare(BinId(0))));
-        StateStream::new(stream, state_stream, states, feedback_handle)
+        let diagnostics = _control
+            .filter(|_| false)
+            .map(|_| (0, ControlError::MissingControls(0)));
+        let acks = _control
+            .filter(|_| false)
+            .map(|_| MigrationAck { sequence: 0, frontier: Vec::new(), moved_bins: Vec::new(), bytes_moved: 0, codec: Default::default(), phase: MigrationPhase::Installed });
+        let stats = _control
+            .filter(|_| false)
+            .map(|_| BinStats { worker: 0, bin: BinId(0), records: 0, notifications: 0, folds: 0, fold_nanos: 0, state_bytes: None });
+        StateStream::new(stream, state_stream, states, feedback_handle, diagnostics, acks, stats)
     }
 }
@@ -0,0 +1,162 @@
+//! State types that track changes, enabling pre-copy migration.
+//!
+//! A bin's state can be copied to its new owner ahead of a configuration change while the current
+//! owner keeps processing records. Further rounds send the entries that changed since the previous
+//! round. Once the configuration becomes active, only the entries that changed since the last round
+//! are sent.
+
+use std::hash::Hash;
+
+use fnv::{FnvHashMap as HashMap, FnvHashSet as HashSet};
+
+use migratable::MigratableState;
+
+/// State that can report the entries that changed since a snapshot.
+pub trait TrackChanges<W> {
+    /// Copy all entries and start tracking changes.
+    fn snapshot(&mut self) -> Vec<W>;
+
+    /// Return the entries changed since the snapshot or the previous delta, followed by the entries
+    /// removed since, and keep tracking changes.
+    fn delta(&mut self) -> (Vec<W>, Vec<W>);
+
+    /// Stop tracking changes and return the entries changed since the snapshot or the previous
+    /// delta, followed by the entries removed since.
+    fn changes(&mut self) -> (Vec<W>, Vec<W>);
+
+    /// Remove the entries corresponding to `items`.
+    fn retract(&mut self, items: Vec<W>);
+}
+
+/// Obtain the change-tracking view of a state.
+pub type Tracker<D, W> = fn(&mut D) -> &mut dyn TrackChanges<W>;
+
+/// A hash map that tracks changed and removed keys while a snapshot is being migrated.
+#[derive(Clone, Debug)]
+pub struct TrackedMap<K: Eq+Hash, V> {
+    map: HashMap<K, V>,
+    // Changed keys and removed entries, if tracking
+    tracking: Option<(HashSet<K>, Vec<(K, V)>)>,
+}
+
+impl<K: Eq+Hash+Clone, V: Clone> TrackedMap<K, V> {
+
+    /// Test if `key` is present.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.map.contains_key(key)
+    }
+
+    /// Obtain a reference to the value of `key`.
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.map.get(key)
+    }
+
+    /// Obtain a mutable reference to the value of `key`, marking it as changed.
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        if let Some((ref mut changed, _)) = self.tracking {
+            if self.map.contains_key(key) {
+                changed.insert(key.clone());
+            }
+        }
+        self.map.get_mut(key)
+    }
+
+    /// Insert a value for `key`, returning the previous value.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some((ref mut changed, _)) = self.tracking {
+            changed.insert(key.clone());
+        }
+        self.map.insert(key, value)
+    }
+
+    /// Remove `key`, returning its value.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let value = self.map.remove(key);
+        if let Some((ref mut changed, ref mut removed)) = self.tracking {
+            changed.remove(key);
+            if let Some(ref value) = value {
+                removed.push((key.clone(), value.clone()));
+            }
+        }
+        value
+    }
+
+    /// The number of entries.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Test if there are no entries.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Iterate all entries.
+    pub fn iter(&self) -> ::std::collections::hash_map::Iter<K, V> {
+        self.map.iter()
+    }
+}
+
+impl<K: Eq+Hash, V> Default for TrackedMap<K, V> {
+    fn default() -> Self {
+        Self {
+            map: Default::default(),
+            tracking: None,
+        }
+    }
+}
+
+impl<K: Eq+Hash+Clone, V: Clone> MigratableState<(K, V)> for TrackedMap<K, V> {
+    type Snapshot = ::std::collections::hash_map::IntoIter<K, V>;
+
+    fn snapshot(self) -> Self::Snapshot {
+        self.map.into_iter()
+    }
+
+    fn restore<I: IntoIterator<Item=(K, V)>>(&mut self, items: I) {
+        self.map.extend(items)
+    }
+
+    fn size_hint_bytes(&self) -> Option<usize> {
+        self.map.size_hint_bytes()
+    }
+
+    fn items(&self) -> Option<Vec<(K, V)>> where (K, V): Clone {
+        Some(self.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+    }
+}
+
+impl<K: Eq+Hash+Clone, V: Clone> TrackChanges<(K, V)> for TrackedMap<K, V> {
+    fn snapshot(&mut self) -> Vec<(K, V)> {
+        self.tracking = Some(Default::default());
+        self.map.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+
+    fn delta(&mut self) -> (Vec<(K, V)>, Vec<(K, V)>) {
+        let (changed, removed) = self.changes();
+        self.tracking = Some(Default::default());
+        (changed, removed)
+    }
+
+    fn changes(&mut self) -> (Vec<(K, V)>, Vec<(K, V)>) {
+        match self.tracking.take() {
+            Some((changed, removed)) => {
+                let map = &self.map;
+                let changed = changed.into_iter().filter_map(|k| map.get(&k).cloned().map(|v| (k, v))).collect();
+                (changed, removed)
+            },
+            None => (Vec::new(), Vec::new()),
+        }
+    }
+
+    fn retract(&mut self, items: Vec<(K, V)>) {
+        for (k, _v) in items {
+            self.map.remove(&k);
+        }
+    }
+}
+
+/// The change-tracking view of a state, for use as a `Tracker`.
+pub fn tracker<D: TrackChanges<W>, W>(state: &mut D) -> &mut dyn TrackChanges<W> {
+    state
+}
@@ -0,0 +1,360 @@
+//! Keyed windows whose state migrates with Megaphone bins.
+//!
+//! The `window` method assigns each record to windows by an event time, aggregates the records of
+//! each key and window with an [`Aggregate`], and reports the result once the window closes:
+//!
+//! ```ignore
+//! let maxima = bids.window(&control, &config, Windows::tumbling(60).with_lateness(10),
+//!     |b: &Bid| b.auction, |b| b.date_time, |end| end, Max(|b: &Bid| b.price), "MaxPrice");
+//! ```
+//!
+//! A window `[start, end)` closes at the dataflow time `time(end)`. Records arriving after that
+//! time, but no later than `time(end + lateness)`, update the window and report its result again.
+//! Later records are dropped. Windows and the timers closing them are kept in bins, and migrate
+//! along with them. Once the input is complete, all open windows close.
+//!
+//! [`Aggregate`]: trait.Aggregate.html
+
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use timely::{Data, ExchangeData};
+use timely::dataflow::{Stream, Scope};
+use timely::order::TotalOrder;
+
+use ::{Control, StatefulConfig};
+use operator::StatefulOperator;
+
+/// An aggregate function over the records of a window.
+pub trait Aggregate<D>: 'static {
+    /// The aggregate of a window's records, which migrates along with the window.
+    type State: ExchangeData+Default;
+    /// The result reported for a window.
+    type Output: Data;
+    /// Add `record` to `state`.
+    fn add(&self, state: &mut Self::State, record: &D);
+    /// Combine the aggregate `other` into `state`, as session windows merge.
+    fn merge(&self, state: &mut Self::State, other: Self::State);
+    /// The result for a window with aggregate `state`.
+    fn result(&self, state: &Self::State) -> Self::Output;
+}
+
+/// Counts the records of a window.
+#[derive(Clone, Copy, Debug)]
+pub struct Count;
+
+impl<D> Aggregate<D> for Count {
+    type State = u64;
+    type Output = u64;
+    fn add(&self, state: &mut u64, _record: &D) {
+        *state += 1;
+    }
+    fn merge(&self, state: &mut u64, other: u64) {
+        *state += other;
+    }
+    fn result(&self, state: &u64) -> u64 {
+        *state
+    }
+}
+
+/// Sums a value extracted from each record of a window.
+#[derive(Clone, Copy, Debug)]
+pub struct Sum<F>(pub F);
+
+impl<D, F: Fn(&D)->u64+'static> Aggregate<D> for Sum<F> {
+    type State = u64;
+    type Output = u64;
+    fn add(&self, state: &mut u64, record: &D) {
+        *state += (self.0)(record);
+    }
+    fn merge(&self, state: &mut u64, other: u64) {
+        *state += other;
+    }
+    fn result(&self, state: &u64) -> u64 {
+        *state
+    }
+}
+
+/// The maximum of a value extracted from each record of a window.
+#[derive(Clone, Copy, Debug)]
+pub struct Max<F>(pub F);
+
+impl<D, F: Fn(&D)->u64+'static> Aggregate<D> for Max<F> {
+    type State = u64;
+    type Output = u64;
+    fn add(&self, state: &mut u64, record: &D) {
+        *state = ::std::cmp::max(*state, (self.0)(record));
+    }
+    fn merge(&self, state: &mut u64, other: u64) {
+        *state = ::std::cmp::max(*state, other);
+    }
+    fn result(&self, state: &u64) -> u64 {
+        *state
+    }
+}
+
+/// How records are assigned to windows, and how long windows accept late records.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Windows {
+    kind: WindowKind,
+    lateness: u64,
+    per_bin: bool,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum WindowKind {
+    Tumbling(/*size*/ u64),
+    Sliding(/*size*/ u64, /*slide*/ u64),
+    Session(/*gap*/ u64),
+}
+
+impl Windows {
+    /// Windows of `size` starting at multiples of `size`.
+    pub fn tumbling(size: u64) -> Self {
+        assert!(size > 0, "Window size must be positive");
+        Self { kind: WindowKind::Tumbling(size), lateness: 0, per_bin: false }
+    }
+
+    /// Windows of `size` starting at multiples of `slide`. Each record belongs to all windows
+    /// containing its event time.
+    pub fn sliding(size: u64, slide: u64) -> Self {
+        assert!(size > 0 && slide > 0, "Window size and slide must be positive");
+        Self { kind: WindowKind::Sliding(size, slide), lateness: 0, per_bin: false }
+    }
+
+    /// Windows of a key's records whose event times are no more than `gap` apart. A window ends
+    /// `gap` after its last record.
+    pub fn session(gap: u64) -> Self {
+        assert!(gap > 0, "Session gap must be positive");
+        Self { kind: WindowKind::Session(gap), lateness: 0, per_bin: false }
+    }
+
+    /// Accept records up to `lateness` after the end of their window.
+    pub fn with_lateness(mut self, lateness: u64) -> Self {
+        self.lateness = lateness;
+        self
+    }
+
+    /// Get the allowed lateness.
+    pub fn lateness(&self) -> u64 {
+        self.lateness
+    }
+
+    /// Aggregate the records of each bin instead of each key, reporting the bin in place of the
+    /// key. This pre-aggregates records before an exchange at one result per bin and window.
+    /// Records belong to the bin the operator's current layout assigns their key to.
+    pub fn per_bin(mut self) -> Self {
+        self.per_bin = true;
+        self
+    }
+
+    /// Test if windows aggregate per bin.
+    pub fn is_per_bin(&self) -> bool {
+        self.per_bin
+    }
+
+    /// The windows containing `event_time` as `(start, end)`. For session windows, the window of
+    /// the record by itself.
+    fn assign(&self, event_time: u64) -> Vec<(u64, u64)> {
+        match self.kind {
+            WindowKind::Tumbling(size) => {
+                let start = event_time / size * size;
+                vec![(start, start + size)]
+            },
+            WindowKind::Sliding(size, slide) => {
+                let mut windows = Vec::new();
+                let mut start = event_time / slide * slide;
+                while start + size > event_time {
+                    windows.push((start, start + size));
+                    if start < slide {
+                        break;
+                    }
+                    start -= slide;
+                }
+                windows
+            },
+            WindowKind::Session(gap) => vec![(event_time, event_time + gap)],
+        }
+    }
+}
+
+/// Records and timers of a window operator, in time order.
+#[derive(Abomonation, Clone, Debug, Eq, PartialEq)]
+enum WindowEvent<D> {
+    /// A record with its key and event time
+    Record(u64, u64, D),
+    /// Report the result of the window `(key, start, end)`
+    Fire(u64, u64, u64),
+    /// Discard the window `(key, start, end)`
+    Expire(u64, u64, u64),
+}
+
+impl<D> WindowEvent<D> {
+    fn is_timer(&self) -> bool {
+        match *self {
+            WindowEvent::Record(..) => false,
+            WindowEvent::Fire(..) | WindowEvent::Expire(..) => true,
+        }
+    }
+
+    /// Test if the event is a timer for the window `(key, start, end)`.
+    fn is_timer_for(&self, key: u64, start: u64, end: u64) -> bool {
+        match *self {
+            WindowEvent::Record(..) => false,
+            WindowEvent::Fire(k, s, e) | WindowEvent::Expire(k, s, e) => (k, s, e) == (key, start, end),
+        }
+    }
+}
+
+/// Provides the `window` method.
+pub trait WindowOperator<G: Scope, D: ExchangeData+Eq> {
+    /// Aggregate the records of each key and window with `aggregate`.
+    ///
+    /// `key` extracts the key of a record, and `event_time` the time assigning it to `windows`.
+    /// `time` maps an event time to the dataflow time at which windows ending at it close, and has
+    /// to be monotonic. Returns the results as `(key, start, end, result)`, at the time the window
+    /// closed or a late record updated it.
+    fn window<
+        B: Fn(&D)->u64+'static,                      // Key extraction function
+        E: Fn(&D)->u64+'static,                      // Event time extraction function
+        F: Fn(u64)->G::Timestamp+'static,            // Dataflow time at which windows close
+        A: Aggregate<D>,
+    >(&self, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, windows: Windows, key: B, event_time: E, time: F, aggregate: A, name: &str) -> Stream<G, (u64, u64, u64, A::Output)>;
+}
+
+impl<G, D> WindowOperator<G, D> for Stream<G, D>
+    where
+        G: Scope,
+        G::Timestamp: TotalOrder,
+        D: ExchangeData+Eq,
+{
+    fn window<
+        B: Fn(&D)->u64+'static,
+        E: Fn(&D)->u64+'static,
+        F: Fn(u64)->G::Timestamp+'static,
+        A: Aggregate<D>,
+    >(&self, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, windows: Windows, key: B, event_time: E, time: F, aggregate: A, name: &str) -> Stream<G, (u64, u64, u64, A::Output)>
+    {
+        // The key routes records and identifies their windows
+        let key = Rc::new(key);
+        let route = Rc::clone(&key);
+        let lateness = windows.lateness;
+        let per_bin = windows.per_bin;
+
+        let mut data_buffer = vec![];
+        let mut results = vec![];
+        // Windows that could not register a timer as the input is complete
+        let mut unfired = vec![];
+
+        self.stateful_unary_input::<_, WindowEvent<D>, _, BTreeMap<(u64, u64), (u64, A::State)>, _, _, _>(control, config, move |d| route(d), name,
+            move |state, cap, time, data, _output| {
+                data.swap(&mut data_buffer);
+                for (_worker, key_id, d) in data_buffer.drain(..) {
+                    let window_key = if per_bin { state.layout().bin(key_id) as u64 } else { key(&d) };
+                    let event = WindowEvent::Record(window_key, event_time(&d), d);
+                    state.get(key_id).notificator().notify_at_data(cap, time.clone(), event);
+                }
+            },
+            move |cap, data, bin, output| {
+                // Apply records before the timers of their time
+                data.sort_by_key(|&(ref time, ref event)| (time.clone(), event.is_timer()));
+                for (now, event) in data.drain(..) {
+                    match event {
+                        WindowEvent::Record(key, event_time, record) => {
+                            let assigned = windows.assign(event_time).into_iter()
+                                .filter(|&(_, end)| now.less_equal(&time(end + lateness)));
+                            // Windows with their prior state, and whether they need timers
+                            let targets: Vec<_> = match windows.kind {
+                                // Merge the record's session with the key's overlapping sessions
+                                WindowKind::Session(_) => assigned.map(|(start, end)| {
+                                    let overlapping: Vec<_> = {
+                                        let state = bin.state();
+                                        let starts: Vec<_> = state.range((key, 0)..=(key, end))
+                                            .filter(|&(_, &(e, _))| start <= e)
+                                            .map(|(&(_, s), _)| s)
+                                            .collect();
+                                        starts.into_iter().map(|s| {
+                                            let (e, state) = state.remove(&(key, s)).expect("Missing session");
+                                            (s, e, state)
+                                        }).collect()
+                                    };
+                                    let merged_start = overlapping.iter().map(|&(s, _, _)| s).fold(start, ::std::cmp::min);
+                                    let merged_end = overlapping.iter().map(|&(_, e, _)| e).fold(end, ::std::cmp::max);
+                                    // A session containing the record keeps its timers
+                                    let new = !(overlapping.len() == 1 && overlapping[0].0 == merged_start && overlapping[0].1 == merged_end);
+                                    let mut merged = A::State::default();
+                                    for (s, e, state) in overlapping {
+                                        if new {
+                                            bin.cancel_timers(|_time, event| event.is_timer_for(key, s, e));
+                                        }
+                                        aggregate.merge(&mut merged, state);
+                                    }
+                                    (merged_start, merged_end, Some(merged), new)
+                                }).collect(),
+                                _ => assigned.map(|(start, end)| {
+                                    let prior = bin.state().remove(&(key, start)).map(|(_, state)| state);
+                                    let new = prior.is_none();
+                                    (start, end, prior, new)
+                                }).collect(),
+                            };
+
+                            for (start, end, prior, new) in targets {
+                                let mut state = prior.unwrap_or_default();
+                                aggregate.add(&mut state, &record);
+                                let close = time(end);
+                                if now.less_equal(&close) {
+                                    if new && !bin.register_timer(cap, close, WindowEvent::Fire(key, start, end)) {
+                                        unfired.push((key, start, end));
+                                    }
+                                } else {
+                                    if !unfired.contains(&(key, start, end)) {
+                                        results.push((now.clone(), (key, start, end, aggregate.result(&state))));
+                                    }
+                                    if new {
+                                        bin.register_timer(cap, time(end + lateness), WindowEvent::Expire(key, start, end));
+                                    }
+                                }
+                                bin.state().insert((key, start), (end, state));
+                            }
+                        },
+                        WindowEvent::Fire(key, start, end) => {
+                            let result = match bin.state().get(&(key, start)) {
+                                Some(&(e, ref state)) if e == end => Some(aggregate.result(state)),
+                                _ => None,
+                            };
+                            if let Some(result) = result {
+                                results.push((now.clone(), (key, start, end, result)));
+                                // Keep the window for late records
+                                if lateness == 0 || !bin.register_timer(cap, time(end + lateness), WindowEvent::Expire(key, start, end)) {
+                                    bin.state().remove(&(key, start));
+                                }
+                            }
+                        },
+                        WindowEvent::Expire(key, start, end) => {
+                            if bin.state().get(&(key, start)).map_or(false, |&(e, _)| e == end) {
+                                bin.state().remove(&(key, start));
+                            }
+                        },
+                    }
+                }
+
+                // The input is complete, close the remaining windows
+                for (key, start, end) in unfired.drain(..) {
+                    if bin.state().get(&(key, start)).map_or(false, |&(e, _)| e == end) {
+                        let (_, state) = bin.state().remove(&(key, start)).expect("Missing window");
+                        results.push((cap.time().clone(), (key, start, end, aggregate.result(&state))));
+                    }
+                }
+
+                // Report results at their time
+                let mut results = results.drain(..).peekable();
+                while let Some((time, result)) = results.next() {
+                    let mut session = output.session(&cap.delayed(&time));
+                    session.give(result);
+                    while results.peek().map_or(false, |&(ref next, _)| *next == time) {
+                        session.give(results.next().expect("Peeked result").1);
+                    }
+                }
+            })
+    }
+}
@@ -1,53 +1,78 @@
-
 extern crate timely;
 extern crate dynamic_scaling_mechanism;
 
 use timely::dataflow::*;
-use timely::dataflow::operators::{Input, Probe, Map, Inspect};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::ops::Range;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use timely::Data;
+use timely::communication::allocator::Generic;
+use timely::dataflow::operators::{Concat, ConnectLoop, Feedback, Filter, Input, Probe, Map, Inspect};
+use timely::dataflow::scopes::Child;
+use timely::worker::Worker;
 
 use timely::Configuration;
+use timely::order::{Product, TotalOrder};
 
-use dynamic_scaling_mechanism::{BIN_SHIFT, ControlInst, Control};
+use dynamic_scaling_mechanism::{BinId, ControlError, ControlInst, Control, ControlSet, ControlSetBuilder, MigrationBudget, StatefulConfig, WorkerPolicy};
+use dynamic_scaling_mechanism::assigner::{FastRange, KeyRanges};
+use dynamic_scaling_mechanism::checkpoint;
+use dynamic_scaling_mechanism::codec::{Codec, CodecStats};
+use dynamic_scaling_mechanism::controller::{Balance, Capped, Greedy};
+use dynamic_scaling_mechanism::driver::DriveControl;
+use dynamic_scaling_mechanism::logging::{MegaphoneEvent, LOGGER_NAME};
+use dynamic_scaling_mechanism::operator::{StatefulBuilder, StatefulOperator};
+use dynamic_scaling_mechanism::partial::{StatefulPartial, StatefulPartialOperator};
+use dynamic_scaling_mechanism::plan::{Plan, ReplayPlan};
+use dynamic_scaling_mechanism::planner::{self, MigrationPattern};
 use dynamic_scaling_mechanism::state_machine::BinnedStateMachine;
+use dynamic_scaling_mechanism::stateful::{MigrationPhase, StateStream, Stateful};
+use dynamic_scaling_mechanism::windows::{Count, WindowOperator, Windows};
 
-#[test]
-fn default_configuration() {
-    timely::execute(Configuration::Process(2), |worker| {
+/// The scope of dataflows built by `run_rounds`.
+type TestScope<'a> = Child<'a, Worker<Generic>, u64>;
 
-        // these results happen to be right, but aren't guaranteed.
-        // the system is at liberty to re-order within a timestamp.
-        let mut result = vec![(0, 0), (0, 2), (0, 6), (0, 12), (0, 20),
-                              (1, 1), (1, 4), (1, 9), (1, 16), (1, 25)];
+/// Run a dataflow on two workers and collect its outputs.
+///
+/// `dataflow` builds the operators under test from the input and control streams, and returns the
+/// stream to observe. The `controls` are sent at their times, after which worker 0 sends one
+/// record per round in `rounds`, at the round's time. Each round is processed before the next.
+/// Returns the observed outputs, each with the index of the worker observing it.
+fn run_rounds<D, F>(rounds: Range<u64>, controls: Vec<(u64, Control)>, dataflow: F) -> Vec<(usize, D)>
+    where
+        D: Data+Send,
+        F: for<'a> Fn(&Stream<TestScope<'a>, u64>, &Stream<TestScope<'a>, Control>) -> Stream<TestScope<'a>, D>+Send+Sync+'static,
+{
+    let outputs = Arc::new(Mutex::new(Vec::new()));
+    let outputs2 = Arc::clone(&outputs);
+    timely::execute(Configuration::Process(2), move |worker| {
 
         let index = worker.index();
         let mut input = InputHandle::new();
         let mut control_input = InputHandle::new();
         let mut probe = ProbeHandle::new();
+        let outputs = Arc::clone(&outputs2);
 
         worker.dataflow(|scope| {
             let control = scope.input_from(&mut control_input);
             let input = scope.input_from(&mut input);
-            input
-                .map(|x| (x % 2, x))
-                .stateful_state_machine(
-                    |_key, val, agg| {
-                        *agg += val;
-                        (false, Some((*_key, *agg)))
-                    },
-                    |key| *key as u64
-                    ,
-                    &control
-                )
-                .inspect(move |x| {
-                    assert!(result.contains(x));
-                    result.retain(|e| e != x);
-                })
+            dataflow(&input, &control)
+                .inspect(move |x| outputs.lock().unwrap().push((index, x.clone())))
                 .probe_with(&mut probe);
         });
 
-        control_input.advance_to(10);
-        // introduce data and watch!
-        for round in 0..10 {
+        for &(time, ref control) in controls.iter() {
+            control_input.advance_to(time);
+            control_input.send(control.clone());
+        }
+        control_input.advance_to(rounds.end);
+        input.advance_to(rounds.start);
+        for round in rounds.clone() {
             if index == 0 {
                 input.send(round);
             }
@@ -56,170 +81,1066 @@ fn default_configuration() {
                 worker.step();
             }
         }
+        control_input.close();
+        input.close();
+        while worker.step() { }
 
     }).unwrap();
+    let outputs = ::std::mem::replace(&mut *outputs.lock().unwrap(), Vec::new());
+    outputs
+}
+
+/// The outputs `worker` observed, in order.
+fn outputs_of<D: Clone>(outputs: &[(usize, D)], worker: usize) -> Vec<D> {
+    outputs.iter().filter(|output| output.0 == worker).map(|output| output.1.clone()).collect()
+}
+
+/// The outputs of all workers, sorted.
+fn sorted<D: Ord>(outputs: Vec<(usize, D)>) -> Vec<D> {
+    let mut outputs: Vec<_> = outputs.into_iter().map(|(_worker, output)| output).collect();
+    outputs.sort();
+    outputs
+}
+
+/// Sum inputs `x` by key `x % keys`, placing keys with `hash`. Outputs `(key, sum)` per input.
+fn sum_by_key<G, H>(input: &Stream<G, u64>, keys: u64, hash: H, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>) -> Stream<G, (u64, u64)>
+    where
+        G: Scope,
+        G::Timestamp: Hash+Eq+TotalOrder,
+        H: Fn(&u64)->u64+Send+Sync+'static,
+{
+    input
+        .map(move |x| (x % keys, x))
+        .stateful_state_machine(
+            |key, val, agg: &mut u64| {
+                *agg += val;
+                (false, Some((*key, *agg)))
+            },
+            hash,
+            control,
+            config
+        )
+}
+
+/// Route `input` with `stateful` without keeping state, and close the feedback loop. `select`
+/// attaches to the streams of `stateful` before.
+fn stateless<G, B, R, F>(input: &Stream<G, u64>, key: B, control: &Stream<G, Control>, config: &StatefulConfig<G::Timestamp>, select: F) -> R
+    where
+        G: Scope,
+        G::Timestamp: Hash+Eq+TotalOrder,
+        B: Fn(&u64)->u64+'static,
+        F: FnOnce(&StateStream<G, u64, Vec<()>, (), ()>)->R,
+{
+    let stateful = input.stateful::<(), Vec<()>, _, ()>(key, control, config, None, None);
+    let selected = select(&stateful);
+    stateful.stream
+        .filter(|_| false)
+        .map(|_| ())
+        .connect_loop(stateful.feedback);
+    selected
+}
+
+// These results happen to be right, but aren't guaranteed. The system is at liberty to re-order
+// within a timestamp.
+
+/// The outputs of `sum_by_key` with two keys for inputs `0..10`.
+const SUMS_2: [(u64, u64); 10] = [(0, 0), (0, 2), (0, 6), (0, 12), (0, 20),
+                                  (1, 1), (1, 4), (1, 9), (1, 16), (1, 25)];
+
+/// The outputs of `sum_by_key` with four keys for inputs `0..10`.
+const SUMS_4: [(u64, u64); 10] = [(0, 0), (0, 4), (0, 12), (1, 1), (1, 6), (1, 15),
+                                  (2, 2), (2, 8), (3, 3), (3, 10)];
+
+/// Move all bins to worker 1 at time 3 and back to worker 0 at time 6.
+fn there_and_back(config: &StatefulConfig<u64>) -> Vec<(u64, Control)> {
+    vec![
+        (3, Control::new(0,  1, ControlInst::Map(vec![1; config.bins()]))),
+        (6, Control::new(1,  1, ControlInst::Map(vec![0; config.bins()]))),
+    ]
+}
+
+#[test]
+fn default_configuration() {
+    let config = StatefulConfig::new();
+    let outputs = run_rounds(0..10, Vec::new(), move |input, control| sum_by_key(input, 2, |key| *key, control, &config));
+    assert_eq!(sorted(outputs), SUMS_2.to_vec());
 }
 
 #[test]
 fn custom_configuration() {
-    timely::execute(Configuration::Process(2), |worker| {
+    let config = StatefulConfig::new();
+    let controls = vec![
+        (0, Control::new(0,  1, ControlInst::Map(vec![0; config.bins()]))),
+        (5, Control::new(1,  1, ControlInst::Map(vec![1; config.bins()]))),
+    ];
+    let outputs = run_rounds(0..10, controls, move |input, control| sum_by_key(input, 2, |key| *key, control, &config));
+    assert_eq!(sorted(outputs), SUMS_2.to_vec());
+}
+
+#[test]
+fn chunked_configuration() {
+    // Send each key's state in a separate chunk
+    let config = StatefulConfig::new().with_chunk_size(1);
+    let controls = there_and_back(&config);
+    let outputs = run_rounds(0..10, controls, move |input, control| sum_by_key(input, 4, |key| *key, control, &config));
+    assert_eq!(sorted(outputs), SUMS_4.to_vec());
+}
+
+#[test]
+fn compressed_configuration() {
+    let config = StatefulConfig::new().with_codec(Codec::Lz4);
+    let controls = there_and_back(&config);
+    let outputs = run_rounds(0..10, controls, move |input, control| sum_by_key(input, 4, |key| *key, control, &config));
+    assert_eq!(sorted(outputs), SUMS_4.to_vec());
+}
+
+#[test]
+fn spill_configuration() {
+    // Spill all bins without pending notifications
+    let directory = ::std::env::temp_dir().join(format!("megaphone-spill-{}", ::std::process::id()));
+    let config = StatefulConfig::new().with_spill(directory.clone(), 0);
+    let controls = there_and_back(&config);
+    let outputs = run_rounds(0..10, controls, move |input, control| sum_by_key(input, 4, |key| *key, control, &config));
+    assert_eq!(sorted(outputs), SUMS_4.to_vec());
+
+    // Each operator spills to a directory of its own, ending in the worker's index. Files of bins
+    // moved away are removed.
+    for entry in ::std::fs::read_dir(&directory).unwrap() {
+        let path = entry.unwrap().path();
+        let spilled = ::std::fs::read_dir(&path).unwrap().next().is_some();
+        assert_eq!(spilled, path.to_string_lossy().ends_with("-0"), "Unexpected spill files in {:?}", path);
+    }
+    let _ = ::std::fs::remove_dir_all(&directory);
+}
+
+#[test]
+fn binary_spill_configuration() {
+    // Spill all bins without pending notifications
+    let directory = ::std::env::temp_dir().join(format!("megaphone-binary-spill-{}", ::std::process::id()));
+    let config = StatefulConfig::new().with_spill(directory.clone(), 0);
+    let outputs = run_rounds(0..10, Vec::new(), move |input, control| {
+        // The first five rounds store their record, later rounds look up a stored record, whose
+        // bin has no notifications and is spilled
+        let stored = input.filter(|x| *x < 5);
+        let lookups = input.filter(|x| *x >= 5).map(|x| x - 5);
+        stored.stateful_binary(control, &config, &lookups, |x: &u64| *x, |x: &u64| *x, "Lookup", |_cap, data, bin1, _bin2, _output| {
+            let state: &mut HashMap<u64, u64> = bin1.state();
+            for (time, x) in data.drain(..) {
+                state.insert(x, time);
+            }
+        }, |cap, data, bin1, bin2, output| {
+            let _: &mut Vec<u64> = bin2.state();
+            let state: &mut HashMap<u64, u64> = bin1.state();
+            let mut session = output.session(cap);
+            for (_time, x) in data.drain(..) {
+                session.give((x, state.get(&x).cloned()));
+            }
+        })
+    });
+    assert_eq!(sorted(outputs), (0..5).map(|x| (x, Some(x))).collect::<Vec<_>>());
+    let _ = ::std::fs::remove_dir_all(&directory);
+}
+
+#[test]
+fn codec_round_trip() {
+    let items: Vec<(u64, String)> = (0..1000).map(|i| (i % 10, format!("value {}", i % 7))).collect();
+    for &codec in &[Codec::None, Codec::Lz4] {
+        let mut stats = CodecStats::default();
+        let encoded = codec.encode(&items, &mut stats);
+        assert_eq!(stats.encoded_bytes, encoded.len());
+        assert_eq!(codec.decode::<(u64, String)>(&encoded), items);
+        // Decoding must not rely on the alignment of its input
+        let mut shifted = vec![0u8];
+        shifted.extend_from_slice(&encoded);
+        assert_eq!(codec.decode::<(u64, String)>(&shifted[1..]), items);
+        if codec == Codec::Lz4 {
+            assert!(stats.ratio() > 2., "Poor compression ratio {}", stats.ratio());
+        }
+    }
+    let mut stats = CodecStats::default();
+    assert!(Codec::Lz4.decode::<u64>(&Codec::Lz4.encode(&Vec::<u64>::new(), &mut stats)).is_empty());
+}
+
+#[test]
+fn btree_state_configuration() {
+    let config = StatefulConfig::new();
+    let controls = there_and_back(&config);
+    let outputs = run_rounds(0..10, controls, move |input, control| {
+        input
+            .map(|x| (x % 4, x))
+            .stateful_unary(control, &config, |&(key, _val): &(u64, u64)| key, "BTreeSum", |cap, data, bin, output| {
+                let state: &mut BTreeMap<u64, u64> = bin.state();
+                let mut session = output.session(cap);
+                for (_time, (key, val)) in data.drain(..) {
+                    let agg = state.entry(key).or_insert(0);
+                    *agg += val;
+                    session.give((key, *agg));
+                }
+            })
+    });
+    assert_eq!(sorted(outputs), SUMS_4.to_vec());
+}
+
+#[test]
+fn background_configuration() {
+    // Send one key's state per activation
+    let config = StatefulConfig::new().with_bin_shift(2).with_migration_budget(MigrationBudget::Records(1));
+    let controls = there_and_back(&config);
+    let outputs = run_rounds(0..10, controls, move |input, control| sum_by_key(input, 4, |key| *key << 62, control, &config));
+    assert_eq!(sorted(outputs), SUMS_4.to_vec());
+}
+
+#[test]
+fn pre_copy_configuration() {
+    let config = StatefulConfig::new().with_bin_shift(2).with_pre_copy(true);
+    // The configuration is known from the start, so the bins are copied while records for rounds
+    // 0 to 7 are processed
+    let controls = vec![(8, Control::new(0,  1, ControlInst::Map(vec![1; config.bins()])))];
+    let outputs = run_rounds(0..10, controls, move |input, control| {
+        input
+            .map(|x| (x % 4, x))
+            .stateful_state_machine(
+                |key, val, agg| {
+                    *agg += val;
+                    // Remove key 1's state after the snapshot
+                    (*key == 1 && val == 5, Some((*key, *agg)))
+                },
+                |key| (*key as u64) << 62,
+                control,
+                &config
+            )
+    });
+    assert_eq!(outputs_of(&outputs, 1), vec![(0, 12), (1, 9)]);
+    assert_eq!(sorted(outputs), vec![(0, 0), (0, 4), (0, 12), (1, 1), (1, 6), (1, 9),
+                                     (2, 2), (2, 8), (3, 3), (3, 10)]);
+}
+
+#[test]
+fn pre_copy_periodic_checkpoints() {
+    let directory = ::std::env::temp_dir().join(format!("megaphone-pre-copy-{}", ::std::process::id()));
+    let _ = ::std::fs::remove_dir_all(&directory);
+
+    // Bins are copied from the start, across the checkpoints at times 2, 4 and 6
+    let config = StatefulConfig::new().with_bin_shift(2).with_pre_copy(true)
+        .with_checkpoint_directory(directory.clone()).with_checkpoint_interval(2);
+    let controls = vec![(8, Control::new(0,  1, ControlInst::Map(vec![1; config.bins()])))];
+    let outputs = run_rounds(0..10, controls, move |input, control| sum_by_key(input, 4, |key| *key << 62, control, &config));
+    assert_eq!(outputs_of(&outputs, 1), vec![(0, 12), (1, 15)]);
+    assert_eq!(sorted(outputs), SUMS_4.to_vec());
+    assert!(checkpoint::latest::<u64>(&directory).unwrap().is_some());
+
+    let _ = ::std::fs::remove_dir_all(&directory);
+}
+
+#[test]
+fn queryable_configuration() {
+    // Move one key's state per activation, so queries find bins in flight
+    let config = StatefulConfig::new().with_bin_shift(2).with_migration_budget(MigrationBudget::Records(1));
+    let controls = there_and_back(&config);
+    let answers = run_rounds(0..10, controls, move |input, control| {
+        // Each round queries the key of its input, answers include the round's input
+        let queries = input.map(|round| round % 4);
+        let (_output, answers): (Stream<_, ()>, _) = input.stateful_unary_query(control, &config, &queries, |x: &u64| (x % 4) << 62, |key: &u64| key << 62, "Query", |_cap, data, bin, _output: &mut _| {
+            let state: &mut HashMap<u64, u64> = bin.state();
+            for (_time, x) in data.drain(..) {
+                *state.entry(x % 4).or_insert(0) += x;
+            }
+        }, |key, state| (key, state.get(&key).cloned().unwrap_or(0)));
+        answers
+    });
+    assert_eq!(sorted(answers), SUMS_4.to_vec());
+}
+
+/// Sum inputs `x` in `rounds` by key `x % 4`, optionally instructing a checkpoint at time 5.
+fn run_checkpointed(config: StatefulConfig<u64>, rounds: Range<u64>, checkpoint: bool) -> Vec<(u64, u64)> {
+    let controls = if checkpoint { vec![(5, Control::new(0,  1, ControlInst::Checkpoint))] } else { Vec::new() };
+    sorted(run_rounds(rounds, controls, move |input, control| sum_by_key(input, 4, |key| *key << 62, control, &config)))
+}
+
+#[test]
+fn checkpoint_restore() {
+    let directory = ::std::env::temp_dir().join(format!("megaphone-checkpoint-{}", ::std::process::id()));
+    let _ = ::std::fs::remove_dir_all(&directory);
+
+    let config = StatefulConfig::new().with_bin_shift(2).with_checkpoint_directory(directory.clone());
+    assert_eq!(run_checkpointed(config, 0..10, true), SUMS_4.to_vec());
+
+    let latest = checkpoint::latest::<u64>(&directory).unwrap().expect("No complete checkpoint");
+    assert_eq!(latest, checkpoint::path(&directory, 0));
+    assert_eq!(checkpoint::frontier::<u64>(&latest).unwrap(), vec![5]);
+
+    // Replaying the input from the checkpoint's frontier produces the same results
+    let config = StatefulConfig::new().with_bin_shift(2).with_restore(latest).with_codec(Codec::Lz4);
+    assert_eq!(run_checkpointed(config, 5..10, false), vec![(0, 12), (1, 6), (1, 15), (2, 8), (3, 10)]);
+
+    let _ = ::std::fs::remove_dir_all(&directory);
+}
+
+#[test]
+fn periodic_checkpoints() {
+    let directory = ::std::env::temp_dir().join(format!("megaphone-periodic-{}", ::std::process::id()));
+    let _ = ::std::fs::remove_dir_all(&directory);
+
+    let config = StatefulConfig::new().with_bin_shift(2).with_checkpoint_directory(directory.clone()).with_checkpoint_interval(4);
+    assert_eq!(run_checkpointed(config, 0..10, false), SUMS_4.to_vec());
+
+    // Checkpoints were taken at times 4 and 8 without any control instruction
+    assert!(checkpoint::periodic_path(&directory, 0, 0).exists());
+    let latest = checkpoint::latest::<u64>(&directory).unwrap().expect("No complete checkpoint");
+    assert_eq!(latest, checkpoint::periodic_path(&directory, 0, 1));
+    assert_eq!(checkpoint::frontier::<u64>(&latest).unwrap(), vec![8]);
+
+    let config = StatefulConfig::new().with_bin_shift(2).with_restore(latest);
+    assert_eq!(run_checkpointed(config, 8..10, false), vec![(0, 12), (1, 15)]);
+
+    let _ = ::std::fs::remove_dir_all(&directory);
+}
+
+#[test]
+fn spilled_checkpoint_restore() {
+    let directory = ::std::env::temp_dir().join(format!("megaphone-spilled-checkpoint-{}", ::std::process::id()));
+    let _ = ::std::fs::remove_dir_all(&directory);
+
+    // Bins are spilled as soon as they are not used, checkpoints copy them from their files
+    let config = StatefulConfig::new().with_bin_shift(2).with_spill(directory.join("spill"), 0).with_checkpoint_directory(directory.join("checkpoints"));
+    assert_eq!(run_checkpointed(config, 0..10, true), SUMS_4.to_vec());
+
+    let latest = checkpoint::latest::<u64>(&directory.join("checkpoints")).unwrap().expect("No complete checkpoint");
+    let config = StatefulConfig::new().with_bin_shift(2).with_restore(latest);
+    assert_eq!(run_checkpointed(config, 5..10, false), vec![(0, 12), (1, 6), (1, 15), (2, 8), (3, 10)]);
+
+    let _ = ::std::fs::remove_dir_all(&directory);
+}
+
+#[test]
+fn checkpoint_failure_diagnostics() {
+    // A file in place of the checkpoint directory fails every checkpoint
+    let directory = ::std::env::temp_dir().join(format!("megaphone-failing-{}", ::std::process::id()));
+    ::std::fs::write(&directory, b"").unwrap();
+
+    let config = StatefulConfig::new().with_checkpoint_directory(directory.clone());
+    let controls = vec![(2, Control::new(0,  1, ControlInst::Checkpoint))];
+    let failed = run_rounds(0..10, controls, move |input, control| stateless(input, |x| *x, control, &config, |stateful| stateful.diagnostics.clone()));
+
+    // Each worker reports its failure
+    let mut workers: Vec<_> = failed.iter().map(|&(worker, _)| worker).collect();
+    workers.sort();
+    assert_eq!(workers, vec![0, 1]);
+    for &(_, ref diagnostic) in failed.iter() {
+        match *diagnostic {
+            (0, ControlError::CheckpointFailed(_)) => {},
+            ref other => panic!("Unexpected diagnostics: {:?}", other),
+        }
+    }
+
+    let _ = ::std::fs::remove_file(&directory);
+}
+
+#[test]
+fn restore_failure_diagnostics() {
+    // Each worker reports the missing checkpoint and starts from the initial configuration
+    let missing = ::std::env::temp_dir().join(format!("megaphone-missing-{}", ::std::process::id()));
+    let config = StatefulConfig::new().with_bin_shift(2).with_restore(missing.clone());
+    let failed = run_rounds(0..10, Vec::new(), move |input, control| stateless(input, |x| *x, control, &config, |stateful| stateful.diagnostics.clone()));
+    let mut workers: Vec<_> = failed.iter().map(|&(worker, _)| worker).collect();
+    workers.sort();
+    assert_eq!(workers, vec![0, 1]);
+    for &(_, ref diagnostic) in failed.iter() {
+        match *diagnostic {
+            (0, ControlError::RestoreFailed(_)) => {},
+            ref other => panic!("Unexpected diagnostics: {:?}", other),
+        }
+    }
+
+    let config = StatefulConfig::new().with_bin_shift(2).with_restore(missing);
+    assert_eq!(run_checkpointed(config, 0..10, false), SUMS_4.to_vec());
+}
+
+#[test]
+fn runtime_bin_shift_configuration() {
+    let config = StatefulConfig::new().with_bin_shift(4);
+    assert_eq!(16, config.bins());
+    let controls = vec![
+        (0, Control::new(0,  1, ControlInst::Map(vec![0; 16]))),
+        (5, Control::new(1,  1, ControlInst::Move(BinId::new(1), 1))),
+    ];
+    let outputs = run_rounds(0..10, controls, move |input, control| sum_by_key(input, 2, |key| *key << 60, control, &config));
+    assert_eq!(sorted(outputs), SUMS_2.to_vec());
+}
+
+#[test]
+fn split_merge_configuration() {
+    let config = StatefulConfig::new().with_bin_shift(1);
+    // Bin 0 keeps keys 0 and 1, bin 2 receives keys 2 and 3
+    let controls = vec![
+        (0, Control::new(0,  1, ControlInst::Split(BinId::new(0)))),
+        (3, Control::new(1,  1, ControlInst::Move(BinId::new(2), 1))),
+        (5, Control::new(2,  1, ControlInst::Move(BinId::new(2), 0))),
+        (7, Control::new(3,  1, ControlInst::Merge(BinId::new(0), BinId::new(2)))),
+    ];
+    // All keys start out in bin 0
+    let outputs = run_rounds(0..10, controls, move |input, control| sum_by_key(input, 4, |key| *key << 61, control, &config));
+    assert_eq!(sorted(outputs), SUMS_4.to_vec());
+}
+
+#[test]
+fn split_merge_validation() {
+    // Bin 0 on worker 0 and bin 1 on worker 1 are siblings
+    let initial = ControlSet::<u64>::initial(&StatefulConfig::new().with_bin_shift(1), 2);
+    let build = |instructions: Vec<ControlInst>| {
+        let mut builder = ControlSetBuilder::default();
+        let count = instructions.len();
+        for inst in instructions {
+            builder.apply(Control::new(1, count, inst)).unwrap();
+        }
+        builder.build(&initial, 2, WorkerPolicy::Reject).map(|config| config.map().clone())
+    };
+
+    assert_eq!(build(vec![ControlInst::Split(BinId::new(2))]), Err(ControlError::BinOutOfRange(BinId::new(2), 2)));
+    assert_eq!(build(vec![ControlInst::Merge(BinId::new(0), BinId::new(2))]), Err(ControlError::BinOutOfRange(BinId::new(2), 2)));
+    assert_eq!(build(vec![ControlInst::Merge(BinId::new(0), BinId::new(1))]), Err(ControlError::InvalidMerge(BinId::new(0), BinId::new(1))));
+    // Instructions earlier in the batch apply to the merge
+    assert_eq!(build(vec![ControlInst::Move(BinId::new(1), 0), ControlInst::Merge(BinId::new(0), BinId::new(1))]), Ok(vec![0, 0]));
+    assert_eq!(build(vec![ControlInst::Split(BinId::new(0)), ControlInst::Merge(BinId::new(0), BinId::new(2))]), Ok(vec![0, 1, 0]));
+    assert_eq!(build(vec![ControlInst::Split(BinId::new(0)), ControlInst::Move(BinId::new(2), 1), ControlInst::Merge(BinId::new(0), BinId::new(2))]), Err(ControlError::InvalidMerge(BinId::new(0), BinId::new(2))));
+}
+
+#[test]
+fn key_range_configuration() {
+    // Bin 0 on worker 0 holds keys 0 and 1, bin 1 on worker 1 holds keys 2 and 3
+    let config = StatefulConfig::new().with_bin_shift(1).with_assigner(KeyRanges::new(vec![0, 2]));
+    // Move key 1 to bin 1
+    let controls = vec![(5, Control::new(0,  1, ControlInst::Reassign(vec![0, 1])))];
+    let outputs = run_rounds(0..10, controls, move |input, control| sum_by_key(input, 4, |key| *key, control, &config));
+    for &(worker, output) in outputs.iter().filter(|&&(_, (key, _))| key == 1) {
+        assert_eq!(worker, if output.1 == 1 { 0 } else { 1 }, "Key 1 on wrong worker: {:?}", output);
+    }
+    assert_eq!(sorted(outputs), SUMS_4.to_vec());
+}
+
+#[test]
+#[should_panic(expected = "does not support bin shift")]
+fn key_ranges_bin_shift_mismatch() {
+    // Two ranges, but four bins
+    let config = StatefulConfig::new().with_bin_shift(2).with_assigner(KeyRanges::new(vec![0, 2]));
+    ControlSet::<u64>::initial(&config, 2);
+}
+
+#[test]
+fn fast_range_assignment() {
+    let config = StatefulConfig::new().with_bin_shift(4).with_assigner(FastRange);
+    // Identity hash, all keys share the same most significant bits
+    let outputs = run_rounds(0..10, Vec::new(), move |input, control| sum_by_key(input, 10, |key| *key, control, &config));
+    // Both workers hold some of the keys
+    for worker in 0..2 {
+        assert!(outputs.iter().any(|output| output.0 == worker), "No keys on worker {}", worker);
+    }
+}
 
-        // these results happen to be right, but aren't guaranteed.
-        // the system is at liberty to re-order within a timestamp.
-        let mut result = vec![(0, 0), (0, 2), (0, 6), (0, 12), (0, 20),
-                              (1, 1), (1, 4), (1, 9), (1, 16), (1, 25)];
+#[test]
+fn adjacent_configuration() {
+    let config = StatefulConfig::new();
+    let controls = vec![
+        (0, Control::new(0,  1, ControlInst::Map(vec![0; config.bins()]))),
+        (1, Control::new(1,  1, ControlInst::Map(vec![1; config.bins()]))),
+    ];
+    let outputs = run_rounds(0..10, controls, move |input, control| sum_by_key(input, 2, |key| *key, control, &config));
+    assert_eq!(sorted(outputs), SUMS_2.to_vec());
+}
+
+#[test]
+fn error_seq_configuration() {
+    let config = StatefulConfig::new();
+    let controls = vec![
+        (3, Control::new(10,  1, ControlInst::Map(vec![0; config.bins()]))),
+        (3, Control::new(9,  1, ControlInst::Map(vec![1; config.bins()]))),
+    ];
+    let rejected = run_rounds(0..10, controls, move |input, control| stateless(input, |x| *x, control, &config, |stateful| stateful.diagnostics.clone()));
+    for worker in 0..2 {
+        assert_eq!(outputs_of(&rejected, worker), vec![(10, ControlError::SequenceMismatch(10, 9))]);
+    }
+}
+
+#[test]
+fn rejected_configuration_diagnostics() {
+    let config = StatefulConfig::new();
+    let bins = config.bins();
+    let controls = vec![
+        (0, Control::new(0,  1, ControlInst::Map(vec![0; bins - 1]))),
+        (2, Control::new(1,  1, ControlInst::Move(BinId::new(bins), 0))),
+        (4, Control::new(2,  2, ControlInst::None)),
+        (6, Control::new(3,  1, ControlInst::Move(BinId::new(0), 7))),
+    ];
+    let rejected = run_rounds(0..10, controls, move |input, control| stateless(input, |x| *x, control, &config, |stateful| stateful.diagnostics.clone()));
+    for worker in 0..2 {
+        assert_eq!(outputs_of(&rejected, worker), vec![
+            (0, ControlError::MapLength(bins, bins - 1)),
+            (1, ControlError::BinOutOfRange(BinId::new(bins), bins)),
+            (2, ControlError::MissingControls(1)),
+            (3, ControlError::WorkerOutOfRange(7, 2)),
+        ]);
+    }
+}
+
+#[test]
+fn migration_acknowledgements() {
+    let config = StatefulConfig::new();
+    // The default configuration assigns bin 0 to worker 0.
+    let controls = vec![(5, Control::new(0,  1, ControlInst::Move(BinId::new(0), 1)))];
+    let acks = run_rounds(0..10, controls, move |input, control| stateless(input, |x| *x, control, &config, |stateful| stateful.acks.clone()));
+    for worker in 0..2 {
+        let acks = outputs_of(&acks, worker);
+        assert_eq!(acks.len(), 2);
+        assert_eq!(acks[0].phase, MigrationPhase::Installed);
+        assert_eq!(acks[1].phase, MigrationPhase::Applied);
+        for ack in acks.iter() {
+            assert_eq!(ack.sequence, 0);
+            assert_eq!(ack.frontier, vec![5]);
+            if worker == 0 {
+                assert_eq!(ack.moved_bins, vec![BinId::new(0)]);
+                assert!(ack.bytes_moved > 0);
+            } else {
+                assert!(ack.moved_bins.is_empty());
+                assert_eq!(ack.bytes_moved, 0);
+            }
+        }
+    }
+}
+
+#[test]
+fn driven_control() {
+    timely::execute(Configuration::Process(2), |worker| {
 
         let index = worker.index();
         let mut input = InputHandle::new();
         let mut control_input = InputHandle::new();
         let mut probe = ProbeHandle::new();
+        let config = StatefulConfig::new();
+        let acks = Rc::new(RefCell::new(Vec::new()));
+        let acks2 = Rc::clone(&acks);
 
         worker.dataflow(|scope| {
-            let control = scope.input_from(&mut control_input);
             let input = scope.input_from(&mut input);
-            input
-                .map(|x| (x % 2, x))
-                .stateful_state_machine(
-                    |_key, val, agg| {
-                        *agg += val;
-                        (false, Some((*_key, *agg)))
-                    },
-                    |key| *key as u64
-                    ,
-                    &control
-                )
-                .inspect(move |x| {
-                    assert!(result.contains(x), "Got {:?}, expected one of {:?}", x, result);
-                    result.retain(|e| e != x);
-                })
-                .probe_with(&mut probe);
+            let control = scope.input_from(&mut control_input).drive(&input, 1);
+            stateless(&input, |x| *x, &control, &config, |stateful| {
+                stateful.acks.inspect(move |x| acks2.borrow_mut().push(x.clone()));
+                stateful.stream.probe_with(&mut probe);
+            });
         });
 
-        control_input.send(Control::new(0,  1, ControlInst::Map(vec![0; 1 << BIN_SHIFT])));
-        control_input.advance_to(5);
-        control_input.send(Control::new(1,  1, ControlInst::Map(vec![1; 1 << BIN_SHIFT])));
-        control_input.advance_to(10);
-        // introduce data and watch!
+        // The control input stays at time 0, records must not wait for it.
         for round in 0..10 {
             if index == 0 {
                 input.send(round);
             }
+            if round == 5 {
+                control_input.send(Control::new(0, 1, ControlInst::Move(BinId::new(0), 1)));
+            }
             input.advance_to(round + 1);
             while probe.less_than(input.time()) {
                 worker.step();
             }
         }
+        control_input.close();
+        input.close();
+        while worker.step() { }
+
+        // Each worker installs and applies the configuration once
+        let acks = acks.borrow();
+        assert_eq!(acks.iter().map(|ack| ack.phase).collect::<Vec<_>>(), vec![MigrationPhase::Installed, MigrationPhase::Applied]);
+        for ack in acks.iter() {
+            assert_eq!(ack.sequence, 0);
+            assert!(ack.frontier[0] > 5);
+        }
+        if index == 0 {
+            assert_eq!(acks[0].moved_bins, vec![BinId::new(0)]);
+        }
 
     }).unwrap();
 }
 
 #[test]
-fn adjacent_configuration() {
-    timely::execute(Configuration::Process(2), |worker| {
-
-        // these results happen to be right, but aren't guaranteed.
-        // the system is at liberty to re-order within a timestamp.
-        let mut result = vec![(0, 0), (0, 2), (0, 6), (0, 12), (0, 20),
-                              (1, 1), (1, 4), (1, 9), (1, 16), (1, 25)];
+fn driven_control_skewed() {
+    let acks = Arc::new(Mutex::new(Vec::new()));
+    let acks2 = Arc::clone(&acks);
+    timely::execute(Configuration::Process(2), move |worker| {
 
         let index = worker.index();
         let mut input = InputHandle::new();
         let mut control_input = InputHandle::new();
         let mut probe = ProbeHandle::new();
+        let config = StatefulConfig::new();
+        let acks = Arc::clone(&acks2);
 
         worker.dataflow(|scope| {
-            let control = scope.input_from(&mut control_input);
             let input = scope.input_from(&mut input);
-            input
-                .map(|x| (x % 2, x))
-                .stateful_state_machine(
-                    |_key, val, agg| {
-                        *agg += val;
-                        (false, Some((*_key, *agg)))
-                    },
-                    |key| *key as u64
-                    ,
-                    &control
-                )
-                .inspect(move |x| {
-                    assert!(result.contains(x));
-                    result.retain(|e| e != x);
-                })
-                .probe_with(&mut probe);
+            let control = scope.input_from(&mut control_input).drive(&input, 1);
+            stateless(&input, |x| *x, &control, &config, |stateful| {
+                stateful.acks.inspect(move |x| acks.lock().unwrap().push(x.clone()));
+                stateful.stream.probe_with(&mut probe);
+            });
         });
 
-        control_input.send(Control::new(0,  1, ControlInst::Map(vec![0; 1 << BIN_SHIFT])));
-        control_input.advance_to(1);
-        control_input.send(Control::new(1,  1, ControlInst::Map(vec![1; 1 << BIN_SHIFT])));
-        control_input.advance_to(10);
-        // introduce data and watch!
+        // Worker 0 follows the data round by round, worker 1 advances all its input before it
+        // receives its copy of the batch and only then catches up.
         for round in 0..10 {
             if index == 0 {
                 input.send(round);
+                if round == 2 {
+                    control_input.send(Control::new(0, 1, ControlInst::Move(BinId::new(0), 1)));
+                }
             }
             input.advance_to(round + 1);
-            while probe.less_than(input.time()) {
-                worker.step();
+            if index == 0 {
+                while probe.less_than(input.time()) {
+                    worker.step();
+                }
             }
         }
+        if index == 1 {
+            control_input.send(Control::new(0, 1, ControlInst::Move(BinId::new(0), 1)));
+        }
+        control_input.close();
+        input.close();
+        while worker.step() { }
 
     }).unwrap();
+
+    // Both workers install and apply the configuration once, at the same time
+    let acks = acks.lock().unwrap();
+    assert_eq!(acks.len(), 4);
+    for ack in acks.iter() {
+        assert_eq!(ack.sequence, 0);
+        assert_eq!(ack.frontier, acks[0].frontier);
+    }
 }
 
+#[test]
+fn clamped_worker_events() {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = Arc::clone(&events);
+    let config = StatefulConfig::new().with_bin_shift(2).with_worker_policy(WorkerPolicy::Clamp);
+    // Worker 5 does not exist, bin 0 moves to worker 1 instead
+    let controls = vec![(5, Control::new(0,  1, ControlInst::Move(BinId::new(0), 5)))];
+    let outputs = run_rounds(0..10, controls, move |input, control| {
+        let events = Arc::clone(&events2);
+        input.scope().log_register().insert::<MegaphoneEvent<u64>, _>(LOGGER_NAME, move |_time, data| {
+            events.lock().unwrap().extend(data.drain(..).map(|(_, worker, event)| (worker, event)));
+        });
+        sum_by_key(input, 4, |key| *key << 62, control, &config)
+    });
+    assert_eq!(sorted(outputs), SUMS_4.to_vec());
+
+    let events = events.lock().unwrap();
+    for worker in 0..2 {
+        assert!(events.contains(&(worker, MegaphoneEvent::WorkerClamped { time: 5, sequence: 0, worker: 5, peers: 2 })));
+    }
+}
 
 #[test]
-#[should_panic]
-fn error_seq_configuration() {
-    timely::execute(Configuration::Process(2), |worker| {
+fn migration_events() {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = Arc::clone(&events);
+    let config = StatefulConfig::new();
+    // The default configuration assigns bin 0 to worker 0.
+    let controls = vec![(5, Control::new(0,  1, ControlInst::Move(BinId::new(0), 1)))];
+    run_rounds(0..10, controls, move |input, control| {
+        let events = Arc::clone(&events2);
+        input.scope().log_register().insert::<MegaphoneEvent<u64>, _>(LOGGER_NAME, move |_time, data| {
+            events.lock().unwrap().extend(data.drain(..).map(|(_, worker, event)| (worker, event)));
+        });
+        stateless(input, |x| *x, control, &config, |stateful| stateful.stream.filter(|_| false).map(|_| ()))
+    });
 
-        // these results happen to be right, but aren't guaranteed.
-        // the system is at liberty to re-order within a timestamp.
-        let mut result = vec![(0, 0), (0, 2), (0, 6), (0, 12), (0, 20),
-                              (1, 1), (1, 4), (1, 9), (1, 16), (1, 25)];
+    // Each worker receives and installs the configuration, worker 0 sends bin 0 to worker 1
+    let events = events.lock().unwrap();
+    for worker in 0..2 {
+        let mut kinds: Vec<_> = events.iter().filter(|event| event.0 == worker).filter_map(|&(_, ref event)| match *event {
+            MegaphoneEvent::ConfigurationReceived { time: 5, sequence: 0, bytes } if bytes > 0 => Some("received"),
+            MegaphoneEvent::ConfigurationInstalled { time: 5, sequence: 0, moved_bins, .. } if moved_bins == 1 - worker => Some("installed"),
+            MegaphoneEvent::BinSendStart { time: 5, ref bin, bytes: Some(0) } if *bin == BinId::new(0) => Some("send start"),
+            MegaphoneEvent::BinSendEnd { time: 5, ref bin, target: 1, .. } if *bin == BinId::new(0) => Some("send end"),
+            MegaphoneEvent::BinReceiveStart { time: 5, ref bin, .. } if *bin == BinId::new(0) => Some("receive start"),
+            MegaphoneEvent::BinReceiveEnd { time: 5, ref bin, .. } if *bin == BinId::new(0) => Some("receive end"),
+            MegaphoneEvent::RecordsStashed { .. } => None,
+            ref other => panic!("Unexpected event on worker {}: {:?}", worker, other),
+        }).collect();
+        kinds.sort();
+        if worker == 0 {
+            assert_eq!(kinds, vec!["installed", "received", "send end", "send start"]);
+        } else {
+            assert_eq!(kinds, vec!["installed", "receive end", "receive start", "received"]);
+        }
+    }
+}
+
+#[test]
+fn bin_statistics() {
+    // Report on every activation
+    let config = StatefulConfig::new().with_bin_shift(2).with_stats_interval(Duration::from_secs(0));
+    let bins = config.bins();
+    let stats = run_rounds(0..10, Vec::new(), move |input, control| stateless(input, |x| (x % 4) << 62, control, &config, |stateful| stateful.stats.clone()));
+
+    // Each worker reports the records it routed, by bin
+    for worker in 0..2 {
+        let mut records = vec![0; bins];
+        for stats in outputs_of(&stats, worker) {
+            assert_eq!(stats.worker, worker);
+            assert_eq!(stats.folds, 0);
+            assert_eq!(stats.state_bytes.unwrap_or(0), 0);
+            records[*stats.bin] += stats.records;
+        }
+        if worker == 0 {
+            assert_eq!(records, vec![3, 3, 2, 2]);
+        } else {
+            assert_eq!(records, vec![0; 4]);
+        }
+    }
+}
+
+#[test]
+fn balance_configuration() {
+    // Bins 0 and 2 carry all records, both on worker 0 by default
+    let config = StatefulConfig::new().with_bin_shift(2).with_stats_interval(Duration::from_secs(0));
+    let initial = ControlSet::initial(&config, 2);
+    let controls = run_rounds(0..10, Vec::new(), move |input, _control| {
+        let (handle, control) = input.scope().feedback(1);
+        let input = input.flat_map(|round| vec![2 * round, 2 * round + 1]);
+        stateless(&input, |x| (x % 2 * 2) << 62, &control, &config, |stateful| {
+            stateful.stats
+                .balance(&stateful.acks, &stateful.diagnostics, &initial, Capped::new(1, Greedy), |stats| stats.records as u64)
+                .connect_loop(handle);
+        });
+        control
+    });
+
+    // One of the loaded bins moves to worker 1, after which the load is balanced
+    for worker in 0..2 {
+        let controls = outputs_of(&controls, worker);
+        assert_eq!(controls.len(), 1);
+        assert_eq!(controls[0].sequence(), 1);
+        assert_eq!(controls[0].count(), 1);
+        match *controls[0].inst() {
+            ControlInst::Move(bin, 1) => assert!(bin == BinId::new(0) || bin == BinId::new(2)),
+            ref inst => panic!("Unexpected instruction {:?}", inst),
+        }
+    }
+}
+
+#[test]
+fn partially_ordered_migration() {
+    timely::execute(Configuration::Process(2), |worker| {
 
         let index = worker.index();
         let mut input = InputHandle::new();
         let mut control_input = InputHandle::new();
         let mut probe = ProbeHandle::new();
+        // Moved state is encoded
+        let config = StatefulConfig::new().with_codec(Codec::Lz4);
+        let outputs = Rc::new(RefCell::new(Vec::new()));
+        let outputs2 = Rc::clone(&outputs);
 
-        worker.dataflow(|scope| {
+        worker.dataflow::<Product<u64, u64>, _, _>(|scope| {
             let control = scope.input_from(&mut control_input);
             let input = scope.input_from(&mut input);
-            input
-                .map(|x| (x % 2, x))
-                .stateful_state_machine(
-                    |_key, val, agg| {
-                        *agg += val;
-                        (false, Some((*_key, *agg)))
-                    },
-                    |key| *key as u64
-                    ,
-                    &control
-                )
-                .inspect(move |x| {
-                    assert!(result.contains(x));
-                    result.retain(|e| e != x);
-                })
+            // All records belong to bin 0, which starts on worker 0
+            input.stateful_unary_partial(&control, &config, |_x: &u64| 0, "Count", move |cap, data, bin, output| {
+                let state: &mut Vec<u64> = bin.state();
+                let mut session = output.session(cap);
+                for (time, d) in data.drain(..) {
+                    state.push(d);
+                    session.give((index, time, state.len()));
+                }
+            })
+                .inspect(move |x| outputs2.borrow_mut().push(x.clone()))
                 .probe_with(&mut probe);
         });
 
-        control_input.advance_to(3);
-        control_input.send(Control::new(10,  1, ControlInst::Map(vec![0; 1 << BIN_SHIFT])));
-//        worker.step();
-//        control_input.advance_to(4);
-        control_input.send(Control::new(9,  1, ControlInst::Map(vec![1; 1 << BIN_SHIFT])));
-        worker.step();
-        control_input.advance_to(10);
-        // introduce data and watch!
-        for round in 0..10 {
-            if index == 0 {
-                input.send(round);
-            }
-            input.advance_to(round + 1);
-            while probe.less_than(input.time()) {
-                worker.step();
+        control_input.advance_to(Product::new(1, 0));
+        control_input.send(Control::new(0, 1, ControlInst::Move(BinId::new(0), 1)));
+        control_input.close();
+        for outer in 0..2 {
+            for inner in 0..5 {
+                if index == 0 {
+                    input.send(outer * 5 + inner);
+                }
+                input.advance_to(Product::new(outer, inner + 1));
+                while probe.less_than(input.time()) {
+                    worker.step();
+                }
             }
+            input.advance_to(Product::new(outer + 1, 0));
         }
+        input.close();
+        while worker.step() { }
+
+        // Each worker counts the records of one outer round, after the other worker's records
+        let mut outputs = outputs.borrow().clone();
+        outputs.sort_by_key(|&(_, time, _)| (time.outer, time.inner));
+        let expected: Vec<_> = (0..5).map(|inner| (index, Product::new(index as u64, inner), index * 5 + inner as usize + 1)).collect();
+        assert_eq!(outputs, expected);
 
     }).unwrap();
 }
+
+#[test]
+fn partial_unsupported_settings() {
+    let directory = ::std::env::temp_dir().join(format!("megaphone-partial-spill-{}", ::std::process::id()));
+    let config = StatefulConfig::new().with_pre_copy(true).with_checkpoint_interval(4).with_spill(directory, 0);
+    let diagnostics = run_rounds(0..4, Vec::new(), move |input, control| {
+        let stateful = input.stateful_partial::<(), Vec<()>, _, ()>(|x| *x, control, &config);
+        stateful.stream.filter(|_| false).map(|_| ()).connect_loop(stateful.feedback);
+        stateful.diagnostics
+    });
+
+    // Each worker reports the settings it ignores
+    for worker in 0..2 {
+        assert_eq!(outputs_of(&diagnostics, worker), vec![
+            (0, ControlError::SettingUnsupported("pre-copy".to_string())),
+            (0, ControlError::SettingUnsupported("checkpoint interval".to_string())),
+            (0, ControlError::SettingUnsupported("spill".to_string())),
+        ]);
+    }
+}
+
+#[test]
+fn co_partitioned_inputs() {
+    let config = StatefulConfig::new();
+    let controls = vec![(1, Control::new(0, 1, ControlInst::Move(BinId::new(0), 1)))];
+    let outputs = run_rounds(0..2, controls, move |input, control| {
+        let mut builder = StatefulBuilder::new("Join", control, &config);
+        // Three inputs receive each record, all records belong to bin 0, which starts on worker 0
+        let handles: Vec<_> = (0..3)
+            .map(|_| builder.add_input::<_, Vec<u64>, _, _>(input, |_x: &u64| 0))
+            .collect();
+        builder.build(move |cap, bin, output| {
+            let mut session = output.session(cap);
+            for (input, handle) in handles.iter().enumerate() {
+                for (_time, d) in handle.data().drain(..) {
+                    handle.bin(bin).state().push(d);
+                    let count: usize = handles.iter().map(|handle| handle.bin(bin).state().len()).sum();
+                    session.give((input, count));
+                }
+            }
+        })
+    });
+
+    // Each worker sees one record per input, after the other worker's records
+    for worker in 0..2 {
+        let mut outputs = outputs_of(&outputs, worker);
+        outputs.sort_by_key(|&(_, count)| count);
+        let mut inputs: Vec<_> = outputs.iter().map(|&(input, _)| input).collect();
+        inputs.sort();
+        assert_eq!(inputs, vec![0, 1, 2]);
+        let expected: Vec<_> = (1..4).map(|count| worker * 3 + count).collect();
+        assert_eq!(outputs.iter().map(|&(_, count)| count).collect::<Vec<_>>(), expected);
+    }
+}
+
+#[test]
+fn side_output() {
+    let config = StatefulConfig::new();
+    let controls = vec![(5, Control::new(0, 1, ControlInst::Move(BinId::new(0), 1)))];
+    let outputs = run_rounds(0..10, controls, move |input, control| {
+        // All records belong to bin 0, which starts on worker 0. Odd records go to the sides by
+        // their remainder modulo 4
+        let (output, sides) = input.stateful_unary_side(control, &config, |_x: &u64| 0, "Split", 2, |cap, side_caps, data, bin, output, sides| {
+            let state: &mut Vec<u64> = bin.state();
+            for (_time, d) in data.drain(..) {
+                state.push(d);
+                if d % 2 == 0 {
+                    output.session(cap).give((d, state.len()));
+                } else {
+                    let side = (d % 4 / 2) as usize;
+                    sides[side].session(&side_caps[side]).give((d, state.len()));
+                }
+            }
+        });
+        sides.iter().enumerate().fold(output.map(|x| (None, x)), |outputs, (side, stream)| {
+            outputs.concat(&stream.map(move |x| (Some(side), x)))
+        })
+    });
+
+    // Each worker folds the five records before or after the migration
+    for worker in 0..2 {
+        let expected = |remainder, modulus| (worker as u64 * 5..worker as u64 * 5 + 5)
+            .filter(|d| d % modulus == remainder)
+            .map(|d| (d, (d + 1) as usize))
+            .collect::<Vec<_>>();
+        let (mut side_outputs, outputs): (Vec<_>, Vec<_>) = outputs_of(&outputs, worker).into_iter().partition(|x| x.0.is_some());
+        assert_eq!(outputs.into_iter().map(|x| x.1).collect::<Vec<_>>(), expected(0, 2));
+        side_outputs.sort();
+        let expected_sides = expected(1, 4).into_iter().map(|x| (Some(0), x))
+            .chain(expected(3, 4).into_iter().map(|x| (Some(1), x)))
+            .collect::<Vec<_>>();
+        assert_eq!(side_outputs, expected_sides);
+    }
+}
+
+#[test]
+fn migrated_timers() {
+    let config = StatefulConfig::new();
+    let controls = vec![(5, Control::new(0, 1, ControlInst::Move(BinId::new(0), 1)))];
+    let outputs = run_rounds(0..10, controls, move |input, control| {
+        // All records belong to bin 0, which starts on worker 0. Each record `d` registers a timer
+        // `d + 1000` three rounds later, record 4 cancels the timer of record 2.
+        input.stateful_unary(control, &config, |_x: &u64| 0, "Timers", |cap, data, bin, output| {
+            let _: &mut Vec<u64> = bin.state();
+            let mut session = output.session(cap);
+            for (time, d) in data.drain(..) {
+                if d >= 1000 {
+                    session.give((time, d - 1000));
+                } else {
+                    assert!(bin.register_timer(cap, time + 3, d + 1000));
+                    if d == 4 {
+                        assert_eq!(bin.cancel_timers(|_time, &timer| timer == 1002), 1);
+                        assert!(bin.timers().all(|(&time, &timer)| timer >= 1000 && time == timer - 997));
+                    }
+                }
+            }
+        })
+    });
+
+    // Timers before the migration fire on worker 0, all later ones on worker 1, remaining timers
+    // fire once the input is complete
+    for worker in 0..2 {
+        let expected: Vec<_> = if worker == 0 { vec![0, 1] } else { (3..10).collect() };
+        let expected: Vec<_> = expected.into_iter().map(|d| (d + 3, d)).collect();
+        assert_eq!(outputs_of(&outputs, worker), expected);
+    }
+}
+
+#[test]
+fn migrated_windows() {
+    let config = StatefulConfig::new();
+    let controls = vec![(5, Control::new(0, 1, ControlInst::Move(BinId::new(0), 1)))];
+    let outputs = run_rounds(0..10, controls, move |input, control| {
+        // Records `(key, event time)`, all keys belong to bin 0, which starts on worker 0
+        let input = input.map(|round| (round % 2, round));
+        let tumbling = input.window(control, &config, Windows::tumbling(4), |x: &(u64, u64)| x.0, |x| x.1, |end| end, Count, "Tumbling");
+        let session = input.window(control, &config, Windows::session(2), |x: &(u64, u64)| x.0, |x| x.1, |end| end, Count, "Session");
+        tumbling.map(|x| ("tumbling", x)).concat(&session.map(|x| ("session", x)))
+    });
+
+    // Windows closing before the migration report on worker 0, later ones on worker 1. Sessions
+    // merge across the migration, and windows still open close once the input is complete.
+    for worker in 0..2 {
+        let mut outputs = outputs_of(&outputs, worker);
+        outputs.sort();
+        let expected = if worker == 0 {
+            vec![("tumbling", (0, 0, 4, 2)), ("tumbling", (1, 0, 4, 2))]
+        } else {
+            vec![("session", (0, 0, 10, 5)), ("session", (1, 1, 11, 5)),
+                 ("tumbling", (0, 4, 8, 2)), ("tumbling", (0, 8, 12, 1)),
+                 ("tumbling", (1, 4, 8, 2)), ("tumbling", (1, 8, 12, 1))]
+        };
+        assert_eq!(outputs, expected);
+    }
+}
+
+#[test]
+fn per_bin_windows() {
+    let config = StatefulConfig::new();
+    let mut outputs = run_rounds(0..8, Vec::new(), move |input, control| {
+        // Records `(key, event time)`, all keys belong to bin 0
+        input
+            .map(|round| (round % 2, round))
+            .window(control, &config, Windows::tumbling(4).per_bin(), |x: &(u64, u64)| x.0, |x| x.1, |end| end, Count, "Tumbling")
+    });
+
+    // One result per bin and window, not per key
+    outputs.sort();
+    assert_eq!(outputs, vec![(0, (0, 0, 4, 4)), (0, (0, 4, 8, 4))]);
+}
+
+#[test]
+fn per_bin_windows_assigner() {
+    // Bin 0 holds keys 0 and 1, bin 1 holds keys 2 and 3
+    let config = StatefulConfig::new().with_bin_shift(1).with_assigner(KeyRanges::new(vec![0, 2]));
+    let outputs = run_rounds(0..8, Vec::new(), move |input, control| {
+        input
+            .map(|round| (round % 4, round))
+            .window(control, &config, Windows::tumbling(4).per_bin(), |x: &(u64, u64)| x.0, |x| x.1, |end| end, Count, "Tumbling")
+    });
+
+    // Results report the bins of the assigner
+    assert_eq!(sorted(outputs), vec![(0, 0, 4, 2), (0, 4, 8, 2), (1, 0, 4, 2), (1, 4, 8, 2)]);
+}
+
+#[test]
+fn replay_plan() {
+    let text = "version 1\n# Move bin 0 to worker 1\nD 5 0 1\nP 8 fluid 0 1 0 0\n";
+    let plan = Plan::parse(text.as_bytes(), 4, 2).unwrap();
+    assert_eq!(plan.steps(), &[
+        (5, vec![ControlInst::Move(BinId::new(0), 1)]),
+        (8, vec![ControlInst::Move(BinId::new(0), 0)]),
+        (9, vec![ControlInst::Move(BinId::new(3), 0)]),
+    ][..]);
+    assert!(Plan::parse("version 2\n".as_bytes(), 4, 2).is_err());
+    assert!(Plan::parse("D 5 0 1\nP 8 fluid 0 1 0 0\n".as_bytes(), 4, 2).is_err());
+    // Version 0 keeps the times of steps and skips empty lines
+    assert_eq!(Plan::parse("D 5 0 1\n\nD 5 1 0\n".as_bytes(), 4, 2).unwrap().steps(), &[
+        (5, vec![ControlInst::Move(BinId::new(0), 1)]),
+        (5, vec![ControlInst::Move(BinId::new(1), 0)]),
+    ][..]);
+
+    let config = StatefulConfig::new().with_bin_shift(2);
+    let acks = run_rounds(0..12, Vec::new(), move |input, _control| {
+        let control = input.replay_plan(&plan, |time| time);
+        stateless(input, |x| *x, &control, &config, |stateful| {
+            stateful.acks
+                .filter(|ack| ack.phase == MigrationPhase::Applied)
+                .map(|ack| (ack.sequence, ack.frontier))
+        })
+    });
+    for worker in 0..2 {
+        assert_eq!(outputs_of(&acks, worker), vec![(0, vec![5]), (1, vec![8]), (2, vec![9])]);
+    }
+}
+
+#[test]
+fn planner_patterns() {
+    assert_eq!(planner::uniform(8, 4), vec![0, 1, 2, 3, 0, 1, 2, 3]);
+    assert_eq!(planner::half(8, 4), vec![0, 2, 2, 0, 0, 2, 2, 0]);
+    assert_eq!(planner::uniform_skew(8, 4), vec![0, 2, 2, 0, 0, 1, 2, 3]);
+
+    // Workers 0 and 1 each send two bins to worker 2 and 3, worker 3 sends one bin to worker 0
+    let current = vec![0, 0, 1, 1, 3, 3];
+    let target = vec![2, 3, 2, 3, 0, 3];
+    let patterns = [MigrationPattern::Sudden, MigrationPattern::Fluid, MigrationPattern::BatchedFluid, MigrationPattern::OptimizedMatching];
+    for pattern in patterns.iter() {
+        let batches = pattern.plan(&current, &target);
+        let mut map = current.clone();
+        for batch in batches.iter() {
+            let (mut sent, mut received) = (vec![0; 4], vec![0; 4]);
+            for inst in batch {
+                match *inst {
+                    ControlInst::Map(ref new_map) => map = new_map.clone(),
+                    ControlInst::Move(bin, worker) => {
+                        sent[map[*bin]] += 1;
+                        received[worker] += 1;
+                        map[*bin] = worker;
+                    },
+                    ref inst => panic!("Unexpected instruction {:?}", inst),
+                }
+            }
+            if *pattern != MigrationPattern::Sudden {
+                assert!(sent.iter().chain(received.iter()).all(|count| *count <= 1), "{:?} moves too many bins at once: {:?}", pattern, batch);
+            }
+        }
+        assert_eq!(map, target, "{:?} does not reach the target", pattern);
+        match *pattern {
+            MigrationPattern::Sudden => assert_eq!(batches.len(), 1),
+            MigrationPattern::Fluid => assert_eq!(batches.len(), 5),
+            MigrationPattern::BatchedFluid => assert!(batches.len() >= 2),
+            // Workers 0 to 3 each send or receive at most two bins
+            MigrationPattern::OptimizedMatching => assert_eq!(batches.len(), 2),
+        }
+    }
+}
+
+#[test]
+#[should_panic(expected = "Bin shift must be less than the number of key bits")]
+fn bin_shift_out_of_range() {
+    StatefulConfig::new().with_bin_shift(64);
+}